@@ -23,11 +23,90 @@ impl Write for MockReadWrite {
 }
 
 fn run_bf(bf: &str) {
+    let program = brainfuck::compile(bf, brainfuck::CompileOptions::default()).unwrap();
+    program.run(MockReadWrite, MockReadWrite).unwrap();
+}
+
+#[cfg(feature = "threaded-dispatch")]
+fn run_bf_threaded(bf: &str) {
+    let bump = Bump::new();
+    let ast = brainfuck::parse::parse_bytes(&bump, bf.as_bytes()).unwrap();
+    let hir = brainfuck::hir::optimized_hir(&bump, &ast);
+    let lir = brainfuck::lir::generate(&bump, &hir);
+    brainfuck::lir::threaded::run(&lir, MockReadWrite, MockReadWrite).unwrap();
+}
+
+#[cfg(feature = "asm-jit")]
+fn run_bf_asm_jit(bf: &str) {
     let bump = Bump::new();
-    let ast = brainfuck::parse::parse(&bump, bf.bytes().enumerate()).unwrap();
+    let ast = brainfuck::parse::parse_bytes(&bump, bf.as_bytes()).unwrap();
     let hir = brainfuck::hir::optimized_hir(&bump, &ast);
     let lir = brainfuck::lir::generate(&bump, &hir);
-    brainfuck::lir::interpreter::run(&lir, MockReadWrite, MockReadWrite, |_| {});
+    brainfuck::lir::asm_jit::asm_jit_run(&lir, MockReadWrite, MockReadWrite).unwrap();
+}
+
+fn compiled_fn(c: &mut Criterion) {
+    // prints the input byte plus one
+    let increment = ",+.";
+    let inputs = (0..=255).map(|byte| [byte]).collect::<Vec<_>>();
+
+    c.bench_function("increment_fresh_run", |b| {
+        b.iter(|| {
+            for input in &inputs {
+                brainfuck::run_source(black_box(increment), input).unwrap();
+            }
+        })
+    });
+
+    let bump = Bump::new();
+    let ast = brainfuck::parse::parse_bytes(&bump, increment.as_bytes()).unwrap();
+    let hir = brainfuck::hir::optimized_hir(&bump, &ast);
+    let mut run = brainfuck::lir::interpreter::compile_to_fn(&bump, &hir);
+    c.bench_function("increment_compiled_fn", |b| {
+        b.iter(|| {
+            for input in &inputs {
+                run(black_box(input));
+            }
+        })
+    });
+}
+
+fn batch_runner(c: &mut Criterion) {
+    // many tiny programs that each only use a few cells
+    let bump = Bump::new();
+    let programs = (1..=200)
+        .map(|n| {
+            let bf = format!("{}[>+>++<<-]>.>.", "+".repeat(n % 20));
+            let ast = brainfuck::parse::parse_bytes(&bump, bf.as_bytes()).unwrap();
+            let hir = brainfuck::hir::optimized_hir(&bump, &ast);
+            brainfuck::lir::generate(&bump, &hir)
+        })
+        .collect::<Vec<_>>();
+    let config = brainfuck::lir::interpreter::RunConfig::default();
+
+    c.bench_function("tiny_programs_fresh_run", |b| {
+        b.iter(|| {
+            for lir in &programs {
+                brainfuck::lir::interpreter::run(
+                    lir,
+                    MockReadWrite,
+                    MockReadWrite,
+                    &config,
+                    |_| {},
+                )
+                .unwrap();
+            }
+        })
+    });
+
+    let mut runner = brainfuck::lir::interpreter::BatchRunner::new(config.clone());
+    c.bench_function("tiny_programs_batch_runner", |b| {
+        b.iter(|| {
+            for lir in &programs {
+                runner.run(lir, MockReadWrite, MockReadWrite).unwrap();
+            }
+        })
+    });
 }
 
 fn optimized(c: &mut Criterion) {
@@ -44,12 +123,28 @@ fn optimized(c: &mut Criterion) {
     c.bench_function("bottles", |b| b.iter(|| run_bf(black_box(bottles))));
     c.bench_function("mandelbrot", |b| b.iter(|| run_bf(black_box(mandelbrot))));
     c.bench_function("hanoi", |b| b.iter(|| run_bf(black_box(hanoi))));
+
+    #[cfg(feature = "threaded-dispatch")]
+    c.bench_function("bench_threaded", |b| {
+        b.iter(|| run_bf_threaded(black_box(bench)))
+    });
+
+    #[cfg(feature = "asm-jit")]
+    c.bench_function("bench_asm_jit", |b| {
+        b.iter(|| run_bf_asm_jit(black_box(bench)))
+    });
+    #[cfg(feature = "asm-jit")]
+    c.bench_function("mandelbrot_asm_jit", |b| {
+        b.iter(|| run_bf_asm_jit(black_box(mandelbrot)))
+    });
 }
 
 pub fn benches() {
     // we need such a low sample size because the benches take ages otherwise
     let mut c = Criterion::default().configure_from_args().sample_size(30);
     optimized(&mut c);
+    compiled_fn(&mut c);
+    batch_runner(&mut c);
 }
 
 criterion_main!(benches);
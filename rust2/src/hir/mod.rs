@@ -4,13 +4,18 @@ use bumpalo::Bump;
 use dbg_pls::DebugPls;
 
 use crate::{
+    lir::cell::{ArithPolicy, CellWidth},
     parse::{Ast, Instr, Span},
     BumpVec,
 };
 
 pub mod opts;
+pub mod text;
+pub mod validate;
+#[cfg(feature = "verify-passes")]
+pub mod verify;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Hir<'hir> {
     pub stmts: BumpVec<'hir, Stmt<'hir>>,
 }
@@ -27,7 +32,7 @@ impl DebugPls for Hir<'_> {
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct Stmt<'hir> {
     pub kind: StmtKind<'hir>,
     pub span: Span,
@@ -55,7 +60,7 @@ impl DebugPls for Stmt<'_> {
     }
 }
 
-#[derive(Debug, Clone, DebugPls)]
+#[derive(Debug, Clone, PartialEq, Eq, DebugPls)]
 pub enum StmtKind<'hir> {
     Add(i32, u8),
     Sub(i32, u8),
@@ -63,12 +68,44 @@ pub enum StmtKind<'hir> {
     MoveAddTo {
         offset: i32,
     },
+    /// Sets the current cell to 0 and subtracts that value of the cell from another cell at
+    /// `offset`
+    MoveSubTo {
+        offset: i32,
+    },
+    /// Sets the current cell to 0 and adds that value of the cell times the factor to every
+    /// target `(offset, factor)`. Like with `MoveAddTo`, nothing is written if the cell is zero.
+    MoveMulTo {
+        targets: BumpVec<'hir, (i32, u8)>,
+    },
     Right(usize),
     Left(usize),
+    /// Moves right by the amount until the current cell is zero, `Loop([Right(n)])`
+    ScanRight(usize),
+    /// Moves left by the amount until the current cell is zero, `Loop([Left(n)])`
+    ScanLeft(usize),
     Loop(Hir<'hir>),
     Out,
+    /// Writes `n` like `Out` does for a cell that contains `n`, without reading the tape
+    OutConst(u8),
+    /// Writes `byte` like `OutConst` does, `count` times in a row
+    OutRepeat {
+        byte: u8,
+        count: usize,
+    },
     In,
     SetN(u8),
+    /// Sets the cell at `offset` to `n` without moving the pointer, `Right(k) SetN(n) Left(k)`
+    SetNOffset {
+        offset: i32,
+        n: u8,
+    },
+    /// Dumps the cells around the pointer. It is never removed or merged with anything, and
+    /// passes must not move anything across it.
+    Debug,
+    /// A statement that was removed by a pass. Passes replace statements with `Nop` in place
+    /// instead of removing them from the vector, `pass_remove_nops` compacts them all at once.
+    Nop,
 }
 
 fn ast_to_ir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
@@ -82,6 +119,7 @@ fn ast_to_ir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
             Instr::Left => StmtKind::Left(1),
             Instr::Out => StmtKind::Out,
             Instr::In => StmtKind::In,
+            Instr::Debug => StmtKind::Debug,
             Instr::Loop(body) => {
                 let ir_body = ast_to_ir(alloc, body);
                 StmtKind::Loop(ir_body)
@@ -96,7 +134,104 @@ fn ast_to_ir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
 }
 
 pub fn optimized_hir<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
+    optimized_hir_with_arith(alloc, ast, ArithPolicy::Wrap)
+}
+
+/// Optimizes the HIR for 8 bit cells with the given arithmetic, which must be the same one the
+/// program is run with later
+pub fn optimized_hir_with_arith<'hir>(
+    alloc: &'hir Bump,
+    ast: &Ast<'_>,
+    arith: ArithPolicy,
+) -> Hir<'hir> {
+    optimized_hir_for_cells(alloc, ast, CellWidth::U8, arith)
+}
+
+/// Optimizes the HIR for cells of the given width and arithmetic, which must be the same ones the
+/// program is run with later
+pub fn optimized_hir_for_cells<'hir>(
+    alloc: &'hir Bump,
+    ast: &Ast<'_>,
+    width: CellWidth,
+    arith: ArithPolicy,
+) -> Hir<'hir> {
+    let mut hir = ast_to_ir(alloc, ast);
+    opts::optimize_for_cells(alloc, &mut hir, width, arith);
+    hir
+}
+
+/// Optimizes code that continues on a tape that was used before, like the snippets of a
+/// [`crate::Machine`]. Unlike [`optimized_hir`], it doesn't assume that the tape starts out zeroed.
+pub fn optimized_snippet<'hir>(alloc: &'hir Bump, ast: &Ast<'_>) -> Hir<'hir> {
     let mut hir = ast_to_ir(alloc, ast);
-    opts::optimize(alloc, &mut hir);
+    opts::optimize_snippet(alloc, &mut hir);
     hir
 }
+
+/// Optimizes instructions that didn't come from brainfuck source code, for other frontends that
+/// target the optimizer. The spans are made up as if the instructions were written out as
+/// brainfuck without any comments. The spans inside of loops are ignored.
+pub fn optimize_instrs<'hir>(alloc: &'hir Bump, instrs: &[Instr<'_>]) -> Hir<'hir> {
+    let ast = instrs_to_ast(alloc, instrs, &mut 0);
+    optimized_hir(alloc, &ast)
+}
+
+fn instrs_to_ast<'ast, 'i, I>(alloc: &'ast Bump, instrs: I, pos: &mut usize) -> Ast<'ast>
+where
+    I: IntoIterator<Item = &'i Instr<'i>>,
+{
+    let mut ast = Vec::new_in(alloc);
+
+    for instr in instrs {
+        let start = *pos;
+        let instr = match instr {
+            Instr::Add => Instr::Add,
+            Instr::Sub => Instr::Sub,
+            Instr::Right => Instr::Right,
+            Instr::Left => Instr::Left,
+            Instr::Out => Instr::Out,
+            Instr::In => Instr::In,
+            Instr::Debug => Instr::Debug,
+            Instr::Loop(body) => {
+                // the `[`
+                *pos += 1;
+                let body = body
+                    .iter()
+                    .map(|(instr, _)| instr.clone())
+                    .collect::<Vec<_>>();
+                Instr::Loop(instrs_to_ast(alloc, &body, pos))
+            }
+        };
+        // the instruction itself or the `]`
+        *pos += 1;
+        ast.push((instr, Span::start_end(start, *pos)));
+    }
+
+    ast
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::parse::Instr;
+
+    #[test]
+    fn optimize_instrs_same_as_source() {
+        let alloc = Bump::new();
+        let src = "++[>+++<-]>[-]<<[>>[-<+>]+<<]>.";
+
+        let ast = crate::parse::parse(&alloc, src.bytes().enumerate()).unwrap();
+        let parsed = super::optimized_hir(&alloc, &ast);
+
+        let instrs = ast
+            .iter()
+            .map(|(instr, _)| instr.clone())
+            .collect::<Vec<Instr<'_>>>();
+        let from_instrs = super::optimize_instrs(&alloc, &instrs);
+
+        assert_eq!(format!("{parsed:?}"), format!("{from_instrs:?}"));
+        let spans = |hir: &super::Hir<'_>| hir.stmts.iter().map(|s| s.span).collect::<Vec<_>>();
+        assert_eq!(spans(&parsed), spans(&from_instrs));
+    }
+}
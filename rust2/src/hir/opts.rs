@@ -1,94 +1,268 @@
-use std::cmp::Ordering;
+use std::{cmp::Ordering, collections::VecDeque};
 
 use bumpalo::Bump;
 use tracing::trace;
 
 use crate::{
     hir::{Hir, Stmt, StmtKind},
+    lir::cell::{ArithPolicy, CellWidth},
     BumpVec,
 };
 
+/// How often [`Pass::DEFAULT`] is repeated at most, if every run still finds something to optimize
+const MAX_ITERATIONS: usize = 8;
+
 pub fn optimize<'hir>(alloc: &'hir Bump, hir: &mut Hir<'hir>) {
-    pass_group(alloc, hir);
-    pass_find_set_null(hir);
-    pass_set_n(hir);
-    pass_cancel_left_right_add_sub(hir);
-    pass_add_sub_offset(hir);
-    pass_move_add_to(hir);
-    // pass_unroll_loops(hir);
-    // pass_cancel_left_right_add_sub(hir);
+    optimize_with_arith(alloc, hir, ArithPolicy::Wrap);
+}
+
+/// Optimizes the HIR without assuming that cells wrap around if they saturate instead
+pub fn optimize_with_arith<'hir>(alloc: &'hir Bump, hir: &mut Hir<'hir>, arith: ArithPolicy) {
+    optimize_for_cells(alloc, hir, CellWidth::U8, arith);
+}
+
+/// Optimizes the HIR without assuming that cells wrap around at 256 if they are wider, or if they
+/// saturate instead
+///
+/// A pass can create something for an earlier pass to optimize, so the passes are repeated until
+/// the HIR stops changing.
+pub fn optimize_for_cells<'hir>(
+    alloc: &'hir Bump,
+    hir: &mut Hir<'hir>,
+    width: CellWidth,
+    arith: ArithPolicy,
+) {
+    optimize_until_unchanged(alloc, hir, Pass::DEFAULT, width, arith);
+}
+
+/// Optimizes code that doesn't start on a zeroed tape, like the snippets a REPL runs one after
+/// another. Only [`Pass::DeadStartLoop`] relies on that, so it is left out.
+pub fn optimize_snippet<'hir>(alloc: &'hir Bump, hir: &mut Hir<'hir>) {
+    let passes = Pass::DEFAULT
+        .iter()
+        .copied()
+        .filter(|&pass| pass != Pass::DeadStartLoop)
+        .collect::<Vec<_>>();
+    optimize_until_unchanged(alloc, hir, &passes, CellWidth::U8, ArithPolicy::Wrap);
+}
+
+fn optimize_until_unchanged<'hir>(
+    alloc: &'hir Bump,
+    hir: &mut Hir<'hir>,
+    passes: &[Pass],
+    width: CellWidth,
+    arith: ArithPolicy,
+) {
+    for _ in 0..MAX_ITERATIONS {
+        let before = hir.clone();
+        run_passes(alloc, hir, passes, width, arith);
+        if *hir == before {
+            break;
+        }
+    }
+}
+
+/// Runs only the given passes in the given order, for 8 bit wrapping cells. Some passes expect
+/// that others ran before them, see the order of [`Pass::DEFAULT`].
+pub fn optimize_with<'hir>(alloc: &'hir Bump, hir: &mut Hir<'hir>, passes: &[Pass]) {
+    run_passes(alloc, hir, passes, CellWidth::U8, ArithPolicy::Wrap);
+}
+
+/// An optimization pass, named after its `pass_*` function
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Pass {
+    Group,
+    FindSetNull,
+    SetN,
+    OutConst,
+    OutRepeat,
+    CancelLeftRightAddSub,
+    AddSubOffset,
+    SetNOffset,
+    BalancedMoves,
+    DeadStartLoop,
+    DeadLoop,
+    MoveAddTo,
+    MoveMul,
+    ScanLoop,
+    RedundantLoopAfterLoop,
+    RemoveNops,
+}
+
+impl Pass {
+    /// The passes that [`optimize`] runs
+    pub const DEFAULT: &'static [Pass] = &[
+        Pass::Group,
+        Pass::FindSetNull,
+        Pass::SetN,
+        Pass::OutConst,
+        Pass::OutRepeat,
+        Pass::CancelLeftRightAddSub,
+        Pass::AddSubOffset,
+        Pass::SetNOffset,
+        Pass::BalancedMoves,
+        Pass::DeadStartLoop,
+        Pass::DeadLoop,
+        // the following passes match on whole loop bodies, which must not contain nops
+        Pass::RemoveNops,
+        Pass::MoveAddTo,
+        Pass::MoveMul,
+        Pass::ScanLoop,
+        Pass::RedundantLoopAfterLoop,
+        Pass::RemoveNops,
+        // Pass::UnrollLoops,
+        // Pass::CancelLeftRightAddSub,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Group => "group",
+            Pass::FindSetNull => "find_set_null",
+            Pass::SetN => "set_n",
+            Pass::OutConst => "out_const",
+            Pass::OutRepeat => "out_repeat",
+            Pass::CancelLeftRightAddSub => "cancel_left_right_add_sub",
+            Pass::AddSubOffset => "add_sub_offset",
+            Pass::SetNOffset => "set_n_offset",
+            Pass::BalancedMoves => "balanced_moves",
+            Pass::DeadStartLoop => "dead_start_loop",
+            Pass::DeadLoop => "dead_loop",
+            Pass::MoveAddTo => "move_add_to",
+            Pass::MoveMul => "move_mul",
+            Pass::ScanLoop => "scan_loop",
+            Pass::RedundantLoopAfterLoop => "redundant_loop_after_loop",
+            Pass::RemoveNops => "remove_nops",
+        }
+    }
+}
+
+fn run_passes<'hir>(
+    alloc: &'hir Bump,
+    hir: &mut Hir<'hir>,
+    passes: &[Pass],
+    width: CellWidth,
+    arith: ArithPolicy,
+) {
+    for &pass in passes {
+        run_pass(pass.name(), hir, width, arith, |hir| match pass {
+            Pass::Group => pass_group(hir, width, arith),
+            Pass::FindSetNull => pass_find_set_null(hir),
+            Pass::SetN => pass_set_n(hir, width, arith),
+            Pass::OutConst => pass_out_const(alloc, hir),
+            Pass::OutRepeat => pass_out_repeat(hir),
+            Pass::CancelLeftRightAddSub => pass_cancel_left_right_add_sub(hir, arith),
+            Pass::AddSubOffset => pass_add_sub_offset(hir),
+            Pass::SetNOffset => pass_set_n_offset(hir),
+            Pass::BalancedMoves => pass_balanced_moves(hir),
+            Pass::DeadStartLoop => pass_dead_start_loop(hir),
+            Pass::DeadLoop => pass_dead_loop(hir),
+            Pass::MoveAddTo => pass_move_add_to(hir),
+            Pass::MoveMul => pass_move_mul(alloc, hir),
+            Pass::ScanLoop => pass_scan_loop(hir),
+            Pass::RedundantLoopAfterLoop => pass_redundant_loop_after_loop(hir),
+            Pass::RemoveNops => pass_remove_nops(hir),
+        });
+    }
+}
+
+/// Runs the pass, and checks that it didn't change the behavior with the `verify-passes` feature
+#[allow(unused_variables)]
+fn run_pass<'hir>(
+    name: &str,
+    hir: &mut Hir<'hir>,
+    width: CellWidth,
+    arith: ArithPolicy,
+    pass: impl FnOnce(&mut Hir<'hir>),
+) {
+    #[cfg(feature = "verify-passes")]
+    let before = hir.clone();
+
+    pass(hir);
+
+    #[cfg(feature = "verify-passes")]
+    crate::hir::verify::verify_pass(name, &before, hir, width, arith);
 }
 
 /// pass that replaces things like `Sub(1) Sub(1)` with `Sub(2)`
-// TODO: This pass is really slow, speed it up please
-#[tracing::instrument(skip(alloc, ir_param))]
-fn pass_group<'hir>(alloc: &'hir Bump, ir_param: &mut Hir<'hir>) {
-    let empty_ir = Hir {
-        stmts: Vec::new_in(alloc),
-    };
+///
+/// The statements are compacted in place, every statement is either merged into the last kept
+/// one or moved right behind it.
+///
+/// 8 bit and single bit cells wrap around modulo a divisor of 256, so 300 `+` are `Add(44)` for
+/// them. Saturating cells are full after 255 `+` already, or after a single one for bits. Wider
+/// cells can't do either, for them the statements are only merged up to 255.
+#[tracing::instrument(skip(ir))]
+pub fn pass_group(ir: &mut Hir<'_>, width: CellWidth, arith: ArithPolicy) {
+    let stmts = &mut ir.stmts;
+    let mut kept = 0;
 
-    let ir = std::mem::replace(ir_param, empty_ir);
-
-    let new_stmts = Vec::new_in(alloc);
-    let stmts =
-        ir.stmts
-            .into_iter()
-            .fold(new_stmts, |mut stmts: BumpVec<'hir, Stmt<'hir>>, next| {
-                let Some(old) = stmts.last_mut() else {
-                if let StmtKind::Loop(mut body) = next.kind {
-                    pass_group(alloc, &mut body);
-                    stmts.push(Stmt::new(
-                         StmtKind::Loop(body),
-                        next.span,
-                    ));
-                } else {
-                    stmts.push(next);
+    for next in 0..stmts.len() {
+        if let StmtKind::Loop(body) = &mut stmts[next].kind {
+            pass_group(body, width, arith);
+        }
+
+        if kept > 0 {
+            let (done, rest) = stmts.split_at_mut(next);
+            if merge_group(&mut done[kept - 1], &rest[0], width, arith) {
+                continue;
+            }
+        }
+        stmts.swap(kept, next);
+        kept += 1;
+    }
+
+    stmts.truncate(kept);
+}
+
+/// Merges `next` into `old` if they are the same kind of statement, returns whether it did
+fn merge_group(old: &mut Stmt<'_>, next: &Stmt<'_>, width: CellWidth, arith: ArithPolicy) -> bool {
+    let merged = match (&mut old.kind, &next.kind) {
+        (StmtKind::Add(offset_a, a), StmtKind::Add(offset_b, b))
+        | (StmtKind::Sub(offset_a, a), StmtKind::Sub(offset_b, b))
+            if *offset_a == *offset_b =>
+        {
+            let sum = match (width, arith) {
+                (CellWidth::U16 | CellWidth::U32, _) => a.checked_add(*b),
+                (CellWidth::U8 | CellWidth::Bit, ArithPolicy::Wrap) => Some(a.wrapping_add(*b)),
+                (CellWidth::U8 | CellWidth::Bit, ArithPolicy::Saturate) => {
+                    Some(a.saturating_add(*b).min(saturated(width)))
                 }
-                return stmts;
             };
-
-                match (&mut old.kind, next.kind) {
-                    (StmtKind::Add(offset_a, a), StmtKind::Add(offset_b, b))
-                        if *a < 255 && *offset_a == offset_b =>
-                    {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (StmtKind::Sub(offset_a, a), StmtKind::Sub(offset_b, b))
-                        if *a < 255 && *offset_a == offset_b =>
-                    {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (StmtKind::Right(a), StmtKind::Right(b)) if *a < 255 => {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (StmtKind::Left(a), StmtKind::Left(b)) if *a < 255 => {
-                        old.span = old.span.merge(next.span);
-                        *a += b;
-                    }
-                    (_, StmtKind::Loop(mut body)) => {
-                        pass_group(alloc, &mut body);
-                        stmts.push(Stmt {
-                            span: next.span,
-                            kind: StmtKind::Loop(body),
-                        });
-                    }
-                    (_, kind) => {
-                        stmts.push(Stmt::new(kind, next.span));
-                    }
+            match sum {
+                Some(sum) => {
+                    *a = sum;
+                    true
                 }
+                None => false,
+            }
+        }
+        (StmtKind::Right(a), StmtKind::Right(b)) | (StmtKind::Left(a), StmtKind::Left(b))
+            if *a < 255 =>
+        {
+            *a += b;
+            true
+        }
+        _ => false,
+    };
 
-                stmts
-            });
+    if merged {
+        old.span = old.span.merge(next.span);
+    }
+    merged
+}
 
-    *ir_param = Hir { stmts };
+/// The value a saturating cell of this width stops at, as far as it fits into the `u8` of the
+/// statements
+fn saturated(width: CellWidth) -> u8 {
+    match width {
+        CellWidth::Bit => 1,
+        CellWidth::U8 | CellWidth::U16 | CellWidth::U32 => u8::MAX,
+    }
 }
 
 /// pass that replaces `Loop([Sub(_)])` to `SetNull`
 #[tracing::instrument(skip(ir))]
-fn pass_find_set_null(ir: &mut Hir<'_>) {
+pub fn pass_find_set_null(ir: &mut Hir<'_>) {
     pass_find_set_null_inner(ir)
 }
 
@@ -113,67 +287,190 @@ fn pass_find_set_null_inner(ir: &mut Hir<'_>) {
     }
 }
 
-/// pass that replaces `SetN(n) Add(m)` with `SetN(n + m)`
+/// pass that replaces `SetN(n) Add(m)` with `SetN(n + m)` and `SetN(n) SetN(m)` with `SetN(m)`
+///
+/// Cells wider than 8 bits don't wrap around or saturate at 255, so for them it is only done if
+/// the result stays in `0..=255`.
+///
+/// `SetN(n) In` is left alone, the cell keeps its value at EOF with `EofBehavior::Unchanged`.
 #[tracing::instrument(skip(ir))]
-fn pass_set_n(ir: &mut Hir<'_>) {
-    pass_set_n_inner(ir)
-}
-fn pass_set_n_inner(ir: &mut Hir<'_>) {
-    window_pass(ir, pass_set_n_inner, |[a, b]| {
-        if let StmtKind::SetN(before) = a.kind() {
-            let new = match b.kind() {
-                StmtKind::Add(0, n) => StmtKind::SetN(before.wrapping_add(*n)),
-                StmtKind::Sub(0, n) => StmtKind::SetN(before.wrapping_sub(*n)),
-                _ => {
-                    return WindowPassAction::None;
-                }
+pub fn pass_set_n(ir: &mut Hir<'_>, width: CellWidth, arith: ArithPolicy) {
+    pass_set_n_inner(ir, width, arith)
+}
+fn pass_set_n_inner(ir: &mut Hir<'_>, width: CellWidth, arith: ArithPolicy) {
+    let is_byte = matches!(width, CellWidth::U8 | CellWidth::Bit);
+    let max = saturated(width);
+    window_pass(
+        ir,
+        |body| pass_set_n_inner(body, width, arith),
+        |[a, b]| {
+            if let StmtKind::SetN(before) = a.kind() {
+                let new = match (b.kind(), arith) {
+                    (StmtKind::SetN(n), _) => Some(*n),
+                    (StmtKind::Add(0, n), ArithPolicy::Wrap) if is_byte => {
+                        Some(before.wrapping_add(*n))
+                    }
+                    (StmtKind::Sub(0, n), ArithPolicy::Wrap) if is_byte => {
+                        Some(before.wrapping_sub(*n))
+                    }
+                    (StmtKind::Add(0, n), ArithPolicy::Saturate) if is_byte => {
+                        Some(before.saturating_add(*n).min(max))
+                    }
+                    (StmtKind::Sub(0, n), ArithPolicy::Saturate) if is_byte => {
+                        Some((*before).min(max).saturating_sub(*n))
+                    }
+                    (StmtKind::Add(0, n), _) => before.checked_add(*n),
+                    (StmtKind::Sub(0, n), _) => before.checked_sub(*n),
+                    _ => None,
+                };
+                return match new {
+                    Some(new) => WindowPassAction::Merge(StmtKind::SetN(new)),
+                    None => WindowPassAction::None,
+                };
+            }
+            WindowPassAction::None
+        },
+    );
+}
+
+/// pass that replaces `SetN(n) Out` with `OutConst(n) SetN(n)`, which writes the byte without
+/// reading the tape
+///
+/// The `SetN` is moved behind all the `OutConst`s after it, so that it ends up right before the
+/// next `SetN` in `SetN(72) Out SetN(105) Out`, which overwrites it in the next round of
+/// `pass_set_n`. If the cell is read again instead, the `SetN` stays.
+#[tracing::instrument(skip(alloc, ir))]
+pub fn pass_out_const<'hir>(alloc: &'hir Bump, ir: &mut Hir<'hir>) {
+    pass_out_const_inner(alloc, ir)
+}
+
+fn pass_out_const_inner<'hir>(alloc: &'hir Bump, ir: &mut Hir<'hir>) {
+    window_pass(
+        ir,
+        |body| pass_out_const_inner(alloc, body),
+        |[a, b]| {
+            let StmtKind::SetN(n) = *a.kind() else {
+                return WindowPassAction::None;
             };
-            return WindowPassAction::Merge(new);
-        }
-        WindowPassAction::None
-    });
+            let out = match b.kind() {
+                StmtKind::Out => Stmt::new(StmtKind::OutConst(n), b.span),
+                StmtKind::OutConst(_) => b.clone(),
+                _ => return WindowPassAction::None,
+            };
+            let mut new = Vec::new_in(alloc);
+            new.push(out);
+            new.push(a.clone());
+            WindowPassAction::MergeMany(new)
+        },
+    );
 }
 
-/// pass that replaces `Left(5) Right(3)` with `Left(2)`
+/// pass that merges the `OutConst`s of the same byte that `pass_out_const` leaves behind, like
+/// the ones from `SetN(45) Out Out Out`, into a single `OutRepeat`. The LIR only has room for a
+/// `u32` count, so longer repeats are split.
 #[tracing::instrument(skip(ir))]
-fn pass_cancel_left_right_add_sub(ir: &mut Hir<'_>) {
-    pass_cancel_left_right_add_sub_inner(ir)
+pub fn pass_out_repeat(ir: &mut Hir<'_>) {
+    pass_out_repeat_inner(ir)
 }
 
-fn pass_cancel_left_right_add_sub_inner(ir: &mut Hir<'_>) {
-    window_pass(ir, pass_cancel_left_right_add_sub_inner, |[a, b]| {
-        match (a.kind(), b.kind()) {
-            (StmtKind::Right(r), StmtKind::Left(l)) | (StmtKind::Left(l), StmtKind::Right(r)) => {
-                let new = match r.cmp(l) {
-                    Ordering::Equal => {
-                        return WindowPassAction::RemoveAll;
+fn pass_out_repeat_inner(ir: &mut Hir<'_>) {
+    window_pass(ir, pass_out_repeat_inner, |[a, b]| {
+        let repeated = |kind: &StmtKind<'_>| match *kind {
+            StmtKind::OutConst(byte) => Some((byte, 1)),
+            StmtKind::OutRepeat { byte, count } => Some((byte, count)),
+            _ => None,
+        };
+        match (repeated(a.kind()), repeated(b.kind())) {
+            (Some((byte, count_a)), Some((byte_b, count_b))) if byte == byte_b => {
+                match count_a.checked_add(count_b) {
+                    Some(count) if u32::try_from(count).is_ok() => {
+                        WindowPassAction::Merge(StmtKind::OutRepeat { byte, count })
                     }
-                    Ordering::Less => StmtKind::Left(l - r),
-                    Ordering::Greater => StmtKind::Right(r - l),
-                };
-
-                WindowPassAction::Merge(new)
+                    _ => WindowPassAction::None,
+                }
             }
-            (StmtKind::Add(offset_a, r), StmtKind::Sub(offset_b, l))
-            | (StmtKind::Sub(offset_a, l), StmtKind::Add(offset_b, r))
-                if offset_a == offset_b =>
-            {
-                let new = match r.cmp(l) {
-                    Ordering::Equal => return WindowPassAction::RemoveAll,
-                    Ordering::Less => StmtKind::Sub(*offset_a, l - r),
-                    Ordering::Greater => StmtKind::Add(*offset_a, r - l),
-                };
+            _ => WindowPassAction::None,
+        }
+    });
+}
 
-                WindowPassAction::Merge(new)
+/// pass that removes the loops at the very start of the program, which are often used for
+/// comments. They are never entered because the tape starts out zeroed.
+///
+/// This is only true for the start of the whole program, not for the start of a loop body or a
+/// snippet that runs on a tape that was used before, see [`optimize_snippet`].
+#[tracing::instrument(skip(ir))]
+pub fn pass_dead_start_loop(ir: &mut Hir<'_>) {
+    for stmt in &mut ir.stmts {
+        match stmt.kind {
+            StmtKind::Nop => {}
+            StmtKind::Loop(_) => {
+                trace!(span = ?stmt.span, "Removing loop at the start of the program");
+                stmt.kind = StmtKind::Nop;
             }
+            _ => break,
+        }
+    }
+}
+
+/// pass that removes loops that are never entered because the current cell is known to be zero,
+/// like `SetN(0) Loop(_)`
+#[tracing::instrument(skip(ir))]
+pub fn pass_dead_loop(ir: &mut Hir<'_>) {
+    pass_dead_loop_inner(ir)
+}
+
+fn pass_dead_loop_inner(ir: &mut Hir<'_>) {
+    window_pass(ir, pass_dead_loop_inner, |[a, b]| {
+        match (a.kind(), b.kind()) {
+            (StmtKind::SetN(0), StmtKind::Loop(_)) => WindowPassAction::Merge(StmtKind::SetN(0)),
             _ => WindowPassAction::None,
         }
     })
 }
 
+/// pass that replaces `Left(5) Right(3)` with `Left(2)`
+///
+/// `Add(5) Sub(3)` is only replaced with `Add(2)` for wrapping cells, a saturating cell at 254 would
+/// end up at 252 instead of 255.
+#[tracing::instrument(skip(ir))]
+pub fn pass_cancel_left_right_add_sub(ir: &mut Hir<'_>, arith: ArithPolicy) {
+    pass_cancel_left_right_add_sub_inner(ir, arith)
+}
+
+fn pass_cancel_left_right_add_sub_inner(ir: &mut Hir<'_>, arith: ArithPolicy) {
+    let recur = |body: &mut Hir<'_>| pass_cancel_left_right_add_sub_inner(body, arith);
+    window_pass(ir, recur, |[a, b]| match (a.kind(), b.kind()) {
+        (StmtKind::Right(r), StmtKind::Left(l)) | (StmtKind::Left(l), StmtKind::Right(r)) => {
+            let new = match r.cmp(l) {
+                Ordering::Equal => {
+                    return WindowPassAction::RemoveAll;
+                }
+                Ordering::Less => StmtKind::Left(l - r),
+                Ordering::Greater => StmtKind::Right(r - l),
+            };
+
+            WindowPassAction::Merge(new)
+        }
+        (StmtKind::Add(offset_a, r), StmtKind::Sub(offset_b, l))
+        | (StmtKind::Sub(offset_a, l), StmtKind::Add(offset_b, r))
+            if offset_a == offset_b && arith == ArithPolicy::Wrap =>
+        {
+            let new = match r.cmp(l) {
+                Ordering::Equal => return WindowPassAction::RemoveAll,
+                Ordering::Less => StmtKind::Sub(*offset_a, l - r),
+                Ordering::Greater => StmtKind::Add(*offset_a, r - l),
+            };
+
+            WindowPassAction::Merge(new)
+        }
+        _ => WindowPassAction::None,
+    })
+}
+
 /// pass that replaces `Right(9) Add(5) Left(9)` with `AddOffset(9, 5)`
 #[tracing::instrument(skip(ir))]
-fn pass_add_sub_offset(ir: &mut Hir<'_>) {
+pub fn pass_add_sub_offset(ir: &mut Hir<'_>) {
     pass_add_sub_offset_inner(ir)
 }
 fn pass_add_sub_offset_inner(ir: &mut Hir<'_>) {
@@ -196,9 +493,132 @@ fn pass_add_sub_offset_inner(ir: &mut Hir<'_>) {
     })
 }
 
-/// pass that replaces `Loop([Sub(1) AddOffset(o, 1)])` with `MoveAddTo(o)`
+/// pass that replaces `Right(9) SetN(5) Left(9)` with `SetNOffset(9, 5)`, like `pass_add_sub_offset`
 #[tracing::instrument(skip(ir))]
-fn pass_move_add_to(ir: &mut Hir<'_>) {
+pub fn pass_set_n_offset(ir: &mut Hir<'_>) {
+    pass_set_n_offset_inner(ir)
+}
+fn pass_set_n_offset_inner(ir: &mut Hir<'_>) {
+    window_pass(ir, pass_set_n_offset_inner, |[a, b, c]| {
+        match (a.kind(), b.kind(), c.kind()) {
+            (StmtKind::Right(r), StmtKind::SetN(n), StmtKind::Left(l)) if r == l => {
+                WindowPassAction::Merge(StmtKind::SetNOffset {
+                    offset: i32::try_from(*r).unwrap(),
+                    n: *n,
+                })
+            }
+            (StmtKind::Left(l), StmtKind::SetN(n), StmtKind::Right(r)) if r == l => {
+                WindowPassAction::Merge(StmtKind::SetNOffset {
+                    offset: -i32::try_from(*r).unwrap(),
+                    n: *n,
+                })
+            }
+            _ => WindowPassAction::None,
+        }
+    })
+}
+
+/// How far the pointer is from where it started after running `body` once, for passes that need
+/// to know whether a loop body returns to its cell. Offsets don't move the pointer.
+///
+/// Nested loops that return to their cell don't move it either, but `None` is returned for other
+/// nested loops and for scans, where the distance depends on the tape. It is also `None` if the
+/// distance doesn't fit into an `i32`.
+pub fn net_pointer_movement(body: &Hir<'_>) -> Option<i32> {
+    let mut pos = 0i64;
+    for stmt in &body.stmts {
+        match stmt.kind() {
+            StmtKind::Right(n) => pos += i64::try_from(*n).ok()?,
+            StmtKind::Left(n) => pos -= i64::try_from(*n).ok()?,
+            StmtKind::Loop(inner) => {
+                if net_pointer_movement(inner)? != 0 {
+                    return None;
+                }
+            }
+            StmtKind::ScanRight(_) | StmtKind::ScanLeft(_) => return None,
+            _ => {}
+        }
+    }
+    i32::try_from(pos).ok()
+}
+
+/// pass that replaces pointer moves that cancel out with offsets on the statements in between,
+/// like `Right(3) Add(0, 3) Right(1) Add(0, 2) Left(4)` with `Add(3, 3) Add(4, 2)`
+///
+/// `pass_add_sub_offset` only handles a single statement between the moves, this handles
+/// any amount of them. This often removes the moves between two loops entirely.
+#[tracing::instrument(skip(ir))]
+pub fn pass_balanced_moves(ir: &mut Hir<'_>) {
+    pass_balanced_moves_inner(ir)
+}
+
+fn pass_balanced_moves_inner(ir: &mut Hir<'_>) {
+    let stmts = &mut ir.stmts;
+    let mut start = 0;
+    while start < stmts.len() {
+        match &mut stmts[start].kind {
+            StmtKind::Loop(body) => {
+                pass_balanced_moves_inner(body);
+                start += 1;
+                continue;
+            }
+            StmtKind::Right(_) | StmtKind::Left(_) => {}
+            _ => {
+                start += 1;
+                continue;
+            }
+        }
+
+        // find the end of the segment where the pointer is back where it started
+        let mut pos = 0i64;
+        let mut end = None;
+        for (idx, stmt) in stmts.iter().enumerate().skip(start) {
+            match stmt.kind {
+                StmtKind::Right(n) => pos += n as i64,
+                StmtKind::Left(n) => pos -= n as i64,
+                StmtKind::Add(..) | StmtKind::Sub(..) | StmtKind::Nop => continue,
+                _ => break,
+            }
+            if pos == 0 {
+                end = Some(idx);
+                break;
+            }
+        }
+
+        let Some(end) = end else {
+            start += 1;
+            continue;
+        };
+
+        trace!(span = ?stmts[start].span, "Replacing balanced moves with offsets");
+        let mut pos = 0i64;
+        for stmt in &mut stmts[start..=end] {
+            match &mut stmt.kind {
+                StmtKind::Right(n) => {
+                    pos += *n as i64;
+                    stmt.kind = StmtKind::Nop;
+                }
+                StmtKind::Left(n) => {
+                    pos -= *n as i64;
+                    stmt.kind = StmtKind::Nop;
+                }
+                StmtKind::Add(offset, _) | StmtKind::Sub(offset, _) => {
+                    *offset += i32::try_from(pos).unwrap();
+                }
+                _ => {}
+            }
+        }
+        start = end + 1;
+    }
+}
+
+/// pass that replaces `Loop([Sub(1) AddOffset(o, 1)])` with `MoveAddTo(o)` and
+/// `Loop([Sub(1) SubOffset(o, 1)])` with `MoveSubTo(o)`
+///
+/// `pass_add_sub_offset` has already turned the `>>>+<<<` of the loop body into `AddOffset(3, 1)`,
+/// so this handles destinations at any distance to the left or right.
+#[tracing::instrument(skip(ir))]
+pub fn pass_move_add_to(ir: &mut Hir<'_>) {
     pass_move_add_to_inner(ir)
 }
 
@@ -209,36 +629,210 @@ fn pass_move_add_to_inner(ir: &mut Hir<'_>) {
             span,
         } = stmt
         {
-            if let [Stmt {
-                kind: StmtKind::Sub(0, 1),
-                ..
-            }, Stmt {
-                kind: StmtKind::Add(offset, 1),
-                ..
-            }]
-            | [Stmt {
-                kind: StmtKind::Add(offset, 1),
+            let kind = match body.stmts.as_slice() {
+                [Stmt {
+                    kind: StmtKind::Sub(0, 1),
+                    ..
+                }, Stmt {
+                    kind: StmtKind::Add(offset, 1),
+                    ..
+                }]
+                | [Stmt {
+                    kind: StmtKind::Add(offset, 1),
+                    ..
+                }, Stmt {
+                    kind: StmtKind::Sub(0, 1),
+                    ..
+                }] => StmtKind::MoveAddTo { offset: *offset },
+                [Stmt {
+                    kind: StmtKind::Sub(0, 1),
+                    ..
+                }, Stmt {
+                    kind: StmtKind::Sub(offset, 1),
+                    ..
+                }]
+                | [Stmt {
+                    kind: StmtKind::Sub(offset, 1),
+                    ..
+                }, Stmt {
+                    kind: StmtKind::Sub(0, 1),
+                    ..
+                }] if *offset != 0 => StmtKind::MoveSubTo { offset: *offset },
+                _ => {
+                    pass_move_add_to_inner(body);
+                    continue;
+                }
+            };
+            trace!(?span, ?kind, "Replacing Statement with move");
+            *stmt = Stmt::new(kind, *span);
+        }
+    }
+}
+
+/// pass that replaces multiplication loops like `Loop([Sub(1) AddOffset(1, 3) AddOffset(2, 2)])`
+/// with `MoveMulTo([(1, 3), (2, 2)])`
+///
+/// The pointer has to be back at the start after every iteration, which `pass_balanced_moves` has
+/// already turned into offsets, and the loop cell must be decremented by exactly one. Anything
+/// else in the body leaves the loop as it is. Loops with a single target and a factor of one are
+/// `MoveAddTo` already.
+#[tracing::instrument(skip(alloc, ir))]
+pub fn pass_move_mul<'hir>(alloc: &'hir Bump, ir: &mut Hir<'hir>) {
+    pass_move_mul_inner(alloc, ir)
+}
+
+fn pass_move_mul_inner<'hir>(alloc: &'hir Bump, ir: &mut Hir<'hir>) {
+    for stmt in &mut ir.stmts {
+        let StmtKind::Loop(body) = &mut stmt.kind else {
+            continue;
+        };
+
+        let mut decrements = 0;
+        let mut targets = Vec::new_in(alloc);
+        let is_mul_loop = body.stmts.iter().all(|stmt| match *stmt.kind() {
+            StmtKind::Sub(0, 1) => {
+                decrements += 1;
+                true
+            }
+            StmtKind::Add(offset, n) if offset != 0 => {
+                targets.push((offset, n));
+                true
+            }
+            _ => false,
+        });
+
+        if is_mul_loop && decrements == 1 && !targets.is_empty() {
+            trace!(span = ?stmt.span, ?targets, "Replacing Statement with MoveMulTo");
+            stmt.kind = StmtKind::MoveMulTo { targets };
+        } else {
+            pass_move_mul_inner(alloc, body);
+        }
+    }
+}
+
+/// pass that replaces `Loop([Right(n)])` with `ScanRight(n)` and `Loop([Left(n)])` with
+/// `ScanLeft(n)`, which the interpreter can run without going through the loop every time
+#[tracing::instrument(skip(ir))]
+pub fn pass_scan_loop(ir: &mut Hir<'_>) {
+    pass_scan_loop_inner(ir)
+}
+
+fn pass_scan_loop_inner(ir: &mut Hir<'_>) {
+    for stmt in &mut ir.stmts {
+        let StmtKind::Loop(body) = &mut stmt.kind else {
+            continue;
+        };
+
+        let scan = match body.stmts.as_slice() {
+            [Stmt {
+                kind: StmtKind::Right(n),
                 ..
-            }, Stmt {
-                kind: StmtKind::Sub(0, 1),
+            }] => StmtKind::ScanRight(*n),
+            [Stmt {
+                kind: StmtKind::Left(n),
                 ..
-            }] = body.stmts.as_slice()
-            {
-                trace!(?span, ?offset, "Replacing Statement with MoveAddTo");
-                *stmt = Stmt::new(StmtKind::MoveAddTo { offset: *offset }, *span);
-            } else {
-                pass_move_add_to_inner(body);
+            }] => StmtKind::ScanLeft(*n),
+            _ => {
+                pass_scan_loop_inner(body);
+                continue;
+            }
+        };
+        trace!(span = ?stmt.span, ?scan, "Replacing Statement with scan");
+        stmt.kind = scan;
+    }
+}
+
+/// pass that removes loops right after another loop, like `Loop(a) Loop(b)`. The current cell is
+/// always zero after a loop, so the second one is never entered.
+///
+/// `SetN(0)` and the statements that replaced loops, like `MoveAddTo` or `ScanRight`, leave the
+/// cell at zero as well, and the latter are never entered on a zero cell either.
+#[tracing::instrument(skip(ir))]
+pub fn pass_redundant_loop_after_loop(ir: &mut Hir<'_>) {
+    pass_redundant_loop_after_loop_inner(ir)
+}
+
+fn pass_redundant_loop_after_loop_inner(ir: &mut Hir<'_>) {
+    let is_loop = |kind: &StmtKind<'_>| {
+        matches!(
+            kind,
+            StmtKind::Loop(_)
+                | StmtKind::MoveAddTo { .. }
+                | StmtKind::MoveSubTo { .. }
+                | StmtKind::MoveMulTo { .. }
+                | StmtKind::ScanRight(_)
+                | StmtKind::ScanLeft(_)
+        )
+    };
+
+    let mut cell_zero = false;
+    for stmt in &mut ir.stmts {
+        match &mut stmt.kind {
+            StmtKind::Nop => continue,
+            kind if cell_zero && is_loop(kind) => {
+                trace!(span = ?stmt.span, "Removing loop after loop");
+                stmt.kind = StmtKind::Nop;
+                continue;
+            }
+            StmtKind::Loop(body) => pass_redundant_loop_after_loop_inner(body),
+            _ => {}
+        }
+        cell_zero = is_loop(&stmt.kind) || matches!(stmt.kind, StmtKind::SetN(0));
+    }
+}
+
+/// pass that removes all `Nop`s left behind by other passes
+#[tracing::instrument(skip(ir))]
+pub fn pass_remove_nops(ir: &mut Hir<'_>) {
+    pass_remove_nops_inner(ir)
+}
+
+fn pass_remove_nops_inner(ir: &mut Hir<'_>) {
+    ir.stmts.retain(|stmt| !matches!(stmt.kind, StmtKind::Nop));
+    for stmt in &mut ir.stmts {
+        if let StmtKind::Loop(body) = &mut stmt.kind {
+            pass_remove_nops_inner(body);
+        }
+    }
+}
+
+/// The single arithmetic statement kind left after [`normalize_arith`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithKind {
+    Add,
+    Sub,
+}
+
+/// pass that replaces every `Sub(o, n)` with `Add(o, -n)` (or the other way around), so backends
+/// only have to handle a single arithmetic statement. This is not part of [`optimize`] and can be
+/// run on the optimized HIR before lowering it.
+///
+/// Adding `256 - n` is the same as subtracting `n` for 8 bit and single bit cells, since they wrap
+/// modulo a divisor of 256. This is not the case for wider cells.
+#[tracing::instrument(skip(ir))]
+pub fn normalize_arith(ir: &mut Hir<'_>, into: ArithKind) {
+    for stmt in &mut ir.stmts {
+        match (&mut stmt.kind, into) {
+            (StmtKind::Sub(offset, n), ArithKind::Add) => {
+                stmt.kind = StmtKind::Add(*offset, n.wrapping_neg());
             }
+            (StmtKind::Add(offset, n), ArithKind::Sub) => {
+                stmt.kind = StmtKind::Sub(*offset, n.wrapping_neg());
+            }
+            (StmtKind::Loop(body), _) => normalize_arith(body, into),
+            _ => {}
         }
     }
 }
 
+#[allow(dead_code)]
 #[tracing::instrument(skip(ir))]
 fn pass_unroll_loops(ir: &mut Hir<'_>) {
     let alloc = Bump::new();
     pass_unroll_loops_inner(&alloc, ir);
 }
 
+#[allow(dead_code)]
 fn pass_unroll_loops_inner(alloc: &Bump, ir: &mut Hir<'_>) {
     window_pass(ir, pass_unroll_loops, |[a, b]| {
         if let (StmtKind::SetN(n), StmtKind::Loop(body)) = (a.kind(), b.kind()) {
@@ -264,6 +858,12 @@ enum WindowPassAction<'hir, 'pass> {
     RemoveAll,
 }
 
+/// Runs `action` over every window of `N` consecutive statements, skipping over `Nop`s. Merged
+/// and removed statements are replaced with `Nop`s in place, like the other passes do.
+///
+/// The statements are moved into a fresh vector as soon as no window can change them anymore,
+/// together with the `Nop`s after them. The window only holds the statements it looks at, so a
+/// long run of merges never walks over the `Nop`s it left behind.
 fn window_pass<'hir, 'pass, P, F, const N: usize>(ir: &mut Hir<'hir>, pass_recur: P, action: F)
 where
     P: Fn(&mut Hir<'hir>),
@@ -271,51 +871,670 @@ where
 {
     assert!(N > 0);
 
-    let stmts = &mut ir.stmts;
-    let mut i = 0;
-    while i < stmts.len() {
-        let a = &mut stmts[i];
-        if let StmtKind::Loop(body) = &mut a.kind {
-            pass_recur(body);
-        }
+    let new_stmts = Vec::with_capacity_in(ir.stmts.len(), *ir.stmts.allocator());
+    let mut input = std::mem::replace(&mut ir.stmts, new_stmts).into_iter();
+    // the statements that are looked at next, the first `N` of them are the window
+    let mut window: VecDeque<Windowed<'hir>> = VecDeque::with_capacity(N);
 
-        if i + N > stmts.len() {
-            break; // there aren't N elements left
+    loop {
+        while window.len() < N {
+            let Some(stmt) = input.next() else {
+                break;
+            };
+            match (&stmt.kind, window.back_mut()) {
+                (StmtKind::Nop, Some(last)) => last.nops.push(stmt),
+                (StmtKind::Nop, None) => ir.stmts.push(stmt),
+                _ => window.push_back(Windowed::new(stmt)),
+            }
+        }
+        if let Some(front) = window.front_mut() {
+            if let StmtKind::Loop(body) = &mut front.stmt.kind {
+                pass_recur(body);
+            }
+        }
+        if window.len() < N {
+            // there aren't N elements left, but the loops among them still need to be handled
+            for windowed in window.iter_mut().skip(1) {
+                if let StmtKind::Loop(body) = &mut windowed.stmt.kind {
+                    pass_recur(body);
+                }
+            }
+            for windowed in window {
+                windowed.emit(&mut ir.stmts);
+            }
+            break;
         }
 
-        let mut elements = stmts[i..][..N].iter();
-        let elements = [(); N].map(|()| elements.next().unwrap());
+        let mut window_iter = window.iter();
+        let elements = [(); N].map(|()| &window_iter.next().unwrap().stmt);
 
         let merged_span = elements[0].span.merge(elements.last().unwrap().span);
         let result = action(elements);
 
         match result {
-            WindowPassAction::None => {
-                // only increment i if we haven't removed anything
-                i += 1;
-            }
+            WindowPassAction::None => window.pop_front().unwrap().emit(&mut ir.stmts),
             WindowPassAction::RemoveAll => {
                 trace!(?elements, "Removing all statements");
-                for _ in 0..N {
-                    stmts.remove(i);
+                for windowed in window.drain(..N) {
+                    windowed.remove(&mut ir.stmts);
                 }
             }
             WindowPassAction::Merge(new) => {
                 trace!(?elements, ?new, "Merging statements");
-                for _ in 1..N {
-                    stmts.remove(i);
+                let mut removed = window.drain(..N);
+                let mut merged = Windowed {
+                    stmt: Stmt::new(new, merged_span),
+                    nops: removed.next().unwrap().nops,
+                };
+                for windowed in removed {
+                    windowed.remove(&mut merged.nops);
                 }
-                stmts[i] = Stmt::new(new, merged_span);
+                window.push_front(merged);
             }
             WindowPassAction::MergeMany(new) => {
                 trace!(?elements, ?new, "Merging many");
-                for _ in 0..N {
-                    stmts.remove(i);
+                // the new statements go in front of the removed ones
+                let mut nops = Vec::new();
+                for windowed in window.drain(..N) {
+                    windowed.remove(&mut nops);
                 }
-                for stmt in new.into_iter().rev() {
-                    stmts.insert(i, stmt);
+                let mut new = new.into_iter().rev().map(Windowed::new);
+                match new.next() {
+                    Some(mut last) => {
+                        last.nops = nops;
+                        window.push_front(last);
+                        for windowed in new {
+                            window.push_front(windowed);
+                        }
+                    }
+                    None => ir.stmts.extend(nops),
                 }
             }
         }
     }
 }
+
+/// A statement in the window of `window_pass`, with the `Nop`s between it and the next one
+struct Windowed<'hir> {
+    stmt: Stmt<'hir>,
+    nops: Vec<Stmt<'hir>>,
+}
+
+impl<'hir> Windowed<'hir> {
+    fn new(stmt: Stmt<'hir>) -> Self {
+        Self {
+            stmt,
+            nops: Vec::new(),
+        }
+    }
+
+    /// Moves the statement and the `Nop`s after it to `out`
+    fn emit(self, out: &mut impl Extend<Stmt<'hir>>) {
+        out.extend(std::iter::once(self.stmt).chain(self.nops));
+    }
+
+    /// Moves the statement to `out` as a `Nop`, along with the `Nop`s after it
+    fn remove(mut self, out: &mut impl Extend<Stmt<'hir>>) {
+        self.stmt.kind = StmtKind::Nop;
+        self.emit(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::{
+        hir::{
+            opts::{ArithKind, Pass},
+            Hir, StmtKind,
+        },
+        lir::cell::{ArithPolicy, CellWidth},
+    };
+
+    fn optimized_hir<'hir>(alloc: &'hir Bump, bf: &str) -> Hir<'hir> {
+        let ast = crate::parse::parse(alloc, bf.bytes().enumerate()).unwrap();
+        crate::hir::optimized_hir(alloc, &ast)
+    }
+
+    fn kinds<'a, 'hir>(hir: &'a Hir<'hir>) -> Vec<&'a StmtKind<'hir>> {
+        hir.stmts.iter().map(|stmt| stmt.kind()).collect()
+    }
+
+    #[test]
+    fn nops_compacted() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ">>+<<.".bytes().enumerate()).unwrap();
+        let mut hir = crate::hir::ast_to_ir(&alloc, &ast);
+
+        super::pass_group(&mut hir, CellWidth::U8, ArithPolicy::Wrap);
+        super::pass_add_sub_offset(&mut hir);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::Add(2, 1),
+                StmtKind::Nop,
+                StmtKind::Nop,
+                StmtKind::Out
+            ]
+        ));
+
+        super::pass_remove_nops(&mut hir);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::Add(2, 1), StmtKind::Out]
+        ));
+    }
+
+    #[test]
+    fn debug_not_merged() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ">+#+<");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::Right(1),
+                StmtKind::Add(0, 1),
+                StmtKind::Debug,
+                StmtKind::Add(0, 1),
+                StmtKind::Left(1)
+            ]
+        ));
+    }
+
+    #[test]
+    fn only_given_passes_run() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ">+++[-]".bytes().enumerate()).unwrap();
+
+        let mut hir = crate::hir::ast_to_ir(&alloc, &ast);
+        super::optimize_with(&alloc, &mut hir, &[Pass::Group]);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::Right(1), StmtKind::Add(0, 3), StmtKind::Loop(_)]
+        ));
+
+        let mut hir = crate::hir::ast_to_ir(&alloc, &ast);
+        super::optimize_with(&alloc, &mut hir, &[Pass::Group, Pass::FindSetNull]);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::Right(1), StmtKind::Add(0, 3), StmtKind::SetN(0)]
+        ));
+
+        let mut hir = crate::hir::ast_to_ir(&alloc, &ast);
+        super::optimize_with(&alloc, &mut hir, Pass::DEFAULT);
+        assert_eq!(
+            format!("{hir:?}"),
+            format!("{:?}", optimized_hir(&alloc, ">+++[-]"))
+        );
+    }
+
+    #[test]
+    fn passes_repeated_until_fixed_point() {
+        let alloc = Bump::new();
+        // the loop after `SetN(0)` is only removed after `pass_set_n` ran, which leaves
+        // `SetN(0) Add(0, 1)` behind for it
+        let hir = optimized_hir(&alloc, ",[-][.]+.");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::In, StmtKind::OutConst(1), StmtKind::SetN(1)]
+        ));
+    }
+
+    #[test]
+    fn group_wraps_around() {
+        let alloc = Bump::new();
+        let bf = format!("{}.", "+".repeat(300));
+        let hir = optimized_hir(&alloc, &bf);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::Add(0, 44), StmtKind::Out]
+        ));
+        assert_eq!(crate::run_source(&bf, &[]).unwrap(), [44]);
+
+        // the `><` only cancel out after the first round of passes, which leaves `Add(200)` and
+        // `Add(100)` next to each other for the second one
+        let bf = format!("{}><{}.", "+".repeat(200), "+".repeat(100));
+        let hir = optimized_hir(&alloc, &bf);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::Add(0, 44), StmtKind::Out]
+        ));
+    }
+
+    #[test]
+    fn group_for_other_cells() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+".repeat(300).bytes().enumerate()).unwrap();
+
+        let group = |width, arith| {
+            let mut hir = crate::hir::ast_to_ir(&alloc, &ast);
+            super::pass_group(&mut hir, width, arith);
+            hir.stmts
+                .iter()
+                .map(|stmt| match stmt.kind() {
+                    StmtKind::Add(0, n) => *n,
+                    kind => panic!("{kind:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(group(CellWidth::U8, ArithPolicy::Saturate), [255]);
+        assert_eq!(group(CellWidth::U16, ArithPolicy::Wrap), [255, 45]);
+    }
+
+    #[test]
+    fn window_pass_reaches_last_loop() {
+        let alloc = Bump::new();
+        // there are fewer statements than a window of three around the loop
+        let hir = optimized_hir(&alloc, ",[>+<.-]");
+        let [StmtKind::In, StmtKind::Loop(body)] = kinds(&hir).as_slice() else {
+            panic!("{hir:?}");
+        };
+        assert!(matches!(
+            kinds(body).as_slice(),
+            [StmtKind::Add(1, 1), StmtKind::Out, StmtKind::Sub(0, 1)]
+        ));
+    }
+
+    #[test]
+    fn dead_loop_at_program_start() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, "[+][a comment.]+.");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::Add(0, 1), StmtKind::Out]
+        ));
+    }
+
+    #[test]
+    fn loop_after_input_kept() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ",[+]");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::In, StmtKind::Loop(_)]
+        ));
+    }
+
+    #[test]
+    fn set_n_overwritten() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ",[-]++[-]+++.[-],.");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::In,
+                StmtKind::OutConst(3),
+                StmtKind::SetN(0),
+                StmtKind::In,
+                StmtKind::Out
+            ]
+        ));
+    }
+
+    #[test]
+    fn dead_loop_after_set_null() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ",[-][+.]+[[+.]-]");
+        // `SetN(0) Add(0, 1)` is only folded after the loop in between was removed
+        let [StmtKind::In, StmtKind::SetN(1), StmtKind::Loop(body)] = kinds(&hir).as_slice()
+        else {
+            panic!("{hir:?}");
+        };
+        // the start of a loop body isn't known to be zero
+        assert!(matches!(
+            kinds(body).as_slice(),
+            [StmtKind::Loop(_), StmtKind::Sub(0, 1)]
+        ));
+    }
+
+    #[test]
+    fn loop_after_loop_removed() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ",[.-][.+][->+<]>,[>][<][,[.-][-]]");
+        let [StmtKind::In, StmtKind::Loop(_), StmtKind::Right(1), StmtKind::In, StmtKind::ScanRight(1)] =
+            kinds(&hir).as_slice()
+        else {
+            panic!("{hir:?}");
+        };
+
+        let hir = optimized_hir(&alloc, ",[,[.-][.+]]");
+        let [StmtKind::In, StmtKind::Loop(body)] = kinds(&hir).as_slice() else {
+            panic!("{hir:?}");
+        };
+        assert!(matches!(
+            kinds(body).as_slice(),
+            [StmtKind::In, StmtKind::Loop(_)]
+        ));
+    }
+
+    #[test]
+    fn balanced_moves_between_loops() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, "++[-.]>>>+++>++<<<<+[-.]>>>.>.");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::Add(0, 2),
+                StmtKind::Loop(_),
+                StmtKind::Add(3, 3),
+                StmtKind::Add(4, 2),
+                StmtKind::Add(0, 1),
+                StmtKind::Loop(_),
+                ..
+            ]
+        ));
+        assert_eq!(run(&hir), [1, 0, 0, 3, 2]);
+    }
+
+    #[test]
+    fn move_add_to_right() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ",[>>>+<<<-]");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::In, StmtKind::MoveAddTo { offset: 3 }]
+        ));
+    }
+
+    #[test]
+    fn move_mul() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, "+++++[->+++>++<<]>.>.");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::Add(0, 5),
+                StmtKind::MoveMulTo { targets },
+                ..
+            ] if targets.as_slice() == [(1, 3), (2, 2)]
+        ));
+        assert_eq!(run(&hir), [15, 10]);
+    }
+
+    #[test]
+    fn move_mul_left_alone() {
+        let alloc = Bump::new();
+        // the pointer doesn't return, the loop cell is decremented by two, and by one twice
+        for bf in [",[->+++>++<]", ",[-->+++<]", ",[->+++<-]"] {
+            let hir = optimized_hir(&alloc, bf);
+            assert!(
+                matches!(kinds(&hir).as_slice(), [StmtKind::In, StmtKind::Loop(_)]),
+                "{bf} was optimized to {hir:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn scan_loops_same_as_naive() {
+        let alloc = Bump::new();
+        // a sparse tape of `1 1 1 0 1 1 0 0 1`, then scans left from the end, right from the
+        // start, and right in steps of two, marking where each of them stops
+        let bf = "+>+>+>>+>+>>>+[<]++<<<<<<<[>]+++>[>>]++++<<<<<<.>.>.>.>.>.>.>.>.";
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+
+        let naive = crate::hir::ast_to_ir(&alloc, &ast);
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::ScanLeft(1)
+        )));
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::ScanRight(1)
+        )));
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::ScanRight(2)
+        )));
+
+        assert_eq!(run(&hir), [1, 1, 1, 3, 1, 1, 4, 2, 1]);
+        assert_eq!(run(&hir), run(&naive));
+    }
+
+    #[test]
+    fn saturating_bits_same_as_naive() {
+        use crate::lir::interpreter::RunConfig;
+
+        let config = RunConfig {
+            cell_width: CellWidth::Bit,
+            arith_policy: ArithPolicy::Saturate,
+            ..RunConfig::default()
+        };
+        let run = |hir: &Hir<'_>| {
+            let alloc = Bump::new();
+            let lir = crate::lir::generate(&alloc, hir);
+            let mut stdout = Vec::new();
+            crate::lir::interpreter::run(&lir, &mut stdout, [].as_slice(), &config, |_| {})
+                .unwrap();
+            stdout
+        };
+
+        // every `+` sets the bit and every `-` clears it, no matter how many came before
+        for bf in [
+            "[-]++-.",
+            "[-]+--+.",
+            "[-]+++-.>+++--+.",
+            "[-]++[-]+.-.",
+            "+>+<++-[->+<]>.",
+        ] {
+            let alloc = Bump::new();
+            let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+            let naive = crate::hir::ast_to_ir(&alloc, &ast);
+            let hir = crate::hir::optimized_hir_for_cells(
+                &alloc,
+                &ast,
+                config.cell_width,
+                config.arith_policy,
+            );
+            assert_eq!(run(&hir), run(&naive), "{bf}");
+        }
+    }
+
+    #[test]
+    fn out_repeat() {
+        let alloc = Bump::new();
+        let bf = format!(",[-]{}.....", "+".repeat(65));
+        let hir = optimized_hir(&alloc, &bf);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::In,
+                StmtKind::OutRepeat { byte: 65, count: 5 },
+                StmtKind::SetN(65)
+            ]
+        ));
+        assert_eq!(run(&hir), b"AAAAA");
+
+        // different bytes are not merged
+        let bf = format!(",[-]{}..+.", "+".repeat(65));
+        let hir = optimized_hir(&alloc, &bf);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::In,
+                StmtKind::OutRepeat { byte: 65, count: 2 },
+                StmtKind::OutConst(66),
+                StmtKind::SetN(66)
+            ]
+        ));
+    }
+
+    #[test]
+    fn out_repeat_fits_into_lir() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "..".bytes().enumerate()).unwrap();
+        let max = u32::MAX as usize;
+        let with_repeat = |count| {
+            let mut hir = crate::hir::ast_to_ir(&alloc, &ast);
+            hir.stmts[0].kind = StmtKind::OutRepeat { byte: 65, count };
+            hir.stmts[1].kind = StmtKind::OutConst(65);
+            super::pass_out_repeat(&mut hir);
+            hir
+        };
+
+        let hir = with_repeat(max - 1);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::OutRepeat { byte: 65, count }, StmtKind::Nop] if *count == max
+        ));
+
+        let hir = with_repeat(max);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::OutRepeat { byte: 65, count },
+                StmtKind::OutConst(65)
+            ] if *count == max
+        ));
+    }
+
+    #[test]
+    fn out_const() {
+        let alloc = Bump::new();
+        let bf = format!(",[-]{}..[-]{}.", "+".repeat(72), "+".repeat(105));
+        let hir = optimized_hir(&alloc, &bf);
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::In,
+                StmtKind::OutRepeat { byte: 72, count: 2 },
+                StmtKind::OutConst(105),
+                StmtKind::SetN(105)
+            ]
+        ));
+        assert_eq!(run(&hir), b"HHi");
+
+        // the loop reads the cell, so the `SetN` has to stay
+        let hir = optimized_hir(&alloc, ",[-]++.[>+<-]>.");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [
+                StmtKind::In,
+                StmtKind::OutConst(2),
+                StmtKind::SetN(2),
+                StmtKind::MoveAddTo { offset: 1 },
+                StmtKind::Right(1),
+                StmtKind::Out
+            ]
+        ));
+        assert_eq!(run(&hir), [2, 2]);
+    }
+
+    #[test]
+    fn set_n_offset_same_as_naive() {
+        let alloc = Bump::new();
+        // clears a cell two to the right and sets it to 5, then clears a cell two to the left,
+        // and dumps the tape
+        let bf = "+>++>+++>++++<<<.>>[-]+++++<<.>>>.<<[-]>>#";
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+
+        let naive = crate::hir::ast_to_ir(&alloc, &ast);
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::SetNOffset { offset: 2, n: 5 }
+        )));
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::SetNOffset { offset: -2, n: 0 }
+        )));
+
+        assert_eq!(run(&hir), run(&naive));
+    }
+
+    #[test]
+    fn move_add_to_left() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, ",[-<<+>>]");
+        assert!(matches!(
+            kinds(&hir).as_slice(),
+            [StmtKind::In, StmtKind::MoveAddTo { offset: -2 }]
+        ));
+    }
+
+    #[test]
+    fn net_pointer_movement() {
+        let alloc = Bump::new();
+        let movement = |bf: &str| {
+            let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+            super::net_pointer_movement(&crate::hir::ast_to_ir(&alloc, &ast))
+        };
+
+        assert_eq!(movement(""), Some(0));
+        assert_eq!(movement("->+<"), Some(0));
+        assert_eq!(movement(">>+<."), Some(1));
+        assert_eq!(movement("<<<-,"), Some(-3));
+        // balanced nested loops stay where they are, no matter how often they run
+        assert_eq!(movement(">[->+<]<"), Some(0));
+        assert_eq!(movement(">[-]"), Some(1));
+        assert_eq!(movement("[>]"), None);
+        assert_eq!(movement("+[[<]>]"), None);
+
+        let hir = optimized_hir(&alloc, ">+[<]>>>[->+<]");
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::ScanLeft(1)
+        )));
+        assert_eq!(super::net_pointer_movement(&hir), None);
+        let hir = optimized_hir(&alloc, ",[->>+<<]>>+>+<<<");
+        assert_eq!(super::net_pointer_movement(&hir), Some(0));
+    }
+
+    #[test]
+    fn move_sub_to() {
+        let alloc = Bump::new();
+        let bf = "+++++>+++<[->-<]>.<++[>>-<<-]>>.";
+        let hir = optimized_hir(&alloc, bf);
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::MoveSubTo { offset: 1 }
+        )));
+        assert!(contains_kind(&hir, |kind| matches!(
+            kind,
+            StmtKind::MoveSubTo { offset: 2 }
+        )));
+
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let naive = crate::hir::ast_to_ir(&alloc, &ast);
+        assert_eq!(run(&hir), run(&naive));
+        assert_eq!(run(&hir), [254, 254]);
+    }
+
+    fn contains_kind(hir: &Hir<'_>, pred: fn(&StmtKind<'_>) -> bool) -> bool {
+        hir.stmts.iter().any(|stmt| match stmt.kind() {
+            StmtKind::Loop(body) => contains_kind(body, pred),
+            kind => pred(kind),
+        })
+    }
+
+    fn run(hir: &Hir<'_>) -> Vec<u8> {
+        let alloc = Bump::new();
+        let lir = crate::lir::generate(&alloc, hir);
+        crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap()
+    }
+
+    #[test]
+    fn normalize_arith_same_output() {
+        let alloc = Bump::new();
+        let hir = optimized_hir(&alloc, include_str!("../../benches/fizzbuzz.bf"));
+        let expected = run(&hir);
+
+        let mut only_add = hir.clone();
+        super::normalize_arith(&mut only_add, ArithKind::Add);
+        assert!(!contains_kind(&only_add, |kind| matches!(
+            kind,
+            StmtKind::Sub(..)
+        )));
+        assert_eq!(run(&only_add), expected);
+
+        let mut only_sub = hir.clone();
+        super::normalize_arith(&mut only_sub, ArithKind::Sub);
+        assert!(!contains_kind(&only_sub, |kind| matches!(
+            kind,
+            StmtKind::Add(..)
+        )));
+        assert_eq!(run(&only_sub), expected);
+    }
+}
@@ -0,0 +1,318 @@
+//! a small text format for the HIR, to look at optimized programs, edit them by hand and load
+//! them again
+//!
+//! Every statement is on its own line, the body of a loop is indented between `loop {` and `}`.
+//! Statements that don't work on the current cell have the offset of their cell after an `@`.
+//! Everything after a `#` is a comment.
+//!
+//! ```text
+//! setn 0
+//! add 3 @+1
+//! loop {
+//!     move_add +2
+//!     move_mul +1*3 -2*2
+//!     right 1
+//! }
+//! out
+//! ```
+
+use std::fmt::{Display, Formatter, Write};
+
+use bumpalo::Bump;
+
+use crate::{
+    hir::{Hir, Stmt, StmtKind},
+    parse::Span,
+    BumpVec,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IrTextError {
+    /// one based
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for IrTextError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+/// Prints the HIR in the text format. Spans are not part of the format.
+pub fn emit_ir_text(hir: &Hir<'_>) -> String {
+    let mut text = String::new();
+    emit_stmts(hir, 0, &mut text);
+    text
+}
+
+/// Shows the HIR in the text format, like [`emit_ir_text`]
+impl Display for Hir<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&emit_ir_text(self))
+    }
+}
+
+fn emit_stmts(hir: &Hir<'_>, depth: usize, text: &mut String) {
+    for stmt in &hir.stmts {
+        let indent = "    ".repeat(depth);
+        match stmt.kind() {
+            StmtKind::Add(offset, n) => emit_arith(text, &indent, "add", *offset, *n),
+            StmtKind::Sub(offset, n) => emit_arith(text, &indent, "sub", *offset, *n),
+            StmtKind::MoveAddTo { offset } => {
+                writeln!(text, "{indent}move_add {offset:+}").unwrap()
+            }
+            StmtKind::MoveSubTo { offset } => {
+                writeln!(text, "{indent}move_sub {offset:+}").unwrap()
+            }
+            StmtKind::MoveMulTo { targets } => {
+                write!(text, "{indent}move_mul").unwrap();
+                for (offset, factor) in targets {
+                    write!(text, " {offset:+}*{factor}").unwrap();
+                }
+                text.push('\n');
+            }
+            StmtKind::Right(n) => writeln!(text, "{indent}right {n}").unwrap(),
+            StmtKind::Left(n) => writeln!(text, "{indent}left {n}").unwrap(),
+            StmtKind::ScanRight(n) => writeln!(text, "{indent}scan_right {n}").unwrap(),
+            StmtKind::ScanLeft(n) => writeln!(text, "{indent}scan_left {n}").unwrap(),
+            StmtKind::Loop(body) => {
+                writeln!(text, "{indent}loop {{").unwrap();
+                emit_stmts(body, depth + 1, text);
+                writeln!(text, "{indent}}}").unwrap();
+            }
+            StmtKind::Out => writeln!(text, "{indent}out").unwrap(),
+            StmtKind::OutConst(n) => writeln!(text, "{indent}out_const {n}").unwrap(),
+            StmtKind::OutRepeat { byte, count } => {
+                writeln!(text, "{indent}out_repeat {byte} {count}").unwrap()
+            }
+            StmtKind::In => writeln!(text, "{indent}in").unwrap(),
+            StmtKind::SetN(n) => writeln!(text, "{indent}setn {n}").unwrap(),
+            StmtKind::SetNOffset { offset, n } => emit_arith(text, &indent, "setn", *offset, *n),
+            StmtKind::Debug => writeln!(text, "{indent}debug").unwrap(),
+            StmtKind::Nop => writeln!(text, "{indent}nop").unwrap(),
+        }
+    }
+}
+
+fn emit_arith(text: &mut String, indent: &str, name: &str, offset: i32, n: u8) {
+    if offset == 0 {
+        writeln!(text, "{indent}{name} {n}").unwrap();
+    } else {
+        writeln!(text, "{indent}{name} {n} @{offset:+}").unwrap();
+    }
+}
+
+/// Parses the text format back into HIR. The span of every statement is the part of `text` it
+/// was parsed from, so that errors found later can point into the text.
+pub fn parse_ir_text<'hir>(alloc: &'hir Bump, text: &str) -> Result<Hir<'hir>, IrTextError> {
+    // the statements around every open loop, with the span of its `loop {` line
+    let mut open_loops: Vec<(BumpVec<'hir, Stmt<'hir>>, Span)> = Vec::new();
+    let mut stmts = Vec::new_in(alloc);
+
+    let mut line_start = 0;
+    for (line_idx, line) in text.split('\n').enumerate() {
+        let error = |message: String| IrTextError {
+            line: line_idx + 1,
+            message,
+        };
+
+        let code = line.split('#').next().unwrap_or_default();
+        let trimmed = code.trim_start();
+        let start = line_start + (code.len() - trimmed.len());
+        let code = trimmed.trim_end();
+        let span = Span::start_end(start, start + code.len());
+        line_start += line.len() + 1;
+
+        let mut words = code.split_whitespace();
+        let Some(name) = words.next() else {
+            continue;
+        };
+        let mut arg = |what: &str| {
+            words
+                .next()
+                .ok_or_else(|| error(format!("`{name}` is missing its {what}")))
+        };
+
+        let line = match name {
+            "add" | "sub" | "setn" => {
+                let n = parse_number(arg("amount")?).map_err(error)?;
+                let offset = match words.next() {
+                    Some(offset) => {
+                        let offset = offset.strip_prefix('@').ok_or_else(|| {
+                            error(format!("expected `@offset`, found `{offset}`"))
+                        })?;
+                        parse_number(offset).map_err(error)?
+                    }
+                    None => 0,
+                };
+                match name {
+                    "add" => Line::Stmt(StmtKind::Add(offset, n)),
+                    "sub" => Line::Stmt(StmtKind::Sub(offset, n)),
+                    _ if offset == 0 => Line::Stmt(StmtKind::SetN(n)),
+                    _ => Line::Stmt(StmtKind::SetNOffset { offset, n }),
+                }
+            }
+            "move_add" => Line::Stmt(StmtKind::MoveAddTo {
+                offset: parse_number(arg("offset")?).map_err(error)?,
+            }),
+            "move_sub" => Line::Stmt(StmtKind::MoveSubTo {
+                offset: parse_number(arg("offset")?).map_err(error)?,
+            }),
+            "move_mul" => {
+                let mut targets = Vec::new_in(alloc);
+                for target in words.by_ref() {
+                    let (offset, factor) = target.split_once('*').ok_or_else(|| {
+                        error(format!("expected `offset*factor`, found `{target}`"))
+                    })?;
+                    let offset = parse_number(offset).map_err(error)?;
+                    let factor = parse_number(factor).map_err(error)?;
+                    targets.push((offset, factor));
+                }
+                if targets.is_empty() {
+                    return Err(error("`move_mul` is missing its targets".to_owned()));
+                }
+                Line::Stmt(StmtKind::MoveMulTo { targets })
+            }
+            "right" => Line::Stmt(StmtKind::Right(
+                parse_number(arg("amount")?).map_err(error)?,
+            )),
+            "left" => Line::Stmt(StmtKind::Left(parse_number(arg("amount")?).map_err(error)?)),
+            "scan_right" => Line::Stmt(StmtKind::ScanRight(
+                parse_number(arg("amount")?).map_err(error)?,
+            )),
+            "scan_left" => Line::Stmt(StmtKind::ScanLeft(
+                parse_number(arg("amount")?).map_err(error)?,
+            )),
+            "out" => Line::Stmt(StmtKind::Out),
+            "out_const" => Line::Stmt(StmtKind::OutConst(
+                parse_number(arg("byte")?).map_err(error)?,
+            )),
+            "out_repeat" => Line::Stmt(StmtKind::OutRepeat {
+                byte: parse_number(arg("byte")?).map_err(error)?,
+                count: parse_number(arg("count")?).map_err(error)?,
+            }),
+            "in" => Line::Stmt(StmtKind::In),
+            "debug" => Line::Stmt(StmtKind::Debug),
+            "nop" => Line::Stmt(StmtKind::Nop),
+            "loop" => {
+                if arg("`{`")? != "{" {
+                    return Err(error("expected `{` after `loop`".to_owned()));
+                }
+                Line::LoopStart
+            }
+            "}" => Line::LoopEnd,
+            _ => return Err(error(format!("unknown statement `{name}`"))),
+        };
+
+        if let Some(extra) = words.next() {
+            return Err(error(format!("unexpected `{extra}` after `{name}`")));
+        }
+
+        match line {
+            Line::Stmt(kind) => stmts.push(Stmt::new(kind, span)),
+            Line::LoopStart => {
+                let outer = std::mem::replace(&mut stmts, Vec::new_in(alloc));
+                open_loops.push((outer, span));
+            }
+            Line::LoopEnd => {
+                let Some((outer, loop_span)) = open_loops.pop() else {
+                    return Err(error("`}` without a loop".to_owned()));
+                };
+                let body = std::mem::replace(&mut stmts, outer);
+                let kind = StmtKind::Loop(Hir { stmts: body });
+                stmts.push(Stmt::new(kind, loop_span.merge(span)));
+            }
+        }
+    }
+
+    if !open_loops.is_empty() {
+        return Err(IrTextError {
+            line: text.split('\n').count(),
+            message: format!("{} loops are not closed", open_loops.len()),
+        });
+    }
+
+    Ok(Hir { stmts })
+}
+
+enum Line<'hir> {
+    Stmt(StmtKind<'hir>),
+    LoopStart,
+    LoopEnd,
+}
+
+fn parse_number<T: std::str::FromStr>(text: &str) -> Result<T, String> {
+    text.parse().map_err(|_| format!("invalid number `{text}`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::hir::Hir;
+
+    fn run(hir: &Hir<'_>) -> Vec<u8> {
+        let alloc = Bump::new();
+        let lir = crate::lir::generate(&alloc, hir);
+        crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let alloc = Bump::new();
+        let bf = include_str!("../../benches/fizzbuzz.bf");
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+
+        let text = super::emit_ir_text(&hir);
+        let parsed = super::parse_ir_text(&alloc, &text).unwrap();
+
+        assert_eq!(format!("{hir:?}"), format!("{parsed:?}"));
+        assert_eq!(super::emit_ir_text(&parsed), text);
+        assert_eq!(run(&hir), run(&parsed));
+    }
+
+    #[test]
+    fn hand_written() {
+        let alloc = Bump::new();
+        let text = "\
+# prints `AB`
+setn 65
+out
+add 1
+out
+add 1 @+2   # something to clear
+right 2
+loop {
+    sub 1
+}
+";
+        let hir = super::parse_ir_text(&alloc, text).unwrap();
+
+        assert_eq!(run(&hir), b"AB");
+    }
+
+    #[test]
+    fn display() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",[>[->+<]<-]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+
+        assert_eq!(
+            hir.to_string(),
+            "in\nloop {\n    right 1\n    move_add +1\n    left 1\n    sub 1\n}\n"
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let alloc = Bump::new();
+        let error = |text| super::parse_ir_text(&alloc, text).unwrap_err().to_string();
+
+        assert_eq!(error("out\njump 3"), "line 2: unknown statement `jump`");
+        assert_eq!(error("add 256"), "line 1: invalid number `256`");
+        assert_eq!(error("loop {\nout"), "line 2: 1 loops are not closed");
+        assert_eq!(error("}"), "line 1: `}` without a loop");
+    }
+}
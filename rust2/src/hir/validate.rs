@@ -0,0 +1,75 @@
+//! opt-in checks on the HIR that reject programs instead of optimizing them
+
+use crate::{
+    hir::{Hir, StmtKind},
+    parse::Span,
+};
+
+/// Checks that the pointer ends up where it started after every iteration of every loop.
+///
+/// Returns the spans of all loops whose body moves the pointer. Nested loops only count as
+/// balanced for their outer loop, they are reported on their own if they aren't.
+pub fn require_balanced_loops(hir: &Hir<'_>) -> Result<(), Vec<Span>> {
+    let mut unbalanced = Vec::new();
+    find_unbalanced_loops(hir, &mut unbalanced);
+
+    if unbalanced.is_empty() {
+        Ok(())
+    } else {
+        Err(unbalanced)
+    }
+}
+
+fn find_unbalanced_loops(hir: &Hir<'_>, unbalanced: &mut Vec<Span>) {
+    for stmt in &hir.stmts {
+        match stmt.kind() {
+            StmtKind::Loop(body) => {
+                if net_pointer_move(body) != 0 {
+                    unbalanced.push(stmt.span);
+                }
+                find_unbalanced_loops(body, unbalanced);
+            }
+            // scans are loops that do nothing but move the pointer
+            StmtKind::ScanRight(_) | StmtKind::ScanLeft(_) => unbalanced.push(stmt.span),
+            _ => {}
+        }
+    }
+}
+
+fn net_pointer_move(hir: &Hir<'_>) -> i64 {
+    hir.stmts
+        .iter()
+        .map(|stmt| match stmt.kind() {
+            StmtKind::Right(n) => *n as i64,
+            StmtKind::Left(n) => -(*n as i64),
+            _ => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::parse::Span;
+
+    fn unbalanced_loops(bf: &str) -> Result<(), Vec<Span>> {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        super::require_balanced_loops(&hir)
+    }
+
+    #[test]
+    fn unbalanced_loop_flagged() {
+        let spans = unbalanced_loops("+[>]").unwrap_err();
+        assert_eq!(spans.len(), 1);
+        assert_eq!((spans[0].start(), spans[0].end()), (1, 4));
+    }
+
+    #[test]
+    fn balanced_loop_accepted() {
+        assert_eq!(unbalanced_loops("+[->+<]"), Ok(()));
+        assert_eq!(unbalanced_loops("+[->>+<[-]<]"), Ok(()));
+    }
+}
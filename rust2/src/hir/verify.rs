@@ -0,0 +1,221 @@
+//! checks that the optimization passes don't change what a program does
+//!
+//! After every pass, the HIR from before and after the pass is run on the same random input by a
+//! simple reference interpreter, and the outputs have to be the same. This is very slow, so it's
+//! only done with the `verify-passes` feature.
+
+use std::num::Wrapping;
+
+use crate::{
+    hir::{Hir, StmtKind},
+    lir::cell::{ArithPolicy, Bit, Cell, CellWidth, Saturating},
+};
+
+const TAPE_SIZE: usize = 32_000;
+const INPUT_LEN: usize = 16;
+/// programs might never terminate, so only this many statements are run
+const MAX_STEPS: u64 = 1_000_000;
+
+/// Panics with the name of the pass if `before` and `after` behave differently on cells of the
+/// given width and policy
+pub fn verify_pass(
+    pass: &str,
+    before: &Hir<'_>,
+    after: &Hir<'_>,
+    width: CellWidth,
+    arith: ArithPolicy,
+) {
+    match (width, arith) {
+        (CellWidth::U8, ArithPolicy::Wrap) => verify_with::<Wrapping<u8>>(pass, before, after),
+        (CellWidth::U8, ArithPolicy::Saturate) => {
+            verify_with::<Saturating<u8>>(pass, before, after)
+        }
+        (CellWidth::Bit, ArithPolicy::Wrap) => verify_with::<Bit>(pass, before, after),
+        (CellWidth::Bit, ArithPolicy::Saturate) => {
+            verify_with::<Saturating<Bit>>(pass, before, after)
+        }
+        (CellWidth::U16, ArithPolicy::Wrap) => verify_with::<Wrapping<u16>>(pass, before, after),
+        (CellWidth::U16, ArithPolicy::Saturate) => {
+            verify_with::<Saturating<u16>>(pass, before, after)
+        }
+        (CellWidth::U32, ArithPolicy::Wrap) => verify_with::<Wrapping<u32>>(pass, before, after),
+        (CellWidth::U32, ArithPolicy::Saturate) => {
+            verify_with::<Saturating<u32>>(pass, before, after)
+        }
+    }
+}
+
+fn verify_with<C: Cell>(pass: &str, before: &Hir<'_>, after: &Hir<'_>) {
+    let input = (0..INPUT_LEN).map(|_| rand::random()).collect::<Vec<u8>>();
+
+    let expected = Reference::<C>::run(before, &input);
+    let actual = Reference::<C>::run(after, &input);
+
+    // if the steps ran out, the optimized program will have come further
+    let same = match (expected.finished, actual.finished) {
+        (true, true) => expected.output == actual.output,
+        _ => {
+            let len = std::cmp::min(expected.output.len(), actual.output.len());
+            expected.output[..len] == actual.output[..len]
+        }
+    };
+
+    assert!(
+        same,
+        "pass `{pass}` changed the behavior of the program for the input {input:?}\n\
+         output before: {:?}\noutput after: {:?}",
+        expected.output, actual.output
+    );
+}
+
+/// Uses the same cells as the interpreter, so that it does the same arithmetic
+struct Reference<'input, C> {
+    tape: Vec<C>,
+    ptr: usize,
+    input: &'input [u8],
+    output: Vec<u8>,
+    steps: u64,
+    finished: bool,
+}
+
+impl<'input, C: Cell> Reference<'input, C> {
+    fn run(hir: &Hir<'_>, input: &'input [u8]) -> Self {
+        let mut reference = Self {
+            tape: vec![C::ZERO; TAPE_SIZE],
+            ptr: 0,
+            input,
+            output: Vec::new(),
+            steps: 0,
+            finished: false,
+        };
+        reference.finished = reference.execute(hir).is_some();
+        reference
+    }
+
+    /// Returns `None` once the steps ran out
+    fn execute(&mut self, hir: &Hir<'_>) -> Option<()> {
+        for stmt in &hir.stmts {
+            self.steps += 1;
+            if self.steps > MAX_STEPS {
+                return None;
+            }
+
+            match *stmt.kind() {
+                StmtKind::Add(offset, n) => self.cell(offset).add(n),
+                StmtKind::Sub(offset, n) => self.cell(offset).sub(n),
+                StmtKind::MoveAddTo { offset } => {
+                    if let Some(value) = self.take() {
+                        self.cell(offset).add_cell(value);
+                    }
+                }
+                StmtKind::MoveSubTo { offset } => {
+                    if let Some(value) = self.take() {
+                        self.cell(offset).sub_cell(value);
+                    }
+                }
+                StmtKind::MoveMulTo { ref targets } => {
+                    if let Some(value) = self.take() {
+                        for &(offset, factor) in targets {
+                            self.cell(offset).add_cell_times(value, factor);
+                        }
+                    }
+                }
+                StmtKind::Right(n) => self.ptr = self.index(n as i64),
+                StmtKind::Left(n) => self.ptr = self.index(-(n as i64)),
+                StmtKind::ScanRight(n) => {
+                    while !self.tape[self.ptr].is_zero() {
+                        self.ptr = self.index(n as i64);
+                    }
+                }
+                StmtKind::ScanLeft(n) => {
+                    while !self.tape[self.ptr].is_zero() {
+                        self.ptr = self.index(-(n as i64));
+                    }
+                }
+                StmtKind::Loop(ref body) => {
+                    while !self.tape[self.ptr].is_zero() {
+                        // count the iteration itself, or an empty loop would never run out
+                        self.steps += 1;
+                        if self.steps > MAX_STEPS {
+                            return None;
+                        }
+                        self.execute(body)?;
+                    }
+                }
+                StmtKind::Out => self.output.push(self.tape[self.ptr].out_byte()),
+                StmtKind::OutConst(n) => self.output.push(constant::<C>(n)),
+                StmtKind::OutRepeat { byte, count } => self
+                    .output
+                    .extend(std::iter::repeat(constant::<C>(byte)).take(count)),
+                StmtKind::In => {
+                    let (&byte, rest) = self.input.split_first().unwrap_or((&0, &[]));
+                    self.input = rest;
+                    self.tape[self.ptr] = C::from_in_byte(byte);
+                }
+                StmtKind::SetN(n) => self.cell(0).set(n),
+                StmtKind::SetNOffset { offset, n } => self.cell(offset).set(n),
+                // the dump only depends on the tape, which is compared already
+                StmtKind::Debug | StmtKind::Nop => {}
+            }
+        }
+        Some(())
+    }
+
+    fn index(&self, offset: i64) -> usize {
+        (self.ptr as i64 + offset).rem_euclid(TAPE_SIZE as i64) as usize
+    }
+
+    fn cell(&mut self, offset: i32) -> &mut C {
+        let idx = self.index(offset.into());
+        &mut self.tape[idx]
+    }
+
+    /// Clears the current cell and returns its value, `None` if it was zero already
+    fn take(&mut self) -> Option<C> {
+        let value = std::mem::replace(&mut self.tape[self.ptr], C::ZERO);
+        (!value.is_zero()).then(|| value)
+    }
+}
+
+/// The byte written for a constant, which is set like a cell
+fn constant<C: Cell>(n: u8) -> u8 {
+    let mut cell = C::ZERO;
+    cell.set(n);
+    cell.out_byte()
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::{
+        hir::{Hir, StmtKind},
+        lir::cell::{ArithPolicy, CellWidth},
+    };
+
+    fn hir<'hir>(alloc: &'hir Bump, bf: &str) -> Hir<'hir> {
+        let ast = crate::parse::parse(alloc, bf.bytes().enumerate()).unwrap();
+        crate::hir::optimized_hir(alloc, &ast)
+    }
+
+    #[test]
+    fn correct_pass_accepted() {
+        let alloc = Bump::new();
+        let before = hir(&alloc, ",[->+<]>.");
+        let after = before.clone();
+
+        super::verify_pass("nothing", &before, &after, CellWidth::U8, ArithPolicy::Wrap);
+    }
+
+    #[test]
+    #[should_panic(expected = "pass `broken` changed the behavior")]
+    fn broken_pass_caught() {
+        let alloc = Bump::new();
+        let before = hir(&alloc, "+++.");
+        let mut after = before.clone();
+        // an off by one in constant folding
+        after.stmts[0].kind = StmtKind::Add(0, 4);
+
+        super::verify_pass("broken", &before, &after, CellWidth::U8, ArithPolicy::Wrap);
+    }
+}
@@ -13,12 +13,23 @@ use std::{
 use bumpalo::Bump;
 use owo_colors::OwoColorize;
 
-use crate::parse::ParseError;
+use crate::{
+    lir::interpreter::{RunConfig, RuntimeError, TapeMode},
+    parse::ParseError,
+};
+pub use crate::{
+    program::{compile, CompileOptions, Program},
+    repl::Repl,
+};
 
 pub mod hir;
 pub mod lir;
+#[cfg(feature = "lsp-support")]
+pub mod lsp;
 mod mir;
 pub mod parse;
+mod program;
+mod repl;
 
 #[derive(clap::Parser, Default)]
 #[clap(author, about)]
@@ -30,9 +41,16 @@ pub struct Args {
     /// Dump the IR info (ast, hir, mir, lir)
     #[clap(long)]
     pub dump: Option<DumpKind>,
-    /// Use experimental mid-level IR
+    /// Run the program through the experimental mid-level IR and its optimizations
     #[clap(long)]
     pub mir: bool,
+    /// Stop with an error when the pointer moves off the tape instead of wrapping around
+    #[clap(long)]
+    pub strict_pointer: bool,
+    /// Stop with an error when a cell next to the pointer is accessed off the tape, instead of
+    /// wrapping around. Makes the interpreter slower.
+    #[clap(long)]
+    pub bounds_check: bool,
     /// The file to run
     pub file: PathBuf,
 }
@@ -66,14 +84,106 @@ pub enum UseProfile {
     No,
 }
 
-pub fn run<R, W>(src: &str, stdout: W, stdin: R, config: &Args) -> Result<(), ParseError>
+/// An error that occurred while compiling or running a program
+#[derive(Debug)]
+pub enum Error {
+    Parse {
+        err: ParseError,
+        /// the one based line and column of the error, if the source code is known
+        line_col: Option<(usize, usize)>,
+    },
+    Runtime(RuntimeError),
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Parse {
+                err,
+                line_col: Some((line, col)),
+            } => write!(
+                f,
+                "Failed to parse brainfuck code at line {line}, column {col}: {err}"
+            ),
+            Self::Parse {
+                err,
+                line_col: None,
+            } => {
+                write!(f, "Failed to parse brainfuck code: {err}")
+            }
+            Self::Runtime(err) => Display::fmt(err, f),
+            Self::Io(err) => Display::fmt(err, f),
+        }
+    }
+}
+
+impl Error {
+    fn parse_in(err: ParseError, src: &str) -> Self {
+        let line_col = Some(err.span().line_col(src));
+        Self::Parse { err, line_col }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Self::Parse {
+            err,
+            line_col: None,
+        }
+    }
+}
+
+impl From<RuntimeError> for Error {
+    fn from(err: RuntimeError) -> Self {
+        Self::Runtime(err)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+/// Runs the program with `input` as stdin and returns the raw bytes it wrote to stdout
+pub fn run_source(src: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut stdout = Vec::new();
+    run(src, &mut stdout, input, &Args::default())?;
+    Ok(stdout)
+}
+
+/// Like [`run_source`], but the program is optimized through the experimental mid-level IR
+pub fn run_via_mir(src: &str, input: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut stdout = Vec::new();
+    let config = Args {
+        mir: true,
+        ..Args::default()
+    };
+    run(src, &mut stdout, input, &config)?;
+    Ok(stdout)
+}
+
+/// Runs the program with `input` as stdin and returns its output, decoded as lossy UTF-8
+///
+/// ```
+/// let output = brainfuck::run_to_string_lossy("++++++++[>++++++++<-]>+.", b"").unwrap();
+/// assert_eq!(output, "A");
+/// ```
+pub fn run_to_string_lossy(src: &str, input: &[u8]) -> Result<String, Error> {
+    let output = run_source(src, input)?;
+    Ok(String::from_utf8_lossy(&output).into_owned())
+}
+
+pub fn run<R, W>(src: &str, stdout: W, stdin: R, config: &Args) -> Result<(), Error>
 where
     W: Write,
     R: Read,
 {
     let ast_alloc = Bump::new();
 
-    let parsed = parse::parse(&ast_alloc, src.bytes().enumerate())?;
+    let parsed =
+        parse::parse_bytes(&ast_alloc, src.as_bytes()).map_err(|err| Error::parse_in(err, src))?;
 
     if let Some(DumpKind::Ast) = config.dump {
         println!("{parsed:#?}");
@@ -92,31 +202,44 @@ where
     drop(parsed);
     drop(ast_alloc);
 
-    if config.dump == Some(DumpKind::Mir) || config.mir {
+    let cg_alloc = Bump::new();
+
+    let lir = if config.dump == Some(DumpKind::Mir) || config.mir {
         let mir_alloc = Bump::new();
         let mir = mir::optimized_mir(&mir_alloc, &optimized_hir);
         if config.dump == Some(DumpKind::Mir) {
             println!("{mir:#?}");
         }
-    }
-
-    let cg_alloc = Bump::new();
-
-    let lir = lir::generate(&cg_alloc, &optimized_hir);
+        match config.mir {
+            true => mir::lower_to_lir(&cg_alloc, &mir),
+            false => lir::generate(&cg_alloc, &optimized_hir),
+        }
+    } else {
+        lir::generate(&cg_alloc, &optimized_hir)
+    };
 
     if let Some(DumpKind::Lir) = config.dump {
-        println!("{lir:#?}");
+        print!("{lir}");
         return Ok(());
     }
 
     drop(optimized_hir);
     drop(hir_alloc);
 
+    let run_config = RunConfig {
+        tape_mode: match config.strict_pointer {
+            true => TapeMode::Strict,
+            false => TapeMode::Wrap,
+        },
+        bounds_check: config.bounds_check,
+        ..RunConfig::default()
+    };
+
     match config.profile {
         true => {
             let mut code_profile_count = vec![0; lir.debug().len()];
 
-            lir::interpreter::run(&lir, stdout, stdin, |ip| unsafe {
+            let result = lir::interpreter::run(&lir, stdout, stdin, &run_config, |ip| unsafe {
                 *code_profile_count.get_unchecked_mut(ip) += 1;
             });
 
@@ -133,9 +256,11 @@ where
             for (char, value) in src.bytes().zip(src_profile_count) {
                 print!("{}", color_by_profile(char as char, value, max));
             }
+
+            result?;
         }
         false => {
-            lir::interpreter::run(&lir, stdout, stdin, |_| {});
+            lir::interpreter::run(&lir, stdout, stdin, &run_config, |_| {})?;
         }
     }
 
@@ -182,4 +307,39 @@ mod tests {
 
         insta::assert_debug_snapshot!(String::from_utf8(stdout));
     }
+
+    #[test]
+    fn hello_world_to_string_lossy() {
+        let str = "++++++++++[>+++++++>++++++++++>+++<<<-]>++.>+.+++++++..+++.>++++++++++++++.------------.<<+++++++++++++++.>.+++.------.--------.>+.";
+
+        let output = super::run_to_string_lossy(str, &[]).unwrap();
+
+        assert_eq!(output, "Hello, World!");
+    }
+
+    #[test]
+    fn run_via_mir_same_output() {
+        let src = include_str!("../benches/bottles.bf");
+        assert_eq!(
+            super::run_via_mir(src, &[]).unwrap(),
+            super::run_source(src, &[]).unwrap()
+        );
+
+        // reverses the input
+        let src = ">,[>,]<[.<]";
+        assert_eq!(super::run_via_mir(src, b"abc").unwrap(), b"cba");
+    }
+
+    #[test]
+    fn parse_error_line_col() {
+        let src = format!("comment\r\n+{}", "[".repeat(1100));
+
+        let err = super::run_source(&src, &[]).unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "Failed to parse brainfuck code at line 2, column 1003: \
+             Loops nested too deeply (1002 levels) at byte 1011"
+        );
+    }
 }
@@ -0,0 +1,551 @@
+//! a tiny hand rolled JIT compiler to x86-64 machine code
+//!
+//! Every statement is translated to a fixed sequence of instructions, without any register
+//! allocation or further optimization, which is already enough to leave the interpreter far
+//! behind, compare `bench` and `mandelbrot` with `bench_asm_jit` and `mandelbrot_asm_jit` in
+//! `cargo bench --features asm-jit`. The generated code follows the System V calling convention
+//! and keeps its state in callee saved registers:
+//!
+//! - `rbx`: the pointer into the tape
+//! - `r12`, `r13`: the start and the end of the tape, to wrap the pointer around
+//! - `r14`: the context passed to the functions doing the I/O
+//!
+//! `.` and `,` call back into Rust. The callbacks return bit 8 set if the I/O failed, which stops
+//! the program.
+//!
+//! Cells at an offset from the pointer wrap around the ends of the tape like in the interpreter.
+//! Their address is computed into `rdx` with a compare and a conditional move, using `rsi` as
+//! scratch.
+
+use std::{
+    io::{self, Read, Write},
+    mem,
+};
+
+use memmap2::MmapMut;
+
+use crate::lir::{
+    dump::debug_dump,
+    interpreter::{read_byte, EofBehavior, MEM_SIZE},
+    Lir, Stmt,
+};
+
+/// set in the return value of the I/O callbacks if they failed
+const IO_ERROR: u64 = 1 << 8;
+
+/// Compiles the code to machine code and runs it. The pointer wraps around the ends of the tape,
+/// `.` writes the cells as raw bytes and `,` leaves the cell unchanged at the end of the input,
+/// like the interpreter with the default config. Cells at an offset from the pointer wrap around
+/// as well.
+///
+/// `stdout` is only flushed when the program stops.
+pub fn asm_jit_run<W, R>(code: &Lir<'_>, stdout: W, stdin: R) -> io::Result<()>
+where
+    W: Write,
+    R: Read,
+{
+    let mut tape = vec![0u8; MEM_SIZE];
+
+    let machine_code = compile::<W, R>(code);
+    let mut exec = MmapMut::map_anon(machine_code.len())?;
+    exec.copy_from_slice(&machine_code);
+    let exec = exec.make_exec()?;
+
+    let mut ctx = Context {
+        stdout,
+        stdin,
+        error: None,
+    };
+    // SAFETY: the code was generated by `compile` for exactly this signature, and only accesses
+    // the tape and the context
+    unsafe {
+        let entry: extern "sysv64" fn(*mut u8, *mut Context<W, R>) = mem::transmute(exec.as_ptr());
+        entry(tape.as_mut_ptr(), &mut ctx);
+    }
+
+    if let Some(err) = ctx.error {
+        return Err(err);
+    }
+    ctx.stdout.flush()
+}
+
+struct Context<W, R> {
+    stdout: W,
+    stdin: R,
+    error: Option<io::Error>,
+}
+
+extern "sysv64" fn out<W: Write, R>(ctx: *mut Context<W, R>, cell: u8) -> u64 {
+    // SAFETY: the context outlives the run of the compiled code, and nothing else accesses it
+    let ctx = unsafe { &mut *ctx };
+    match ctx.stdout.write_all(&[cell]) {
+        Ok(()) => 0,
+        Err(err) => {
+            ctx.error = Some(err);
+            IO_ERROR
+        }
+    }
+}
+
+extern "sysv64" fn out_bytes<W: Write, R>(
+    ctx: *mut Context<W, R>,
+    bytes: *const u8,
+    len: usize,
+) -> u64 {
+    // SAFETY: the bytes are owned by the `Lir` and the context outlives the run of the compiled
+    // code, and nothing else accesses it
+    let (ctx, bytes) = unsafe { (&mut *ctx, std::slice::from_raw_parts(bytes, len)) };
+    match ctx.stdout.write_all(bytes) {
+        Ok(()) => 0,
+        Err(err) => {
+            ctx.error = Some(err);
+            IO_ERROR
+        }
+    }
+}
+
+extern "sysv64" fn out_repeat<W: Write, R>(ctx: *mut Context<W, R>, byte: u8, count: usize) -> u64 {
+    // SAFETY: the context outlives the run of the compiled code, and nothing else accesses it
+    let ctx = unsafe { &mut *ctx };
+    match ctx.stdout.write_all(&vec![byte; count]) {
+        Ok(()) => 0,
+        Err(err) => {
+            ctx.error = Some(err);
+            IO_ERROR
+        }
+    }
+}
+
+extern "sysv64" fn debug<W: Write, R>(ctx: *mut Context<W, R>, tape: *const u8, ptr: usize) -> u64 {
+    // SAFETY: `tape` is the start of the tape, which outlives the run of the compiled code, and
+    // the context outlives it as well and nothing else accesses it
+    let (ctx, tape) = unsafe { (&mut *ctx, std::slice::from_raw_parts(tape, MEM_SIZE)) };
+    let dump = debug_dump(tape.iter().copied(), ptr);
+    match ctx.stdout.write_all(dump.as_bytes()) {
+        Ok(()) => 0,
+        Err(err) => {
+            ctx.error = Some(err);
+            IO_ERROR
+        }
+    }
+}
+
+/// Returns the new value of the cell, which is `cell` before the read
+extern "sysv64" fn input<W, R: Read>(ctx: *mut Context<W, R>, cell: u8) -> u64 {
+    // SAFETY: the context outlives the run of the compiled code, and nothing else accesses it
+    let ctx = unsafe { &mut *ctx };
+    match read_byte(&mut ctx.stdin) {
+        Ok(Some(byte)) => byte.into(),
+        Ok(None) => EofBehavior::default().byte_at_eof(cell).into(),
+        Err(err) => {
+            ctx.error = Some(err);
+            IO_ERROR
+        }
+    }
+}
+
+fn compile<W: Write, R: Read>(code: &Lir<'_>) -> Vec<u8> {
+    let mut asm = Assembler::default();
+    // the start of the machine code of every statement
+    let mut stmt_starts = Vec::with_capacity(code.stmts().len());
+    // the places where the rel32 of a jump to a statement or the epilogue has to be filled in
+    let mut jumps = Vec::new();
+    let mut exits = Vec::new();
+
+    // push rbx, r12, r13, r14, r15
+    // five pushes and the return address keep the stack 16 byte aligned for the calls
+    asm.emit(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+    // mov rbx, rdi
+    asm.emit(&[0x48, 0x89, 0xfb]);
+    // mov r12, rdi
+    asm.emit(&[0x49, 0x89, 0xfc]);
+    // lea r13, [rdi + MEM_SIZE]
+    asm.emit(&[0x4c, 0x8d, 0xaf]);
+    asm.emit_u32(MEM_SIZE as u32);
+    // mov r14, rsi
+    asm.emit(&[0x49, 0x89, 0xf6]);
+
+    for stmt in code.stmts() {
+        stmt_starts.push(asm.code.len());
+        match *stmt {
+            // add byte [rbx], n
+            Stmt::Add(n) => asm.emit(&[0x80, 0x03, n]),
+            // sub byte [rbx], n
+            Stmt::Sub(n) => asm.emit(&[0x80, 0x2b, n]),
+            Stmt::AddOffset { offset, n } => {
+                asm.emit_cell_addr(offset);
+                // add byte [rdx], n
+                asm.emit(&[0x80, 0x02, n]);
+            }
+            Stmt::SubOffset { offset, n } => {
+                asm.emit_cell_addr(offset);
+                // sub byte [rdx], n
+                asm.emit(&[0x80, 0x2a, n]);
+            }
+            Stmt::MoveAddTo { offset } => {
+                // adding zero to the other cell does nothing, so there is no need to check the
+                // cell first
+                // movzx eax, byte [rbx]
+                asm.emit(&[0x0f, 0xb6, 0x03]);
+                // mov byte [rbx], 0
+                asm.emit(&[0xc6, 0x03, 0x00]);
+                asm.emit_cell_addr(offset);
+                // add byte [rdx], al
+                asm.emit(&[0x00, 0x02]);
+            }
+            Stmt::MoveSubTo { offset } => {
+                // like `MoveAddTo`, subtracting zero does nothing
+                // movzx eax, byte [rbx]
+                asm.emit(&[0x0f, 0xb6, 0x03]);
+                // mov byte [rbx], 0
+                asm.emit(&[0xc6, 0x03, 0x00]);
+                asm.emit_cell_addr(offset);
+                // sub byte [rdx], al
+                asm.emit(&[0x28, 0x02]);
+            }
+            Stmt::MoveMulTo(idx) => {
+                // movzx eax, byte [rbx]
+                asm.emit(&[0x0f, 0xb6, 0x03]);
+                // mov byte [rbx], 0
+                asm.emit(&[0xc6, 0x03, 0x00]);
+                for &(offset, factor) in code.mul_targets(idx) {
+                    // imul ecx, eax, factor
+                    asm.emit(&[0x69, 0xc8]);
+                    asm.emit_u32(factor.into());
+                    asm.emit_cell_addr(offset);
+                    // add byte [rdx], cl
+                    asm.emit(&[0x00, 0x0a]);
+                }
+            }
+            Stmt::Right(n) => asm.emit_right(n),
+            Stmt::Left(n) => asm.emit_left(n),
+            Stmt::ScanRight(n) => asm.emit_scan(|asm| asm.emit_right(n)),
+            Stmt::ScanLeft(n) => asm.emit_scan(|asm| asm.emit_left(n)),
+            Stmt::Out => {
+                // movzx esi, byte [rbx]
+                asm.emit(&[0x0f, 0xb6, 0x33]);
+                asm.emit_call(out::<W, R> as usize);
+                exits.push(asm.emit_exit_on_error());
+            }
+            Stmt::OutConst(n) => {
+                // mov esi, n
+                asm.emit(&[0xbe]);
+                asm.emit_u32(n.into());
+                asm.emit_call(out::<W, R> as usize);
+                exits.push(asm.emit_exit_on_error());
+            }
+            Stmt::OutRepeat { byte, count } => {
+                // mov esi, byte
+                asm.emit(&[0xbe]);
+                asm.emit_u32(byte.into());
+                // mov rdx, count
+                asm.emit(&[0x48, 0xba]);
+                asm.emit(&u64::from(count).to_le_bytes());
+                asm.emit_call(out_repeat::<W, R> as usize);
+                exits.push(asm.emit_exit_on_error());
+            }
+            Stmt::OutBytes(idx) => {
+                let bytes = code.out_bytes(idx);
+                // mov rsi, bytes
+                asm.emit(&[0x48, 0xbe]);
+                asm.emit(&(bytes.as_ptr() as u64).to_le_bytes());
+                // mov rdx, len
+                asm.emit(&[0x48, 0xba]);
+                asm.emit(&(bytes.len() as u64).to_le_bytes());
+                asm.emit_call(out_bytes::<W, R> as usize);
+                exits.push(asm.emit_exit_on_error());
+            }
+            Stmt::In => {
+                // movzx esi, byte [rbx]
+                asm.emit(&[0x0f, 0xb6, 0x33]);
+                asm.emit_call(input::<W, R> as usize);
+                exits.push(asm.emit_exit_on_error());
+                // mov byte [rbx], al
+                asm.emit(&[0x88, 0x03]);
+            }
+            // mov byte [rbx], n
+            Stmt::SetN(n) => asm.emit(&[0xc6, 0x03, n]),
+            Stmt::SetNOffset { offset, n } => {
+                asm.emit_cell_addr(offset);
+                // mov byte [rdx], n
+                asm.emit(&[0xc6, 0x02, n]);
+            }
+            Stmt::Debug => {
+                // mov rsi, r12
+                asm.emit(&[0x4c, 0x89, 0xe6]);
+                // mov rdx, rbx
+                asm.emit(&[0x48, 0x89, 0xda]);
+                // sub rdx, r12
+                asm.emit(&[0x4c, 0x29, 0xe2]);
+                asm.emit_call(debug::<W, R> as usize);
+                exits.push(asm.emit_exit_on_error());
+            }
+            Stmt::JmpIfZero(pos) => {
+                // cmp byte [rbx], 0
+                asm.emit(&[0x80, 0x3b, 0x00]);
+                // je pos
+                asm.emit(&[0x0f, 0x84]);
+                jumps.push((asm.code.len(), pos as usize));
+                asm.emit_u32(0);
+            }
+            Stmt::JmpIfNonZero(pos) => {
+                // cmp byte [rbx], 0
+                asm.emit(&[0x80, 0x3b, 0x00]);
+                // jne pos
+                asm.emit(&[0x0f, 0x85]);
+                jumps.push((asm.code.len(), pos as usize));
+                asm.emit_u32(0);
+            }
+            Stmt::End => {}
+        }
+    }
+
+    let epilogue = asm.code.len();
+    // pop r15, r14, r13, r12, rbx
+    asm.emit(&[0x41, 0x5f, 0x41, 0x5e, 0x41, 0x5d, 0x41, 0x5c, 0x5b]);
+    // ret
+    asm.emit(&[0xc3]);
+
+    for (at, pos) in jumps {
+        asm.patch_rel32(at, stmt_starts[pos]);
+    }
+    for at in exits {
+        asm.patch_rel32(at, epilogue);
+    }
+
+    asm.code
+}
+
+#[derive(Default)]
+struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn emit_u32(&mut self, n: u32) {
+        self.emit(&n.to_le_bytes());
+    }
+
+    fn emit_right(&mut self, n: u32) {
+        // add rbx, n
+        self.emit(&[0x48, 0x81, 0xc3]);
+        self.emit_u32(n % MEM_SIZE as u32);
+        // cmp rbx, r13
+        self.emit(&[0x4c, 0x39, 0xeb]);
+        // jb +7
+        self.emit(&[0x72, 0x07]);
+        // sub rbx, MEM_SIZE
+        self.emit(&[0x48, 0x81, 0xeb]);
+        self.emit_u32(MEM_SIZE as u32);
+    }
+
+    fn emit_left(&mut self, n: u32) {
+        // sub rbx, n
+        self.emit(&[0x48, 0x81, 0xeb]);
+        self.emit_u32(n % MEM_SIZE as u32);
+        // cmp rbx, r12
+        self.emit(&[0x4c, 0x39, 0xe3]);
+        // jae +7
+        self.emit(&[0x73, 0x07]);
+        // add rbx, MEM_SIZE
+        self.emit(&[0x48, 0x81, 0xc3]);
+        self.emit_u32(MEM_SIZE as u32);
+    }
+
+    /// Puts the address of the cell at `offset` from the pointer into `rdx`, wrapping around the
+    /// ends of the tape
+    fn emit_cell_addr(&mut self, offset: i32) {
+        // the pointer is on the tape, so a single subtraction is enough to wrap the sum
+        let wrapped = offset.rem_euclid(MEM_SIZE as i32);
+        // lea rdx, [rbx + wrapped]
+        self.emit(&[0x48, 0x8d, 0x93]);
+        self.emit_u32(wrapped as u32);
+        // lea rsi, [rdx - MEM_SIZE]
+        self.emit(&[0x48, 0x8d, 0xb2]);
+        self.emit_u32(-(MEM_SIZE as i32) as u32);
+        // cmp rdx, r13
+        self.emit(&[0x4c, 0x39, 0xea]);
+        // cmovae rdx, rsi
+        self.emit(&[0x48, 0x0f, 0x43, 0xd6]);
+    }
+
+    /// Repeats the pointer move emitted by `emit_move` until the current cell is zero
+    fn emit_scan(&mut self, emit_move: impl FnOnce(&mut Self)) {
+        let start = self.code.len();
+        // cmp byte [rbx], 0
+        self.emit(&[0x80, 0x3b, 0x00]);
+        // je after the loop
+        self.emit(&[0x0f, 0x84]);
+        let exit = self.code.len();
+        self.emit_u32(0);
+        emit_move(self);
+        // jmp start
+        self.emit(&[0xe9]);
+        let back = self.code.len();
+        self.emit_u32(0);
+        self.patch_rel32(back, start);
+        let end = self.code.len();
+        self.patch_rel32(exit, end);
+    }
+
+    /// Calls one of the I/O callbacks with the context as the first argument
+    fn emit_call(&mut self, function: usize) {
+        // mov rdi, r14
+        self.emit(&[0x4c, 0x89, 0xf7]);
+        // mov rax, function
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&(function as u64).to_le_bytes());
+        // call rax
+        self.emit(&[0xff, 0xd0]);
+    }
+
+    /// Jumps to the epilogue if the callback returned `IO_ERROR`. Returns where the rel32 of the
+    /// jump has to be patched.
+    fn emit_exit_on_error(&mut self) -> usize {
+        // test ah, 1
+        self.emit(&[0xf6, 0xc4, (IO_ERROR >> 8) as u8]);
+        // jnz epilogue
+        self.emit(&[0x0f, 0x85]);
+        let at = self.code.len();
+        self.emit_u32(0);
+        at
+    }
+
+    fn patch_rel32(&mut self, at: usize, target: usize) {
+        // relative to the end of the jump instruction, which ends with the rel32
+        let rel = target as i64 - (at as i64 + 4);
+        let rel = i32::try_from(rel).expect("jump too far");
+        self.code[at..at + 4].copy_from_slice(&rel.to_le_bytes());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use bumpalo::Bump;
+
+    use crate::lir::Stmt;
+
+    fn run_both(bf: &str) -> (Vec<u8>, Vec<u8>) {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let expected = crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap();
+        let mut actual = Vec::new();
+        super::asm_jit_run(&lir, &mut actual, [].as_slice()).unwrap();
+
+        (expected, actual)
+    }
+
+    #[test]
+    fn same_output_as_interpreter() {
+        for bf in [
+            include_str!("../../benches/fizzbuzz.bf"),
+            include_str!("../../benches/bottles.bf"),
+            include_str!("../../benches/hanoi.bf"),
+        ] {
+            let (expected, actual) = run_both(bf);
+            assert_eq!(expected, actual);
+        }
+    }
+
+    #[test]
+    fn scans_same_as_interpreter() {
+        let (expected, actual) = run_both(">+>+>+>>+>>+<<<<<<[>]+.[<]>.>[>>]+.<<[<<]+.");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn out_const_same_as_interpreter() {
+        // the `[-]` after the `+` isn't removed as a dead loop, so the `.`s print known cells
+        let (expected, actual) = run_both("+[-]++++++++.[-]+++++++.++.");
+        assert!(!expected.is_empty());
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn move_sub_same_as_interpreter() {
+        let (expected, actual) = run_both("+++++>+++<[->-<]>.<++[>>-<<-]>>.<<<+[-<->]<.");
+        assert_eq!(expected, [254, 254, 255]);
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn out_repeat() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",[-]+++....>,[-]++.<.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+        assert!(lir.stmts().contains(&Stmt::OutRepeat { byte: 3, count: 4 }));
+
+        let mut stdout = Vec::new();
+        super::asm_jit_run(&lir, &mut stdout, b"ab".as_slice()).unwrap();
+
+        assert_eq!(stdout, [3, 3, 3, 3, 2, 3]);
+    }
+
+    #[test]
+    fn debug_same_as_interpreter() {
+        let (expected, actual) = run_both("++>+++#[-<+>]>>#<<<#");
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn input_and_wrapping_pointer() {
+        let alloc = Bump::new();
+        // stores the second byte at the other end of the tape
+        let ast = crate::parse::parse(&alloc, ",<,>.<.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = Vec::new();
+        super::asm_jit_run(&lir, &mut stdout, b"ab".as_slice()).unwrap();
+
+        assert_eq!(stdout, b"ab");
+    }
+
+    #[test]
+    fn wrapping_offsets_same_as_interpreter() {
+        // statements with offsets and moves across both ends of the tape
+        let (expected, actual) = run_both(
+            "<+>++[-<+>]<.>>>+<<++[->>+<<]>>.<<<-<++++[->>>+<<<]>>>.<<<<+++[->++>>>+<<<<]>.>>>.",
+        );
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn eof_same_as_interpreter() {
+        let (expected, actual) = run_both("+++,.>,.");
+        assert_eq!(expected, [3, 0]);
+        assert_eq!(expected, actual);
+    }
+
+    struct BrokenPipe;
+
+    impl Read for BrokenPipe {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn io_error_stops_program() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+[,.]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = Vec::new();
+        let result = super::asm_jit_run(&lir, &mut stdout, b"ab".chain(BrokenPipe));
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(stdout, b"ab");
+    }
+}
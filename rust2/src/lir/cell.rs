@@ -0,0 +1,439 @@
+//! the different kinds of cells the tape of the interpreter can consist of
+//!
+//! Arithmetic on cells is modular, unless it's wrapped in [`Saturating`]. The optimizer folds
+//! constants modulo 256, which stays correct for the 8 bit and single bit cells since their
+//! modulus divides 256. It has to be told about saturating cells with [`ArithPolicy`] and about
+//! wider cells with [`CellWidth`].
+//!
+//! Wider cells still do I/O a byte at a time: `.` writes the low byte of the cell, and `,` stores
+//! the byte read as it is, clearing the upper bits. Other encoders from `output`, like `Decimal`,
+//! get the whole value of the cell.
+
+use std::{fmt::Debug, num::Wrapping};
+
+/// A single cell on the tape
+pub trait Cell: Copy + Debug {
+    const ZERO: Self;
+
+    fn add(&mut self, n: u8);
+
+    fn sub(&mut self, n: u8);
+
+    fn set(&mut self, n: u8);
+
+    fn add_cell(&mut self, other: Self);
+
+    fn sub_cell(&mut self, other: Self);
+
+    /// Adds `other` to the cell `n` times, like a multiplication loop does
+    fn add_cell_times(&mut self, other: Self, n: u8);
+
+    fn is_zero(self) -> bool;
+
+    /// The byte written to stdout by `.`
+    fn out_byte(self) -> u8;
+
+    /// The value handed to the `OutputEncoder` by `.`, which writes its low byte by default
+    #[inline]
+    fn out_value(self) -> u64 {
+        self.value()
+    }
+
+    /// The value of the cell as a number, for debugging
+    fn value(self) -> u64;
+
+    /// The value of the cell after `,` read `byte` from stdin
+    fn from_in_byte(byte: u8) -> Self;
+}
+
+/// The width of the cells on the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CellWidth {
+    /// Classic wrapping 8 bit cells
+    U8,
+    /// Every cell is a single bit, see [`Bit`]
+    Bit,
+    /// 16 bit cells, which wrap around at 65536
+    U16,
+    /// 32 bit cells, which wrap around at 2^32
+    U32,
+}
+
+impl Default for CellWidth {
+    fn default() -> Self {
+        Self::U8
+    }
+}
+
+/// What happens when arithmetic goes past the bounds of a cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithPolicy {
+    /// Wrap around to the other end, like `255 + 1 = 0`
+    Wrap,
+    /// Stay at the bound, like `255 + 1 = 255` and `0 - 1 = 0`
+    Saturate,
+}
+
+impl Default for ArithPolicy {
+    fn default() -> Self {
+        Self::Wrap
+    }
+}
+
+impl Cell for Wrapping<u8> {
+    const ZERO: Self = Wrapping(0);
+
+    #[inline]
+    fn add(&mut self, n: u8) {
+        *self += n;
+    }
+
+    #[inline]
+    fn sub(&mut self, n: u8) {
+        *self -= n;
+    }
+
+    #[inline]
+    fn set(&mut self, n: u8) {
+        *self = Wrapping(n);
+    }
+
+    #[inline]
+    fn add_cell(&mut self, other: Self) {
+        *self += other;
+    }
+
+    #[inline]
+    fn sub_cell(&mut self, other: Self) {
+        *self -= other;
+    }
+
+    #[inline]
+    fn add_cell_times(&mut self, other: Self, n: u8) {
+        *self += other * Wrapping(n);
+    }
+
+    #[inline]
+    fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    #[inline]
+    fn out_byte(self) -> u8 {
+        self.0
+    }
+
+    fn value(self) -> u64 {
+        self.0.into()
+    }
+
+    #[inline]
+    fn from_in_byte(byte: u8) -> Self {
+        Wrapping(byte)
+    }
+}
+
+macro_rules! wide_cells {
+    ($($int:ty),*) => {
+        $(
+            impl Cell for Wrapping<$int> {
+                const ZERO: Self = Wrapping(0);
+
+                #[inline]
+                fn add(&mut self, n: u8) {
+                    *self += <$int>::from(n);
+                }
+
+                #[inline]
+                fn sub(&mut self, n: u8) {
+                    *self -= <$int>::from(n);
+                }
+
+                #[inline]
+                fn set(&mut self, n: u8) {
+                    *self = Wrapping(n.into());
+                }
+
+                #[inline]
+                fn add_cell(&mut self, other: Self) {
+                    *self += other;
+                }
+
+                #[inline]
+                fn sub_cell(&mut self, other: Self) {
+                    *self -= other;
+                }
+
+                #[inline]
+                fn add_cell_times(&mut self, other: Self, n: u8) {
+                    *self += other * Wrapping(<$int>::from(n));
+                }
+
+                #[inline]
+                fn is_zero(self) -> bool {
+                    self.0 == 0
+                }
+
+                #[inline]
+                fn out_byte(self) -> u8 {
+                    self.0 as u8
+                }
+
+                fn value(self) -> u64 {
+                    self.0.into()
+                }
+
+                #[inline]
+                fn from_in_byte(byte: u8) -> Self {
+                    Wrapping(byte.into())
+                }
+            }
+
+            impl Cell for Saturating<$int> {
+                const ZERO: Self = Saturating(0);
+
+                #[inline]
+                fn add(&mut self, n: u8) {
+                    self.0 = self.0.saturating_add(n.into());
+                }
+
+                #[inline]
+                fn sub(&mut self, n: u8) {
+                    self.0 = self.0.saturating_sub(n.into());
+                }
+
+                #[inline]
+                fn set(&mut self, n: u8) {
+                    self.0 = n.into();
+                }
+
+                #[inline]
+                fn add_cell(&mut self, other: Self) {
+                    self.0 = self.0.saturating_add(other.0);
+                }
+
+                #[inline]
+                fn sub_cell(&mut self, other: Self) {
+                    self.0 = self.0.saturating_sub(other.0);
+                }
+
+                #[inline]
+                fn add_cell_times(&mut self, other: Self, n: u8) {
+                    self.0 = self.0.saturating_add(other.0.saturating_mul(n.into()));
+                }
+
+                #[inline]
+                fn is_zero(self) -> bool {
+                    self.0 == 0
+                }
+
+                #[inline]
+                fn out_byte(self) -> u8 {
+                    self.0 as u8
+                }
+
+                fn value(self) -> u64 {
+                    self.0.into()
+                }
+
+                #[inline]
+                fn from_in_byte(byte: u8) -> Self {
+                    Saturating(byte.into())
+                }
+            }
+        )*
+    };
+}
+
+wide_cells!(u16, u32);
+
+/// A cell that can only be `0` or `1`.
+///
+/// Arithmetic is done modulo 2, so every `+` and `-` toggles the bit, and `Add(n)` toggles it
+/// if `n` is odd. Moving a cell into another one is an XOR.
+///
+/// `.` writes the ASCII character `'0'` or `'1'`, and `,` stores the lowest bit of the byte read,
+/// so reading `'0'` or `'1'` does what one would expect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Bit(bool);
+
+impl Cell for Bit {
+    const ZERO: Self = Bit(false);
+
+    #[inline]
+    fn add(&mut self, n: u8) {
+        self.0 ^= n % 2 == 1;
+    }
+
+    #[inline]
+    fn sub(&mut self, n: u8) {
+        // -1 and +1 are the same thing modulo 2
+        self.add(n);
+    }
+
+    #[inline]
+    fn set(&mut self, n: u8) {
+        self.0 = n % 2 == 1;
+    }
+
+    #[inline]
+    fn add_cell(&mut self, other: Self) {
+        self.0 ^= other.0;
+    }
+
+    #[inline]
+    fn sub_cell(&mut self, other: Self) {
+        self.add_cell(other);
+    }
+
+    #[inline]
+    fn add_cell_times(&mut self, other: Self, n: u8) {
+        self.0 ^= other.0 && n % 2 == 1;
+    }
+
+    #[inline]
+    fn is_zero(self) -> bool {
+        !self.0
+    }
+
+    #[inline]
+    fn out_byte(self) -> u8 {
+        match self.0 {
+            true => b'1',
+            false => b'0',
+        }
+    }
+
+    fn value(self) -> u64 {
+        self.0.into()
+    }
+
+    /// The digit, so that the output is readable
+    #[inline]
+    fn out_value(self) -> u64 {
+        self.out_byte().into()
+    }
+
+    #[inline]
+    fn from_in_byte(byte: u8) -> Self {
+        Bit(byte % 2 == 1)
+    }
+}
+
+/// A cell that stays at its maximum or minimum value instead of wrapping around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Saturating<T>(T);
+
+impl Cell for Saturating<u8> {
+    const ZERO: Self = Saturating(0);
+
+    #[inline]
+    fn add(&mut self, n: u8) {
+        self.0 = self.0.saturating_add(n);
+    }
+
+    #[inline]
+    fn sub(&mut self, n: u8) {
+        self.0 = self.0.saturating_sub(n);
+    }
+
+    #[inline]
+    fn set(&mut self, n: u8) {
+        self.0 = n;
+    }
+
+    #[inline]
+    fn add_cell(&mut self, other: Self) {
+        self.add(other.0);
+    }
+
+    #[inline]
+    fn sub_cell(&mut self, other: Self) {
+        self.sub(other.0);
+    }
+
+    #[inline]
+    fn add_cell_times(&mut self, other: Self, n: u8) {
+        self.add(other.0.saturating_mul(n));
+    }
+
+    #[inline]
+    fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    #[inline]
+    fn out_byte(self) -> u8 {
+        self.0
+    }
+
+    fn value(self) -> u64 {
+        self.0.into()
+    }
+
+    #[inline]
+    fn from_in_byte(byte: u8) -> Self {
+        Saturating(byte)
+    }
+}
+
+/// A bit that is set by any `+` and cleared by any `-`
+impl Cell for Saturating<Bit> {
+    const ZERO: Self = Saturating(Bit::ZERO);
+
+    #[inline]
+    fn add(&mut self, n: u8) {
+        self.0 .0 |= n > 0;
+    }
+
+    #[inline]
+    fn sub(&mut self, n: u8) {
+        self.0 .0 &= n == 0;
+    }
+
+    #[inline]
+    fn set(&mut self, n: u8) {
+        // the optimizer folds saturating constants for bits to 0 or 1, but any value above 0 is
+        // a set bit anyway
+        self.0 .0 = n > 0;
+    }
+
+    #[inline]
+    fn add_cell(&mut self, other: Self) {
+        self.0 .0 |= other.0 .0;
+    }
+
+    #[inline]
+    fn sub_cell(&mut self, other: Self) {
+        self.0 .0 &= !other.0 .0;
+    }
+
+    #[inline]
+    fn add_cell_times(&mut self, other: Self, n: u8) {
+        self.0 .0 |= other.0 .0 && n > 0;
+    }
+
+    #[inline]
+    fn is_zero(self) -> bool {
+        self.0.is_zero()
+    }
+
+    #[inline]
+    fn out_byte(self) -> u8 {
+        self.0.out_byte()
+    }
+
+    fn value(self) -> u64 {
+        self.0.value()
+    }
+
+    #[inline]
+    fn out_value(self) -> u64 {
+        self.0.out_value()
+    }
+
+    #[inline]
+    fn from_in_byte(byte: u8) -> Self {
+        Saturating(Bit::from_in_byte(byte))
+    }
+}
@@ -0,0 +1,95 @@
+//! rendering the tape for humans, for debuggers and debug output
+
+use std::{fmt::Write, num::Wrapping};
+
+/// The amount of cells on each side of the pointer that `#` shows
+pub const DEBUG_WINDOW: usize = 5;
+
+/// Renders the cells up to `window` cells left and right of the pointer, with the index, the
+/// decimal value and the character of every cell. The cell under the pointer is put in brackets.
+///
+/// ```text
+/// cell    1    2 [  3]   4    5
+/// dec   101  108 [108] 111    0
+/// char    e    l [  l]   o    .
+/// ```
+///
+/// Characters that aren't printable ASCII are shown as `.`.
+pub fn format_tape(tape: &[Wrapping<u8>], ptr: usize, window: usize) -> String {
+    let start = ptr.saturating_sub(window);
+    let end = ptr.saturating_add(window).saturating_add(1).min(tape.len());
+    let cells = start..end;
+
+    let mut out = String::new();
+    let mut row = |name: &str, fmt_cell: &dyn Fn(usize) -> String| {
+        write!(out, "{name:<5}").unwrap();
+        for idx in cells.clone() {
+            let cell = fmt_cell(idx);
+            if idx == ptr {
+                write!(out, "[{cell:>3}]").unwrap();
+            } else {
+                write!(out, " {cell:>3} ").unwrap();
+            }
+        }
+        out.truncate(out.trim_end().len());
+        out.push('\n');
+    };
+
+    row("cell", &|idx| idx.to_string());
+    row("dec", &|idx| tape[idx].0.to_string());
+    row("char", &|idx| {
+        let byte = tape[idx].0;
+        if byte.is_ascii_graphic() || byte == b' ' {
+            char::from(byte).to_string()
+        } else {
+            ".".to_owned()
+        }
+    });
+
+    out
+}
+
+/// What `#` writes to stdout, the cells around the pointer like [`format_tape`] and an empty line.
+/// Only the start of `tape` up to the window is looked at.
+pub fn debug_dump(tape: impl IntoIterator<Item = u8>, ptr: usize) -> String {
+    let tape = tape
+        .into_iter()
+        .take(ptr + DEBUG_WINDOW + 1)
+        .map(Wrapping)
+        .collect::<Vec<_>>();
+    format_tape(&tape, ptr, DEBUG_WINDOW) + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Wrapping;
+
+    #[test]
+    fn marks_pointer_cell() {
+        let tape = b"Hello\0\0"
+            .iter()
+            .copied()
+            .map(Wrapping)
+            .collect::<Vec<_>>();
+
+        let dump = super::format_tape(&tape, 3, 2);
+
+        assert_eq!(
+            dump,
+            "\
+cell    1    2 [  3]   4    5
+dec   101  108 [108] 111    0
+char    e    l [  l]   o    .
+"
+        );
+    }
+
+    #[test]
+    fn window_clamped_at_tape_start() {
+        let tape = [Wrapping(7), Wrapping(0)];
+
+        let dump = super::format_tape(&tape, 0, 3);
+
+        assert_eq!(dump, "cell [  0]   1\ndec  [  7]   0\nchar [  .]   .\n");
+    }
+}
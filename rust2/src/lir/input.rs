@@ -0,0 +1,88 @@
+//! input sources for running programs in tests
+
+use std::io::{self, Read};
+
+/// An endless stream of pseudo random bytes that is always the same for the same seed, for
+/// reproducible tests of programs that read input.
+///
+/// The bytes come from SplitMix64, which is fast and good enough for this, but not suitable for
+/// anything that needs real randomness. They don't depend on how many bytes are read at once.
+#[derive(Debug, Clone)]
+pub struct SeededInput {
+    state: u64,
+    /// the bytes of the last number that haven't been read yet, from the back
+    bytes: [u8; 8],
+    remaining: usize,
+}
+
+impl SeededInput {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            bytes: [0; 8],
+            remaining: 0,
+        }
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        if self.remaining == 0 {
+            self.bytes = self.next_u64().to_le_bytes();
+            self.remaining = self.bytes.len();
+        }
+        self.remaining -= 1;
+        self.bytes[self.bytes.len() - 1 - self.remaining]
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl Read for SeededInput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        for byte in buf.iter_mut() {
+            *byte = self.next_byte();
+        }
+        Ok(buf.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::SeededInput;
+
+    fn bytes(seed: u64) -> Vec<u8> {
+        let mut buf = vec![0; 100];
+        SeededInput::new(seed).read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn same_seed_same_bytes() {
+        assert_eq!(bytes(42), bytes(42));
+        assert_ne!(bytes(42), bytes(43));
+    }
+
+    #[test]
+    fn same_bytes_for_any_read_size() {
+        let mut input = SeededInput::new(42);
+        let mut buf = vec![0; 100];
+        let mut start = 0;
+        for len in [1, 3, 7, 1, 20].into_iter().cycle() {
+            let end = usize::min(start + len, buf.len());
+            input.read_exact(&mut buf[start..end]).unwrap();
+            start = end;
+            if start == buf.len() {
+                break;
+            }
+        }
+
+        assert_eq!(buf, bytes(42));
+    }
+}
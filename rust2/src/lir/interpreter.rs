@@ -1,139 +1,2201 @@
 use std::{
-    io::{Read, Write},
+    cmp,
+    collections::HashSet,
+    fmt::{Display, Formatter},
+    io::{self, ErrorKind, Read, Write},
+    mem,
     num::Wrapping,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
 
-use crate::lir::{Lir, Stmt};
+use bumpalo::Bump;
 
-const MEM_SIZE: usize = 32_000;
+use crate::{
+    hir::Hir,
+    lir::{
+        cell::{ArithPolicy, Bit, Cell, CellWidth, Saturating},
+        dump::debug_dump,
+        output::{OutputEncoder, RawByte},
+        Lir, Stmt,
+    },
+    parse::Span,
+};
+
+/// The default amount of cells on the tape
+pub(crate) const MEM_SIZE: usize = 32_000;
+
+/// How many statements are executed between two checks of `RunConfig::cancel`. Checking the
+/// atomic before every statement would slow down the hot loop.
+const CANCEL_CHECK_INTERVAL: u64 = 65536;
+
+/// What happens when the pointer is moved off either end of the tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapeMode {
+    /// Wrap around to the other end of the tape
+    Wrap,
+    /// Stop execution with a `RuntimeError::PointerOutOfBounds`, for programs that assume an
+    /// infinite tape
+    Strict,
+    /// Extend the tape with zeroed cells when the pointer moves past its end, doubling its size.
+    /// `RunConfig::mem_size` is only the starting size.
+    Grow { left: LeftEdge },
+}
+
+impl Default for TapeMode {
+    fn default() -> Self {
+        Self::Wrap
+    }
+}
+
+/// What happens when the pointer is moved left of the first cell of a `TapeMode::Grow` tape
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeftEdge {
+    /// Wrap around to the current end of the tape
+    Wrap,
+    /// Stop execution with a `RuntimeError::PointerOutOfBounds`
+    Strict,
+}
+
+impl Default for LeftEdge {
+    fn default() -> Self {
+        Self::Strict
+    }
+}
+
+/// What `,` does with the current cell once stdin has no more input
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EofBehavior {
+    /// Set the cell to 0
+    Zero,
+    /// Set the cell to -1, which wraps around to the maximum value of the cell. Saturating cells
+    /// are set to 0.
+    NegativeOne,
+    /// Leave the cell as it is
+    Unchanged,
+}
+
+impl Default for EofBehavior {
+    fn default() -> Self {
+        Self::Unchanged
+    }
+}
+
+impl EofBehavior {
+    /// The value of an 8 bit wrapping cell that was `cell` after `,` reached the end of the input,
+    /// for the backends that only have those
+    #[cfg(any(feature = "threaded-dispatch", feature = "asm-jit"))]
+    pub(crate) fn byte_at_eof(self, cell: u8) -> u8 {
+        match self {
+            Self::Zero => 0,
+            Self::NegativeOne => u8::MAX,
+            Self::Unchanged => cell,
+        }
+    }
+}
+
+/// Reads the next byte for `,`, `None` at the end of the input. Interrupted reads are retried.
+pub(crate) fn read_byte(mut stdin: impl Read) -> io::Result<Option<u8>> {
+    let mut buf = [0; 1];
+    loop {
+        match stdin.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buf[0])),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RunConfig {
+    pub tape_mode: TapeMode,
+    pub cell_width: CellWidth,
+    /// The HIR must have been optimized with the same policy and cell width, see
+    /// `hir::optimized_hir_for_cells`
+    pub arith_policy: ArithPolicy,
+    /// The amount of output bytes buffered before they are written and flushed to stdout.
+    /// `0` flushes after every `.`. The rest is always flushed before reading input and when the
+    /// program stops.
+    pub out_buffer: usize,
+    /// Flush after every `.`, no matter how big `out_buffer` is, for interactive programs that
+    /// must show their output right away
+    pub flush_on_output: bool,
+    pub eof_behavior: EofBehavior,
+    /// The amount of cells on the tape, the pointer wraps around at the end of it. With
+    /// `TapeMode::Grow`, this is only the starting size. Must not be 0.
+    pub mem_size: usize,
+    /// Once this is set, execution stops with a `RuntimeError::Cancelled`, so that another thread
+    /// can stop a program that runs for too long. It is only checked every 65536 statements.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The maximum amount of statements that are executed before the program is stopped with a
+    /// `RuntimeError::OutOfFuel`. Unlike `cancel`, this stops at the same point on every machine.
+    pub fuel: Option<u64>,
+    /// Stop with a `RuntimeError::PointerOutOfBounds` when a statement accesses a cell at an
+    /// offset from the pointer that is off the tape, even if the tape mode would wrap around to
+    /// it. This is for debugging programs, it runs in a separate, slower loop.
+    pub bounds_check: bool,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            tape_mode: TapeMode::default(),
+            cell_width: CellWidth::default(),
+            arith_policy: ArithPolicy::default(),
+            out_buffer: 8 * 1024,
+            flush_on_output: false,
+            eof_behavior: EofBehavior::default(),
+            mem_size: MEM_SIZE,
+            cancel: None,
+            fuel: None,
+            bounds_check: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeError {
+    /// The pointer was moved off the tape from cell `ptr` by the code at `span` under
+    /// `TapeMode::Strict`, or left of the first cell under `LeftEdge::Strict`. It is also returned
+    /// for a cell at a nonzero `offset` from the pointer that is off the tape in these modes, or
+    /// in any mode with `RunConfig::bounds_check`.
+    PointerOutOfBounds { ptr: usize, offset: i32, span: Span },
+    /// `RunConfig::cancel` was set while the program was running
+    Cancelled,
+    /// The program executed `RunConfig::fuel` statements without finishing
+    OutOfFuel,
+    /// Reading from stdin or writing to stdout failed. The end of the input is not an error, it is
+    /// handled by `RunConfig::eof_behavior`.
+    Io { kind: ErrorKind, message: String },
+}
 
-type Memory = [Wrapping<u8>; MEM_SIZE];
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PointerOutOfBounds {
+                ptr,
+                offset: 0,
+                span,
+            } => write!(
+                f,
+                "Pointer moved off the tape from cell {ptr} at byte {}",
+                span.start()
+            ),
+            Self::PointerOutOfBounds { ptr, offset, span } => write!(
+                f,
+                "The cell at offset {offset} from cell {ptr} is off the tape at byte {}",
+                span.start()
+            ),
+            Self::Cancelled => f.write_str("The program was cancelled"),
+            Self::OutOfFuel => f.write_str("The program ran out of fuel"),
+            Self::Io { message, .. } => write!(f, "Failed to read or write: {message}"),
+        }
+    }
+}
 
+impl From<io::Error> for RuntimeError {
+    fn from(err: io::Error) -> Self {
+        Self::Io {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+/// An access to a cell, see [`run_with_tape_profile`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileEvent {
+    /// The index of the cell
+    pub ptr: usize,
+    pub access: Access,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    /// Also used when the old value is read first, like for `+`
+    Write,
+}
+
+/// A statement that was executed, see [`run_traced`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// The index of the statement in `Lir::stmts`
+    pub ip: usize,
+    pub stmt: Stmt,
+    /// The pointer after the statement
+    pub ptr: usize,
+    /// The value of the cell after the statement. That's the cell at the offset for the
+    /// statements with one that write to it, and the current cell for all others. `MoveMulTo`
+    /// writes to several cells, so it reports the current cell, which it cleared.
+    pub cell: u64,
+}
+
+/// Records the statements a run executes, see [`run_traced`]
+pub trait TraceRecorder {
+    /// Called after every executed statement, `entry` creates the entry for it. Not calling it
+    /// costs nothing.
+    fn record(&mut self, entry: impl FnOnce() -> TraceEntry);
+}
+
+impl<X: TraceRecorder> TraceRecorder for &mut X {
+    #[inline(always)]
+    fn record(&mut self, entry: impl FnOnce() -> TraceEntry) {
+        (**self).record(entry)
+    }
+}
+
+/// Records nothing, which compiles the tracing away completely
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoTrace;
+
+impl TraceRecorder for NoTrace {
+    #[inline(always)]
+    fn record(&mut self, _: impl FnOnce() -> TraceEntry) {}
+}
+
+/// Records the first `max_len` executed statements, the rest of the run is left out so that long
+/// running programs can't use up all the memory
+#[derive(Debug, Clone)]
+pub struct Trace {
+    entries: Vec<TraceEntry>,
+    max_len: usize,
+    truncated: bool,
+}
+
+impl Trace {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            max_len,
+            truncated: false,
+        }
+    }
+
+    pub fn entries(&self) -> &[TraceEntry] {
+        &self.entries
+    }
+
+    /// Whether statements were left out because the trace was full
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+}
+
+impl TraceRecorder for Trace {
+    fn record(&mut self, entry: impl FnOnce() -> TraceEntry) {
+        if self.entries.len() < self.max_len {
+            self.entries.push(entry());
+        } else {
+            self.truncated = true;
+        }
+    }
+}
+
+/// What a finished run did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct RunStats {
+    /// The amount of LIR statements that were executed
+    pub instructions_executed: u64,
+    /// The highest cell the pointer was moved to
+    pub max_ptr: usize,
+    /// The amount of bytes written to stdout
+    pub out_bytes: u64,
+    /// The amount of bytes read from stdin, not counting the end of the input
+    pub in_bytes: u64,
+}
+
+/// What happened in a single [`Interpreter::step`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// A statement was executed and there are more to come
+    Continue,
+    /// The program is finished, stepping further does nothing
+    Halted,
+    /// The next statement was generated from the code at a breakpoint, see
+    /// [`Interpreter::run_to_breakpoint`]
+    Breakpoint(Span),
+}
+
+/// An interpreter that can be driven one statement at a time, for debuggers. [`run`] runs the
+/// same code in a loop.
 // `repr(C)` to make sure rustc never reorders the fields weirdly
 // maybe useless, but seems to give tiny wins
 #[repr(C)]
-struct Interpreter<'lir, W, R, P> {
+pub struct Interpreter<
+    'lir,
+    W,
+    R,
+    P = fn(usize),
+    C = Wrapping<u8>,
+    E = RawByte,
+    T = fn(ProfileEvent),
+    X = NoTrace,
+> {
     code: &'lir Lir<'lir>,
+    config: &'lir RunConfig,
     profile_collector: P,
+    tape_collector: T,
+    trace: X,
+    encoder: E,
     ip: usize,
     ptr: usize,
-    mem: Memory,
+    /// the highest value `ptr` had, to know which part of the tape was used
+    max_ptr: usize,
+    /// `ptr` must stay below this, or the statements with an offset might leave the tape. Only
+    /// differs from the tape length with `TapeMode::Grow`, where it leaves room for the offsets.
+    ptr_limit: usize,
+    stats: RunStats,
+    /// the amount of executed statements at which the cancellation and fuel are checked next
+    check_at: u64,
+    /// the error the program stopped with, stepping further returns it again
+    error: Option<RuntimeError>,
+    mem: Vec<C>,
+    out_buf: Vec<u8>,
     stdout: W,
     stdin: R,
 }
 
-pub fn run<W, R, P>(code: &Lir<'_>, stdout: W, stdin: R, profile_collector: P)
+/// Runs the code, writing the lowest byte of the cell as is on `.`
+pub fn run<W, R, P>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    profile_collector: P,
+) -> Result<RunStats, RuntimeError>
 where
     W: Write,
     R: Read,
     P: FnMut(usize),
 {
-    let mut interpreter = Interpreter {
+    run_with_encoder(code, stdout, stdin, config, RawByte, profile_collector)
+}
+
+/// Runs the code with the default config on `input` and returns everything it wrote to stdout
+pub fn run_to_vec(code: &Lir<'_>, input: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+    let mut stdout = Vec::new();
+    run(code, &mut stdout, input, &RunConfig::default(), |_| {})?;
+    Ok(stdout)
+}
+
+/// Like [`run_to_vec`], but the output is decoded as lossy UTF-8
+pub fn run_to_string(code: &Lir<'_>, input: &[u8]) -> Result<String, RuntimeError> {
+    let stdout = run_to_vec(code, input)?;
+    Ok(String::from_utf8_lossy(&stdout).into_owned())
+}
+
+/// Runs the code, turning the cells into output with `encoder` on `.`
+pub fn run_with_encoder<W, R, E, P>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    encoder: E,
+    profile_collector: P,
+) -> Result<RunStats, RuntimeError>
+where
+    W: Write,
+    R: Read,
+    E: OutputEncoder,
+    P: FnMut(usize),
+{
+    run_with_collectors(
         code,
-        ip: 0,
-        ptr: 0,
         stdout,
         stdin,
-        mem: [Wrapping(0u8); MEM_SIZE],
+        config,
+        encoder,
         profile_collector,
-    };
+        |_| {},
+        NoTrace,
+    )
+}
+
+/// Runs the code like [`run`], and also calls `tape_collector` for every access to a cell. Like
+/// `profile_collector`, it costs nothing if it does nothing.
+///
+/// Every statement reports the cells it touches, and scans report every cell they check. Only the
+/// dump of `#` reads the tape without reporting it.
+pub fn run_with_tape_profile<W, R, P, T>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    profile_collector: P,
+    tape_collector: T,
+) -> Result<RunStats, RuntimeError>
+where
+    W: Write,
+    R: Read,
+    P: FnMut(usize),
+    T: FnMut(ProfileEvent),
+{
+    run_with_collectors(
+        code,
+        stdout,
+        stdin,
+        config,
+        RawByte,
+        profile_collector,
+        tape_collector,
+        NoTrace,
+    )
+}
+
+/// Runs the code like [`run`], and records every executed statement into `trace`, see
+/// [`TraceRecorder`]
+///
+/// ```
+/// # use brainfuck::lir::interpreter::{run_traced, RunConfig, Trace};
+/// let alloc = bumpalo::Bump::new();
+/// let ast = brainfuck::parse::parse_bytes(&alloc, b"++>+").unwrap();
+/// let hir = brainfuck::hir::optimized_hir(&alloc, &ast);
+/// let lir = brainfuck::lir::generate(&alloc, &hir);
+///
+/// let mut trace = Trace::new(1000);
+/// run_traced(&lir, std::io::sink(), [].as_slice(), &RunConfig::default(), &mut trace).unwrap();
+/// assert_eq!(trace.entries().last().unwrap().cell, 1);
+/// ```
+pub fn run_traced<W, R, X>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    trace: X,
+) -> Result<RunStats, RuntimeError>
+where
+    W: Write,
+    R: Read,
+    X: TraceRecorder,
+{
+    run_with_collectors(code, stdout, stdin, config, RawByte, |_| {}, |_| {}, trace)
+}
 
-    // SAFETY: `Lir` can only be produced by the `crate::lir` module, which is trusted to not
-    // produce out of bounds jumps and put the `End` at the end
-    unsafe {
-        interpreter.execute();
+#[allow(clippy::too_many_arguments)]
+fn run_with_collectors<W, R, E, P, T, X>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    encoder: E,
+    profile_collector: P,
+    tape_collector: T,
+    trace: X,
+) -> Result<RunStats, RuntimeError>
+where
+    W: Write,
+    R: Read,
+    E: OutputEncoder,
+    P: FnMut(usize),
+    T: FnMut(ProfileEvent),
+    X: TraceRecorder,
+{
+    match (config.cell_width, config.arith_policy) {
+        (CellWidth::U8, ArithPolicy::Wrap) => run_with_cell::<_, _, _, _, _, _, Wrapping<u8>>(
+            code,
+            stdout,
+            stdin,
+            config,
+            encoder,
+            profile_collector,
+            tape_collector,
+            trace,
+        ),
+        (CellWidth::U8, ArithPolicy::Saturate) => {
+            run_with_cell::<_, _, _, _, _, _, Saturating<u8>>(
+                code,
+                stdout,
+                stdin,
+                config,
+                encoder,
+                profile_collector,
+                tape_collector,
+                trace,
+            )
+        }
+        (CellWidth::Bit, ArithPolicy::Wrap) => run_with_cell::<_, _, _, _, _, _, Bit>(
+            code,
+            stdout,
+            stdin,
+            config,
+            encoder,
+            profile_collector,
+            tape_collector,
+            trace,
+        ),
+        (CellWidth::Bit, ArithPolicy::Saturate) => {
+            run_with_cell::<_, _, _, _, _, _, Saturating<Bit>>(
+                code,
+                stdout,
+                stdin,
+                config,
+                encoder,
+                profile_collector,
+                tape_collector,
+                trace,
+            )
+        }
+        (CellWidth::U16, ArithPolicy::Wrap) => run_with_cell::<_, _, _, _, _, _, Wrapping<u16>>(
+            code,
+            stdout,
+            stdin,
+            config,
+            encoder,
+            profile_collector,
+            tape_collector,
+            trace,
+        ),
+        (CellWidth::U16, ArithPolicy::Saturate) => {
+            run_with_cell::<_, _, _, _, _, _, Saturating<u16>>(
+                code,
+                stdout,
+                stdin,
+                config,
+                encoder,
+                profile_collector,
+                tape_collector,
+                trace,
+            )
+        }
+        (CellWidth::U32, ArithPolicy::Wrap) => run_with_cell::<_, _, _, _, _, _, Wrapping<u32>>(
+            code,
+            stdout,
+            stdin,
+            config,
+            encoder,
+            profile_collector,
+            tape_collector,
+            trace,
+        ),
+        (CellWidth::U32, ArithPolicy::Saturate) => {
+            run_with_cell::<_, _, _, _, _, _, Saturating<u32>>(
+                code,
+                stdout,
+                stdin,
+                config,
+                encoder,
+                profile_collector,
+                tape_collector,
+                trace,
+            )
+        }
     }
 }
 
-impl<'c, W: Write, R: Read, P> Interpreter<'c, W, R, P>
+#[allow(clippy::too_many_arguments)]
+fn run_with_cell<W, R, E, P, T, X, C>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    encoder: E,
+    profile_collector: P,
+    tape_collector: T,
+    trace: X,
+) -> Result<RunStats, RuntimeError>
 where
+    W: Write,
+    R: Read,
+    E: OutputEncoder,
     P: FnMut(usize),
+    T: FnMut(ProfileEvent),
+    X: TraceRecorder,
+    C: Cell,
 {
-    unsafe fn execute(&mut self) {
-        let stmts = self.code.stmts();
+    assert!(config.mem_size > 0, "the tape must have at least one cell");
+    let mut mem = vec![C::ZERO; config.mem_size];
+    run_on_tape(
+        code,
+        &mut mem,
+        stdout,
+        stdin,
+        config,
+        encoder,
+        profile_collector,
+        tape_collector,
+        trace,
+    )
+}
+
+/// Runs the code on an existing tape, which is expected to be zeroed. With `TapeMode::Grow`, the
+/// tape is longer afterwards.
+#[allow(clippy::too_many_arguments)]
+fn run_on_tape<W, R, E, P, T, X, C>(
+    code: &Lir<'_>,
+    mem: &mut Vec<C>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+    encoder: E,
+    profile_collector: P,
+    tape_collector: T,
+    trace: X,
+) -> Result<RunStats, RuntimeError>
+where
+    W: Write,
+    R: Read,
+    E: OutputEncoder,
+    P: FnMut(usize),
+    T: FnMut(ProfileEvent),
+    X: TraceRecorder,
+    C: Cell,
+{
+    assert!(!mem.is_empty(), "the tape must have at least one cell");
+    let mut interpreter = Interpreter::with_tape(
+        code,
+        mem::take(mem),
+        stdout,
+        stdin,
+        config,
+        encoder,
+        profile_collector,
+        tape_collector,
+        trace,
+    );
+
+    let result = interpreter.execute();
+    let flushed = interpreter.flush_output();
+    *mem = interpreter.mem;
+    result?;
+    flushed?;
+
+    Ok(RunStats {
+        max_ptr: interpreter.max_ptr,
+        ..interpreter.stats
+    })
+}
+
+/// The index of the cell at `offset` from `ptr` on a tape that wraps around after `len` cells
+fn wrap_offset(ptr: usize, offset: i32, len: usize) -> usize {
+    (ptr as isize + offset as isize).rem_euclid(len as isize) as usize
+}
+
+/// Runs the code like [`run`] on a tape that was used before, starting at the cell `*ptr`. The tape
+/// and the pointer are left where the program stopped, even if it failed.
+pub(crate) fn run_from<W, R>(
+    code: &Lir<'_>,
+    mem: &mut Vec<Wrapping<u8>>,
+    ptr: &mut usize,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+) -> Result<RunStats, RuntimeError>
+where
+    W: Write,
+    R: Read,
+{
+    let mut interpreter = Interpreter::with_tape(
+        code,
+        mem::take(mem),
+        stdout,
+        stdin,
+        config,
+        RawByte,
+        |_| {},
+        |_| {},
+        NoTrace,
+    );
+    interpreter.ptr = *ptr;
+    interpreter.max_ptr = *ptr;
+
+    let result = interpreter.execute();
+    let flushed = interpreter.flush_output();
+    let stats = interpreter.stats();
+    *ptr = interpreter.ptr;
+    *mem = interpreter.mem;
+    result.and(flushed).map(|()| stats)
+}
+
+/// The amount of executed statements at which the limits are checked next, whichever of the
+/// cancellation check and the end of the fuel comes first
+fn next_check(executed: u64, fuel: Option<u64>) -> u64 {
+    let next = (executed / CANCEL_CHECK_INTERVAL + 1) * CANCEL_CHECK_INTERVAL;
+    match fuel {
+        Some(fuel) if fuel > executed && fuel < next => fuel,
+        _ => next,
+    }
+}
+
+/// Zeroes the cells that might have been written by a run of `code`. Those are the ones up to the
+/// highest pointer plus the largest offset, and the ones at the end of the tape that offsets left
+/// of the first cell wrap around to.
+fn clear_used_cells(code: &Lir<'_>, stats: RunStats, mem: &mut [Wrapping<u8>]) {
+    let (left, right) = code.max_offsets();
+    let last_cell = cmp::min(stats.max_ptr + right, mem.len() - 1);
+    mem[..=last_cell].fill(Wrapping(0));
+    let first_wrapped = mem.len().saturating_sub(left);
+    mem[first_wrapped..].fill(Wrapping(0));
+}
+
+/// Compiles the HIR into a function that runs the program on the given input and returns its
+/// output. The tape is allocated once and reset after every call, which makes this cheaper than
+/// calling [`run`] over and over for tiny programs.
+///
+/// Reading past the end of the input leaves the cell unchanged, like the default config.
+pub fn compile_to_fn<'lir>(
+    alloc: &'lir Bump,
+    hir: &Hir<'_>,
+) -> impl FnMut(&[u8]) -> Vec<u8> + 'lir {
+    let code = super::generate(alloc, hir);
+    let config = RunConfig::default();
+    let mut mem = vec![Wrapping(0u8); config.mem_size];
+
+    move |input| {
+        let mut stdout = Vec::new();
+        // the default config wraps the pointer around, so this can't fail
+        let stats = run_on_tape(
+            &code,
+            &mut mem,
+            &mut stdout,
+            input,
+            &config,
+            RawByte,
+            |_| {},
+            |_| {},
+            NoTrace,
+        )
+        .unwrap();
+        clear_used_cells(&code, stats, &mut mem);
+        stdout
+    }
+}
+
+/// Runs many programs one after another on the same tape, for harnesses that run lots of tiny
+/// programs. Only the part of the tape a program used is zeroed for the next one, instead of
+/// allocating and zeroing a fresh tape for every program like [`run`] does.
+pub struct BatchRunner {
+    config: RunConfig,
+    mem: Vec<Wrapping<u8>>,
+}
+
+impl BatchRunner {
+    /// The cell width of the config is ignored, the batch runner always uses 8 bit cells
+    pub fn new(config: RunConfig) -> Self {
+        assert!(config.mem_size > 0, "the tape must have at least one cell");
+        Self {
+            mem: vec![Wrapping(0); config.mem_size],
+            config,
+        }
+    }
+
+    pub fn run<W, R>(
+        &mut self,
+        code: &Lir<'_>,
+        stdout: W,
+        stdin: R,
+    ) -> Result<RunStats, RuntimeError>
+    where
+        W: Write,
+        R: Read,
+    {
+        let result = run_on_tape(
+            code,
+            &mut self.mem,
+            stdout,
+            stdin,
+            &self.config,
+            RawByte,
+            |_| {},
+            |_| {},
+            NoTrace,
+        );
+        match result {
+            Ok(stats) => {
+                clear_used_cells(code, stats, &mut self.mem);
+                Ok(stats)
+            }
+            Err(err) => {
+                // errors are rare, so just clear everything
+                self.mem.fill(Wrapping(0));
+                Err(err)
+            }
+        }
+    }
+}
+
+impl<'lir, W: Write, R: Read> Interpreter<'lir, W, R> {
+    /// Creates an interpreter with 8 bit cells that starts at the first statement. The cell width
+    /// of the config is ignored.
+    pub fn new(code: &'lir Lir<'lir>, stdout: W, stdin: R, config: &'lir RunConfig) -> Self {
+        assert!(config.mem_size > 0, "the tape must have at least one cell");
+        Self::with_tape(
+            code,
+            vec![Wrapping(0); config.mem_size],
+            stdout,
+            stdin,
+            config,
+            RawByte,
+            |_| {},
+            |_| {},
+            NoTrace,
+        )
+    }
+}
+
+impl<'lir, W: Write, R: Read, P, C: Cell, E, T, X> Interpreter<'lir, W, R, P, C, E, T, X>
+where
+    P: FnMut(usize),
+    E: OutputEncoder,
+    T: FnMut(ProfileEvent),
+    X: TraceRecorder,
+{
+    #[allow(clippy::too_many_arguments)]
+    fn with_tape(
+        code: &'lir Lir<'lir>,
+        mut mem: Vec<C>,
+        stdout: W,
+        stdin: R,
+        config: &'lir RunConfig,
+        encoder: E,
+        profile_collector: P,
+        tape_collector: T,
+        trace: X,
+    ) -> Self {
+        let ptr_limit = match config.tape_mode {
+            TapeMode::Wrap | TapeMode::Strict => mem.len(),
+            TapeMode::Grow { .. } => {
+                let (_, headroom) = code.max_offsets();
+                if mem.len() <= headroom {
+                    mem.resize(headroom + 1, C::ZERO);
+                }
+                mem.len() - headroom
+            }
+        };
+
+        Self {
+            code,
+            config,
+            ip: 0,
+            ptr: 0,
+            max_ptr: 0,
+            ptr_limit,
+            stats: RunStats::default(),
+            // checked right away, in case there is no fuel or it was cancelled already
+            check_at: 0,
+            error: None,
+            stdout,
+            stdin,
+            mem,
+            out_buf: Vec::with_capacity(config.out_buffer),
+            profile_collector,
+            tape_collector,
+            trace,
+            encoder,
+        }
+    }
+
+    /// Executes a single statement. The output is flushed once the program halts or fails.
+    pub fn step(&mut self) -> Result<StepResult, RuntimeError> {
+        // the pointer might be off the tape after an error
+        if let Some(err) = &self.error {
+            return Err(err.clone());
+        }
+        let result = match self.config.bounds_check {
+            true => self.execute_stmt::<true>(),
+            false => self.execute_stmt::<false>(),
+        };
+        let result = match result {
+            Ok(StepResult::Halted) => self.flush_output().map(|()| StepResult::Halted),
+            Err(err) => {
+                // the error of the program is more useful than one from flushing
+                let _ = self.flush_output();
+                Err(err)
+            }
+            result => result,
+        };
+        if let Err(err) = &result {
+            self.error = Some(err.clone());
+        }
+        result
+    }
+
+    /// Steps until the program halts or the next statement was generated from code at one of the
+    /// `breakpoints`, which are byte offsets into the source code. The statement at the breakpoint
+    /// is not executed yet. The first statement is always executed, so that calling this again
+    /// continues after the breakpoint.
+    pub fn run_to_breakpoint(
+        &mut self,
+        breakpoints: &HashSet<usize>,
+    ) -> Result<StepResult, RuntimeError> {
         loop {
+            if let StepResult::Halted = self.step()? {
+                return Ok(StepResult::Halted);
+            }
+            let span = self.code.debug()[self.ip];
+            // the jumps of a loop have the span of the whole loop, but only stand for a bracket
+            let (start, end) = match (self.code.stmts()[self.ip], span.loop_brackets()) {
+                (Stmt::JmpIfZero(_), Some((open, _))) => (open, open + 1),
+                (Stmt::JmpIfNonZero(_), Some((_, close))) => (close, close + 1),
+                _ => (span.start(), span.end()),
+            };
+            if (start..end).any(|offset| breakpoints.contains(&offset)) {
+                return Ok(StepResult::Breakpoint(span));
+            }
+        }
+    }
+
+    /// The index of the next statement in `Lir::stmts`
+    pub fn ip(&self) -> usize {
+        self.ip
+    }
+
+    /// The index of the current cell
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// The whole tape
+    pub fn mem(&self) -> &[C] {
+        &self.mem
+    }
+
+    /// The index of the cell at `offset` from the pointer that a statement accesses, or the error
+    /// if it is off the tape in a mode where that is one
+    pub(crate) fn offset_idx(&self, offset: i32) -> Result<usize, RuntimeError> {
+        let idx = (self.ptr as isize + offset as isize) as usize;
+        match idx < self.mem.len() {
+            true => Ok(idx),
+            false => self.offset_off_tape(offset),
+        }
+    }
+
+    /// What the program did so far
+    pub fn stats(&self) -> RunStats {
+        RunStats {
+            max_ptr: self.max_ptr,
+            ..self.stats
+        }
+    }
+
+    /// Runs until the program halts or fails, without the checks `step` does after an error
+    fn execute(&mut self) -> Result<(), RuntimeError> {
+        // the checks get their own loop, so that the normal one stays as fast as it was
+        match self.config.bounds_check {
+            true => while let StepResult::Continue = self.execute_stmt::<true>()? {},
+            false => while let StepResult::Continue = self.execute_stmt::<false>()? {},
+        }
+        Ok(())
+    }
+
+    /// Executes the next statement. With `CHECKED`, the cells at offsets are checked to be on
+    /// the tape before they are accessed, see `RunConfig::bounds_check`.
+    #[inline(always)]
+    fn execute_stmt<const CHECKED: bool>(&mut self) -> Result<StepResult, RuntimeError> {
+        let stmts = self.code.stmts();
+        if self.stats.instructions_executed == self.check_at {
+            self.check_limits()?;
+        }
+        let instr = if cfg!(debug_assertions) {
+            *stmts.get(self.ip).unwrap_or_else(|| {
+                panic!("instruction pointer {} out of bounds of the code", self.ip)
+            })
+        } else {
             // SAFETY: If the code ends with an `End` and there are no out of bounds jumps,
-            // `self.ip` will never be out of bounds
+            // `self.ip` will never be out of bounds. `Lir` can only be produced by the
+            // `crate::lir` module, which is trusted to do that and checks LIR from other places
+            // in `Lir::new_checked`, and `End` stays at itself.
             // Removing this bounds check speeds up execution by about 40%
-            debug_assert!(self.ip < stmts.len());
-            let instr = unsafe { *stmts.get_unchecked(self.ip) };
-            self.ip += 1;
-            match instr {
-                Stmt::Add(n) => {
-                    *self.elem_mut() += n;
-                }
-                Stmt::Sub(n) => {
-                    *self.elem_mut() -= n;
-                }
-                Stmt::AddOffset { offset, n } => *self.elem_mut_offset(offset) += n,
-                Stmt::SubOffset { offset, n } => *self.elem_mut_offset(offset) -= n,
-                Stmt::MoveAddTo { offset } => {
-                    let value = self.elem();
-                    *self.elem_mut() = Wrapping(0);
-                    *self.elem_mut_offset(offset) += value;
-                }
-                Stmt::Right(n) => {
-                    self.ptr += n as usize;
-                    if self.ptr >= MEM_SIZE {
-                        self.ptr = 0;
-                    }
+            unsafe { *stmts.get_unchecked(self.ip) }
+        };
+        let ip = self.ip;
+        self.ip += 1;
+        match instr {
+            Stmt::Add(n) => {
+                self.elem_mut().add(n);
+            }
+            Stmt::Sub(n) => {
+                self.elem_mut().sub(n);
+            }
+            Stmt::AddOffset { offset, n } => {
+                self.check_offset::<CHECKED>(offset)?;
+                self.elem_mut_offset(offset)?.add(n);
+            }
+            Stmt::SubOffset { offset, n } => {
+                self.check_offset::<CHECKED>(offset)?;
+                self.elem_mut_offset(offset)?.sub(n);
+            }
+            Stmt::MoveAddTo { offset } => {
+                // the loop this came from is never entered for a zero cell, so the other cell
+                // must not be touched, it might not even be on the tape
+                let value = self.elem();
+                if !value.is_zero() {
+                    self.check_offset::<CHECKED>(offset)?;
+                    *self.elem_mut() = C::ZERO;
+                    self.elem_mut_offset(offset)?.add_cell(value);
+                }
+            }
+            Stmt::MoveSubTo { offset } => {
+                let value = self.elem();
+                if !value.is_zero() {
+                    self.check_offset::<CHECKED>(offset)?;
+                    *self.elem_mut() = C::ZERO;
+                    self.elem_mut_offset(offset)?.sub_cell(value);
                 }
-                Stmt::Left(n) => {
-                    if self.ptr < n as usize {
-                        let diff = n as usize - self.ptr;
-                        self.ptr = MEM_SIZE - 1 - diff;
-                    } else {
-                        self.ptr -= n as usize;
+            }
+            Stmt::MoveMulTo(idx) => {
+                let value = self.elem();
+                if !value.is_zero() {
+                    // all of them first, so that no cell is changed if one is off the tape, also
+                    // without `CHECKED` for the tape modes that don't wrap
+                    for &(offset, _) in self.code.mul_targets(idx) {
+                        self.check_offset::<CHECKED>(offset)?;
+                        self.offset_idx(offset)?;
+                    }
+                    *self.elem_mut() = C::ZERO;
+                    for &(offset, factor) in self.code.mul_targets(idx) {
+                        self.elem_mut_offset(offset)?.add_cell_times(value, factor);
                     }
                 }
-                Stmt::Out => {
-                    let char = self.elem() as char;
-                    write!(self.stdout, "{char}").unwrap();
-                    self.stdout.flush().unwrap();
+            }
+            Stmt::Right(n) => {
+                self.ptr += n as usize;
+                if self.ptr >= self.ptr_limit {
+                    self.right_off_tape(n)?;
                 }
-                Stmt::In => {
-                    let mut buf = [0; 1];
-                    self.stdin.read_exact(&mut buf).unwrap();
-                    *self.elem_mut() = Wrapping(buf[0]);
+                self.max_ptr = cmp::max(self.max_ptr, self.ptr);
+            }
+            Stmt::Left(n) => {
+                if self.ptr < n as usize {
+                    self.left_off_tape(n)?;
+                    self.max_ptr = cmp::max(self.max_ptr, self.ptr);
+                } else {
+                    self.ptr -= n as usize;
+                }
+            }
+            Stmt::ScanRight(n) => self.scan_right(n)?,
+            Stmt::ScanLeft(n) => self.scan_left(n)?,
+            Stmt::Out => {
+                let cell = self.elem().out_value();
+                // writing to a `Vec` can't fail
+                self.encoder.encode(cell, &mut self.out_buf).unwrap();
+                if self.config.flush_on_output || self.out_buf.len() >= self.config.out_buffer {
+                    self.flush_output()?;
+                }
+            }
+            Stmt::OutConst(n) => {
+                let mut cell = C::ZERO;
+                cell.set(n);
+                self.encoder
+                    .encode(cell.out_value(), &mut self.out_buf)
+                    .unwrap();
+                if self.config.flush_on_output || self.out_buf.len() >= self.config.out_buffer {
+                    self.flush_output()?;
+                }
+            }
+            Stmt::OutRepeat { byte, count } => {
+                let mut cell = C::ZERO;
+                cell.set(byte);
+                let start = self.out_buf.len();
+                self.encoder
+                    .encode(cell.out_value(), &mut self.out_buf)
+                    .unwrap();
+                // the encoding is the same every time, so it only has to be done once
+                let encoded = start..self.out_buf.len();
+                for _ in 1..count {
+                    self.out_buf.extend_from_within(encoded.clone());
                 }
-                Stmt::SetN(n) => {
-                    *self.elem_mut() = Wrapping(n);
+                if self.config.flush_on_output || self.out_buf.len() >= self.config.out_buffer {
+                    self.flush_output()?;
                 }
-                Stmt::JmpIfZero(pos) => {
-                    if self.elem() == 0 {
-                        self.ip = pos as usize;
+            }
+            Stmt::OutBytes(idx) => {
+                for &byte in self.code.out_bytes(idx) {
+                    self.encoder.encode(byte.into(), &mut self.out_buf).unwrap();
+                }
+                if self.config.flush_on_output || self.out_buf.len() >= self.config.out_buffer {
+                    self.flush_output()?;
+                }
+            }
+            Stmt::In => {
+                // a prompt must be visible before waiting for the answer
+                if !self.out_buf.is_empty() {
+                    self.flush_output()?;
+                }
+                match self.read_byte()? {
+                    Some(byte) => {
+                        *self.elem_mut() = C::from_in_byte(byte);
+                        self.stats.in_bytes += 1;
                     }
+                    None => self.eof(),
+                }
+            }
+            Stmt::Debug => {
+                let tape = self.mem.iter().map(|cell| cell.out_byte());
+                let dump = debug_dump(tape, self.ptr);
+                self.out_buf.extend_from_slice(dump.as_bytes());
+                self.flush_output()?;
+            }
+            Stmt::SetN(n) => {
+                self.elem_mut().set(n);
+            }
+            Stmt::SetNOffset { offset, n } => {
+                self.check_offset::<CHECKED>(offset)?;
+                self.elem_mut_offset(offset)?.set(n);
+            }
+            Stmt::JmpIfZero(pos) => {
+                if self.elem().is_zero() {
+                    self.ip = pos as usize;
                 }
-                Stmt::JmpIfNonZero(pos) => {
-                    if self.elem() != 0 {
-                        self.ip = pos as usize;
+            }
+            Stmt::JmpIfNonZero(pos) => {
+                if !self.elem().is_zero() {
+                    self.ip = pos as usize;
+                }
+            }
+            Stmt::End => {
+                self.ip -= 1;
+                return Ok(StepResult::Halted);
+            }
+        }
+
+        self.stats.instructions_executed += 1;
+        // this should be a no-op if `profile_collector` is does nothing
+        (self.profile_collector)(self.ip);
+        // and this one with `NoTrace`, which never calls the closure
+        self.trace.record(|| {
+            let cell = match instr {
+                Stmt::AddOffset { offset, .. }
+                | Stmt::SubOffset { offset, .. }
+                | Stmt::SetNOffset { offset, .. }
+                | Stmt::MoveAddTo { offset }
+                | Stmt::MoveSubTo { offset } => {
+                    let idx = (self.ptr as isize + offset as isize) as usize;
+                    match idx < self.mem.len() {
+                        true => idx,
+                        // the statement would have failed if the tape mode didn't allow this
+                        false => wrap_offset(self.ptr, offset, self.ptr_limit),
                     }
                 }
-                Stmt::End => break,
+                _ => self.ptr,
+            };
+            TraceEntry {
+                ip,
+                stmt: instr,
+                ptr: self.ptr,
+                cell: self.mem[cell].value(),
             }
+        });
+
+        Ok(StepResult::Continue)
+    }
 
-            // this should be a no-op if `profile_collector` is does nothing
-            (self.profile_collector)(self.ip);
+    /// Called before the next statement is executed once `check_at` statements were executed
+    #[cold]
+    fn check_limits(&mut self) -> Result<(), RuntimeError> {
+        let executed = self.stats.instructions_executed;
+        // a program that needs exactly all of the fuel still finishes
+        let finished = matches!(self.code.stmts()[self.ip], Stmt::End);
+        if self.config.fuel == Some(executed) && !finished {
+            return Err(RuntimeError::OutOfFuel);
         }
+        if let Some(cancel) = &self.config.cancel {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(RuntimeError::Cancelled);
+            }
+        }
+        self.check_at = next_check(executed, self.config.fuel);
+        Ok(())
     }
 
-    fn elem_mut_offset(&mut self, offset: i32) -> &mut Wrapping<u8> {
-        let ptr = self.ptr as isize;
-        let offset = offset as isize;
-        // SAFETY: `self.ptr` is never out of bounds
-        debug_assert!(self.ptr < self.mem.len());
-        unsafe { self.mem.get_unchecked_mut((ptr + offset) as usize) }
+    fn flush_output(&mut self) -> Result<(), RuntimeError> {
+        self.stats.out_bytes += self.out_buf.len() as u64;
+        let result = self.stdout.write_all(&self.out_buf);
+        self.out_buf.clear();
+        result?;
+        self.stdout.flush()?;
+        Ok(())
     }
 
-    fn elem_mut(&mut self) -> &mut Wrapping<u8> {
+    /// `None` at the end of the input
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        read_byte(&mut self.stdin).map_err(RuntimeError::from)
+    }
+
+    #[cold]
+    fn eof(&mut self) {
+        match self.config.eof_behavior {
+            EofBehavior::Zero => *self.elem_mut() = C::ZERO,
+            EofBehavior::NegativeOne => {
+                *self.elem_mut() = C::ZERO;
+                self.elem_mut().sub(1);
+            }
+            EofBehavior::Unchanged => {}
+        }
+    }
+
+    /// Moves right in steps of `n` until the current cell is zero, stopping at the same cell as
+    /// `Loop([Right(n)])` would
+    fn scan_right(&mut self, n: u32) -> Result<(), RuntimeError> {
+        let step = n as usize;
+        loop {
+            let cells = &self.mem[self.ptr..self.ptr_limit];
+            match cells.iter().step_by(step).position(|cell| cell.is_zero()) {
+                Some(steps) => {
+                    self.record_scan(self.ptr, step as isize, steps + 1);
+                    self.ptr += steps * step;
+                    self.max_ptr = cmp::max(self.max_ptr, self.ptr);
+                    return Ok(());
+                }
+                None => {
+                    // all cells up to the end were checked, move off the end from the last one
+                    let last = self.ptr + (cells.len() - 1) / step * step;
+                    self.record_scan(self.ptr, step as isize, (last - self.ptr) / step + 1);
+                    self.max_ptr = cmp::max(self.max_ptr, last);
+                    self.ptr = last + step;
+                    self.right_off_tape(n)?;
+                }
+            }
+        }
+    }
+
+    /// Moves left in steps of `n` until the current cell is zero, stopping at the same cell as
+    /// `Loop([Left(n)])` would
+    fn scan_left(&mut self, n: u32) -> Result<(), RuntimeError> {
+        let step = n as usize;
+        loop {
+            let cells = &self.mem[..=self.ptr];
+            match cells
+                .iter()
+                .rev()
+                .step_by(step)
+                .position(|cell| cell.is_zero())
+            {
+                Some(steps) => {
+                    self.record_scan(self.ptr, -(step as isize), steps + 1);
+                    self.ptr -= steps * step;
+                    return Ok(());
+                }
+                None => {
+                    // all cells down to the start were checked, move off the start from the last
+                    self.record_scan(self.ptr, -(step as isize), self.ptr / step + 1);
+                    self.ptr %= step;
+                    self.left_off_tape(n)?;
+                    self.max_ptr = cmp::max(self.max_ptr, self.ptr);
+                }
+            }
+        }
+    }
+
+    /// Called when `Right(n)` moved the pointer to or past `ptr_limit`
+    #[cold]
+    fn right_off_tape(&mut self, n: u32) -> Result<(), RuntimeError> {
+        match self.config.tape_mode {
+            TapeMode::Wrap => {
+                self.ptr %= self.mem.len();
+                Ok(())
+            }
+            TapeMode::Strict => Err(self.pointer_out_of_bounds(self.ptr - n as usize)),
+            TapeMode::Grow { .. } => {
+                let headroom = self.mem.len() - self.ptr_limit;
+                let len = cmp::max(self.mem.len() * 2, self.ptr + headroom + 1);
+                self.mem.resize(len, C::ZERO);
+                self.ptr_limit = len - headroom;
+                Ok(())
+            }
+        }
+    }
+
+    /// Called when `Left(n)` would move the pointer left of the first cell
+    #[cold]
+    fn left_off_tape(&mut self, n: u32) -> Result<(), RuntimeError> {
+        match self.config.tape_mode {
+            TapeMode::Wrap
+            | TapeMode::Grow {
+                left: LeftEdge::Wrap,
+            } => {
+                let ptr = self.ptr as isize - n as isize;
+                self.ptr = ptr.rem_euclid(self.ptr_limit as isize) as usize;
+                Ok(())
+            }
+            TapeMode::Strict
+            | TapeMode::Grow {
+                left: LeftEdge::Strict,
+            } => Err(self.pointer_out_of_bounds(self.ptr)),
+        }
+    }
+
+    fn pointer_out_of_bounds(&self, ptr: usize) -> RuntimeError {
+        RuntimeError::PointerOutOfBounds {
+            ptr,
+            offset: 0,
+            // `self.ip` already points to the next statement
+            span: self.code.debug()[self.ip - 1],
+        }
+    }
+
+    /// Only does something if `CHECKED`, the normal loop lets `offset_off_tape` wrap around
+    #[inline(always)]
+    fn check_offset<const CHECKED: bool>(&self, offset: i32) -> Result<(), RuntimeError> {
+        let idx = self.ptr as i64 + i64::from(offset);
+        if CHECKED && !(0..self.mem.len() as i64).contains(&idx) {
+            return Err(RuntimeError::PointerOutOfBounds {
+                ptr: self.ptr,
+                offset,
+                span: self.code.debug()[self.ip - 1],
+            });
+        }
+        Ok(())
+    }
+
+    fn elem_mut_offset(&mut self, offset: i32) -> Result<&mut C, RuntimeError> {
+        let idx = (self.ptr as isize + offset as isize) as usize;
+        // a negative index turns into a huge one, so this catches both ends
+        let idx = match idx < self.mem.len() {
+            true => idx,
+            false => self.offset_off_tape(offset)?,
+        };
+        self.record_access(idx, Access::Write);
+        // SAFETY: `idx` was checked to be on the tape
+        Ok(unsafe { self.mem.get_unchecked_mut(idx) })
+    }
+
+    /// Called when the cell at `offset` from the pointer is off the tape. It wraps around the
+    /// ends of the tape, or is an error, like moving the pointer there would be.
+    #[cold]
+    fn offset_off_tape(&self, offset: i32) -> Result<usize, RuntimeError> {
+        match self.config.tape_mode {
+            TapeMode::Wrap
+            | TapeMode::Grow {
+                left: LeftEdge::Wrap,
+            } => Ok(wrap_offset(self.ptr, offset, self.ptr_limit)),
+            TapeMode::Strict
+            | TapeMode::Grow {
+                left: LeftEdge::Strict,
+            } => Err(RuntimeError::PointerOutOfBounds {
+                ptr: self.ptr,
+                offset,
+                span: self.code.debug()[self.ip - 1],
+            }),
+        }
+    }
+
+    fn elem_mut(&mut self) -> &mut C {
+        if cfg!(debug_assertions) && self.ptr >= self.mem.len() {
+            self.out_of_bounds(self.ptr);
+        }
+        self.record_access(self.ptr, Access::Write);
         // SAFETY: `self.ptr` is never out of bounds
-        debug_assert!(self.ptr < self.mem.len());
         unsafe { self.mem.get_unchecked_mut(self.ptr) }
     }
 
-    fn elem(&self) -> u8 {
+    fn elem(&mut self) -> C {
+        if cfg!(debug_assertions) && self.ptr >= self.mem.len() {
+            self.out_of_bounds(self.ptr);
+        }
+        self.record_access(self.ptr, Access::Read);
         // SAFETY: `self.ptr` is never out of bounds
-        debug_assert!(self.ptr < self.mem.len());
-        unsafe { self.mem.get_unchecked(self.ptr).0 }
+        unsafe { *self.mem.get_unchecked(self.ptr) }
+    }
+
+    fn record_access(&mut self, ptr: usize, access: Access) {
+        (self.tape_collector)(ProfileEvent { ptr, access });
+    }
+
+    /// Reports the reads of a scan that checked `count` cells from `start` in steps of `step`
+    fn record_scan(&mut self, start: usize, step: isize, count: usize) {
+        for i in 0..count {
+            let ptr = start as isize + i as isize * step;
+            self.record_access(ptr as usize, Access::Read);
+        }
+    }
+
+    /// Only called in debug builds, where a broken invariant should panic instead of being UB
+    #[cold]
+    #[inline(never)]
+    fn out_of_bounds(&self, idx: usize) -> ! {
+        panic!(
+            "cell {} out of bounds of the tape (ip: {}, ptr: {})",
+            idx as isize,
+            self.ip - 1,
+            self.ptr
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashSet,
+        io::{ErrorKind, Read, Write},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use bumpalo::Bump;
+
+    use crate::{
+        lir::{
+            cell::{ArithPolicy, CellWidth},
+            interpreter::{
+                EofBehavior, Interpreter, LeftEdge, RunConfig, RunStats, RuntimeError, StepResult,
+                TapeMode, Trace, TraceEntry,
+            },
+            Stmt,
+        },
+        parse::Span,
+    };
+
+    fn run_with_input(bf: &str, input: &[u8], config: &RunConfig) -> Result<Vec<u8>, RuntimeError> {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir_for_cells(
+            &alloc,
+            &ast,
+            config.cell_width,
+            config.arith_policy,
+        );
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = Vec::new();
+        super::run(&lir, &mut stdout, input, config, |_| {})?;
+        Ok(stdout)
+    }
+
+    fn run_stats(bf: &str, config: &RunConfig) -> Result<RunStats, RuntimeError> {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        super::run(&lir, std::io::sink(), std::io::empty(), config, |_| {})
+    }
+
+    fn run_with_config(bf: &str, config: &RunConfig) -> Result<Vec<u8>, RuntimeError> {
+        run_with_input(bf, &[], config)
+    }
+
+    fn run_with_tape_mode(bf: &str, tape_mode: TapeMode) -> Result<RunStats, RuntimeError> {
+        let config = RunConfig {
+            tape_mode,
+            ..RunConfig::default()
+        };
+        run_stats(bf, &config)
+    }
+
+    #[test]
+    fn right_out_of_bounds_errors() {
+        let config = RunConfig {
+            tape_mode: TapeMode::Strict,
+            mem_size: 10,
+            ..RunConfig::default()
+        };
+        let result = run_stats("+[>+]", &config);
+        assert_eq!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds {
+                ptr: 9,
+                offset: 0,
+                span: Span::single(2),
+            })
+        );
+    }
+
+    #[test]
+    fn offset_out_of_bounds_errors() {
+        let config = RunConfig {
+            bounds_check: true,
+            ..RunConfig::default()
+        };
+        // moves the cell to the one left of the first cell
+        let result = run_stats("+[-<+>]", &config);
+        assert_eq!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds {
+                ptr: 0,
+                offset: -1,
+                span: Span::start_end(1, 7),
+            })
+        );
+        // the loop is never entered, so the cell isn't accessed
+        assert!(run_stats("[-<+>]", &config).is_ok());
+
+        let config = RunConfig {
+            mem_size: 10,
+            ..config
+        };
+        let result = run_stats(">>>>>>>>>[-]+>+<", &config);
+        assert!(matches!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds {
+                ptr: 9,
+                offset: 1,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn offsets_wrap_around() {
+        let alloc = Bump::new();
+        let tape_after = |bf: &str, config: &RunConfig| {
+            let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+            let hir = crate::hir::optimized_hir(&alloc, &ast);
+            let lir = crate::lir::generate(&alloc, &hir);
+            let lir = alloc.alloc(lir);
+            let mut interpreter = Interpreter::new(lir, std::io::sink(), std::io::empty(), config);
+            while interpreter.step()? != StepResult::Halted {}
+            Ok::<_, RuntimeError>(
+                interpreter
+                    .mem()
+                    .iter()
+                    .map(|cell| cell.0)
+                    .collect::<Vec<_>>(),
+            )
+        };
+
+        let config = RunConfig {
+            mem_size: 4,
+            ..RunConfig::default()
+        };
+        // a `MoveAddTo` from the first cell to the one left of it
+        assert_eq!(tape_after("++[-<+>]", &config), Ok(vec![0, 0, 0, 2]));
+        // and from the last cell to the one right of it
+        assert_eq!(tape_after(">>>++[->+<]", &config), Ok(vec![2, 0, 0, 0]));
+
+        let config = RunConfig {
+            tape_mode: TapeMode::Strict,
+            ..config
+        };
+        assert_eq!(
+            tape_after("++[-<+>]", &config),
+            Err(RuntimeError::PointerOutOfBounds {
+                ptr: 0,
+                offset: -1,
+                span: Span::start_end(2, 8),
+            })
+        );
+    }
+
+    #[test]
+    fn left_out_of_bounds_errors() {
+        let result = run_with_tape_mode("+>>.<<<", TapeMode::Strict);
+        assert_eq!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds {
+                ptr: 2,
+                offset: 0,
+                span: Span::start_end(4, 7),
+            })
+        );
+    }
+
+    #[test]
+    fn left_out_of_bounds_wraps() {
+        let result = run_with_tape_mode("+<+", TapeMode::Wrap);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn pointer_wraps_by_the_distance() {
+        let config = RunConfig {
+            mem_size: 4,
+            ..RunConfig::default()
+        };
+        // the cells are 1 2 3 4, then it moves right by 2 from the last cell, left by 3, and left
+        // by 6, which is more than the whole tape
+        let bf = "+>++>+++>++++>>.<<<.<<<<<<.";
+        assert_eq!(run_with_config(bf, &config).unwrap(), [2, 3, 1]);
+    }
+
+    #[test]
+    fn run_stats_counted() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",[.>,]>>.<".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let stats = super::run(
+            &lir,
+            std::io::sink(),
+            b"abc".as_slice(),
+            &RunConfig::default(),
+            |_| {},
+        )
+        .unwrap();
+
+        assert_eq!(
+            stats,
+            RunStats {
+                // `In` and `JmpIfZero`, three iterations of `Out`/`Right`/`In`/`JmpIfNonZero`,
+                // then `Right`/`Out`/`Left`
+                instructions_executed: 2 + 3 * 4 + 3,
+                max_ptr: 5,
+                out_bytes: 4,
+                in_bytes: 3,
+            }
+        );
+    }
+
+    #[test]
+    fn growing_tape() {
+        let config = RunConfig {
+            tape_mode: TapeMode::Grow {
+                left: LeftEdge::Strict,
+            },
+            mem_size: 4,
+            ..RunConfig::default()
+        };
+        // the new cells must be zeroed, and the old ones kept
+        let bf = format!("{}>.<[<]>.", ">+".repeat(100));
+        let output = run_with_config(&bf, &config).unwrap();
+        assert_eq!(output, [0, 1]);
+        assert_eq!(run_stats(&bf, &config).unwrap().max_ptr, 101);
+    }
+
+    #[test]
+    fn growing_tape_left_edge() {
+        let tape_mode = TapeMode::Grow {
+            left: LeftEdge::Strict,
+        };
+        let result = run_with_tape_mode(">+<<", tape_mode);
+        assert_eq!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds {
+                ptr: 1,
+                offset: 0,
+                span: Span::start_end(2, 4),
+            })
+        );
+
+        let tape_mode = TapeMode::Grow {
+            left: LeftEdge::Wrap,
+        };
+        assert!(run_with_tape_mode(">+<<+", tape_mode).is_ok());
+    }
+
+    #[test]
+    fn strict_stays_on_tape() {
+        let result = run_with_tape_mode(">>>+<<>", TapeMode::Strict);
+        assert_eq!(result.unwrap().max_ptr, 3);
+    }
+
+    #[test]
+    fn bit_cells_toggle() {
+        let config = RunConfig {
+            cell_width: CellWidth::Bit,
+            ..RunConfig::default()
+        };
+        // `++` toggles twice, `[->+<]` xors the cell into its neighbour
+        let output = run_with_config(".+.+.+.>+.<[->+<].>.", &config).unwrap();
+        assert_eq!(output, b"0101100");
+
+        // `SetN(1) Out` becomes `OutConst(1)`, which has to be written like the cell
+        let output = run_with_config(",[-]+.", &config).unwrap();
+        assert_eq!(output, b"1");
+    }
+
+    #[test]
+    fn saturating_cells() {
+        let config = RunConfig {
+            arith_policy: ArithPolicy::Saturate,
+            ..RunConfig::default()
+        };
+        // `SetN(250) Add(10)` must not be folded into `SetN(4)`, 255 - 200 is '7'
+        let bf = format!(
+            "[-]{}{}{}.",
+            "+".repeat(250),
+            "+".repeat(10),
+            "-".repeat(200)
+        );
+        assert_eq!(run_with_config(&bf, &config).unwrap(), b"7");
+
+        // the same at runtime, when the optimizer can't see the value
+        let bf = format!(",{}{}.", "+".repeat(10), "-".repeat(200));
+        assert_eq!(run_with_input(&bf, &[250], &config).unwrap(), b"7");
+    }
+
+    #[test]
+    fn bigger_tape() {
+        let config = RunConfig {
+            mem_size: 64_000,
+            ..RunConfig::default()
+        };
+        let bf = format!(
+            "{}+{}.{}.",
+            ">".repeat(40_000),
+            "<".repeat(32_000),
+            ">".repeat(32_000)
+        );
+        assert_eq!(run_with_config(&bf, &config).unwrap(), [0, 1]);
+    }
+
+    #[test]
+    fn raw_bytes_written() {
+        let bf = format!("{}{}", "+".repeat(128), ".+".repeat(128));
+        let output = run_with_config(&bf, &RunConfig::default()).unwrap();
+        assert_eq!(output, (0x80..=0xff).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn input_errors() {
+        /// Fails once with `error`, then reads `a` forever
+        struct FailOnce(Option<ErrorKind>);
+
+        impl Read for FailOnce {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                if let Some(kind) = self.0.take() {
+                    return Err(std::io::Error::new(kind, "broken"));
+                }
+                buf.fill(b'a');
+                Ok(buf.len())
+            }
+        }
+
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",.,.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+        let run = |kind| {
+            let mut stdout = Vec::new();
+            let config = RunConfig::default();
+            let result = super::run(&lir, &mut stdout, FailOnce(Some(kind)), &config, |_| {});
+            result.map(|_| stdout)
+        };
+
+        // interrupted reads are retried
+        assert_eq!(run(ErrorKind::Interrupted).unwrap(), b"aa");
+        assert_eq!(
+            run(ErrorKind::PermissionDenied),
+            Err(RuntimeError::Io {
+                kind: ErrorKind::PermissionDenied,
+                message: "broken".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn eof_behavior() {
+        let output = |eof_behavior| {
+            let config = RunConfig {
+                eof_behavior,
+                ..RunConfig::default()
+            };
+            // the `+` makes sure that the optimizer can't see the value of the cell
+            run_with_input(",+,.", b"a", &config).unwrap()
+        };
+
+        assert_eq!(output(EofBehavior::Zero), [0]);
+        assert_eq!(output(EofBehavior::NegativeOne), [255]);
+        assert_eq!(output(EofBehavior::Unchanged), b"b");
+    }
+
+    #[test]
+    fn wide_cells() {
+        for cell_width in [CellWidth::U16, CellWidth::U32] {
+            let config = RunConfig {
+                cell_width,
+                ..RunConfig::default()
+            };
+            // 256 doesn't wrap around to 0, so the loop is entered
+            let bf = format!("[-]{}[>+<[-]]>.", "+".repeat(256));
+            assert_eq!(run_with_config(&bf, &config).unwrap(), [1]);
+            // only the low byte is written, but the cell is wider than that before
+            let bf = format!("{}>,[-<->]<.", "+".repeat(300));
+            assert_eq!(run_with_input(&bf, &[200], &config).unwrap(), b"d");
+            // 300 - 44 is 256 at runtime, which is only zero for 8 bit cells
+            let bf = format!("{}>,[-<->]<[>>+<<[-]]>>.", "+".repeat(300));
+            assert_eq!(run_with_input(&bf, &[44], &config).unwrap(), [1]);
+            let u8_config = RunConfig::default();
+            assert_eq!(run_with_input(&bf, &[44], &u8_config).unwrap(), [0]);
+        }
+    }
+
+    #[test]
+    fn debug_dumps_tape() {
+        let output = run_with_config("++>+++#>", &RunConfig::default()).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "\
+cell    0 [  1]   2    3    4    5    6
+dec     2 [  3]   0    0    0    0    0
+char    . [  .]   .    .    .    .    .
+
+"
+        );
+    }
+
+    #[test]
+    fn out_of_bounds_offset_wraps() {
+        // `AddOffset(-1, 1)` on the first cell, which wraps around to the last one
+        let output = run_with_config("<+>", &RunConfig::default());
+        assert_eq!(output, Ok(Vec::new()));
+    }
+
+    #[test]
+    fn trace_recorded() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",>++<.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+        let entry = |ip: usize, cell| TraceEntry {
+            ip,
+            stmt: lir.stmts()[ip],
+            ptr: 0,
+            cell,
+        };
+        let run = |trace: &mut Trace| {
+            let config = RunConfig::default();
+            super::run_traced(&lir, std::io::sink(), b"a".as_slice(), &config, trace).unwrap();
+        };
+
+        let mut trace = Trace::new(10);
+        run(&mut trace);
+        // `In`, `AddOffset` and `Out`, the `End` doesn't count as executed
+        assert_eq!(trace.entries(), [entry(0, 97), entry(1, 2), entry(2, 97)]);
+        assert!(matches!(trace.entries()[1].stmt, Stmt::AddOffset { .. }));
+        assert!(!trace.is_truncated());
+
+        let mut trace = Trace::new(2);
+        run(&mut trace);
+        assert_eq!(trace.entries(), [entry(0, 97), entry(1, 2)]);
+        assert!(trace.is_truncated());
+    }
+
+    #[test]
+    fn trace_reports_move_target() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",[->+<]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut trace = Trace::new(10);
+        let config = RunConfig::default();
+        super::run_traced(&lir, std::io::sink(), b"a".as_slice(), &config, &mut trace).unwrap();
+
+        let [_, entry] = trace.entries() else {
+            panic!("{:?}", trace.entries());
+        };
+        assert!(matches!(entry.stmt, Stmt::MoveAddTo { offset: 1 }));
+        assert_eq!(entry.cell, 97);
+    }
+
+    #[test]
+    fn run_to_vec_and_string() {
+        let alloc = Bump::new();
+        // reverses the input
+        let ast = crate::parse::parse(&alloc, ">,[>,]<[.<]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        assert_eq!(super::run_to_vec(&lir, b"abc").unwrap(), b"cba");
+        assert_eq!(super::run_to_string(&lir, b"\xFFa").unwrap(), "a\u{FFFD}");
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "instruction pointer 1 out of bounds of the code")]
+    fn missing_end_panics() {
+        let alloc = Bump::new();
+        let mut lir = crate::lir::Lir::new_in(&alloc);
+        lir.push(crate::lir::Stmt::Add(1), crate::parse::Span::default(), 0);
+
+        let _ = super::run(
+            &lir,
+            std::io::sink(),
+            [].as_slice(),
+            &RunConfig::default(),
+            |_| {},
+        );
+    }
+
+    #[test]
+    fn batch_runner_clears_used_cells() {
+        let alloc = Bump::new();
+        let lir = |bf: &str| {
+            let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+            let hir = crate::hir::optimized_hir(&alloc, &ast);
+            crate::lir::generate(&alloc, &hir)
+        };
+        // leaves junk behind in cells 1 to 3, and in 13 and 23 through offsets past the pointer
+        let dirty =
+            lir(">+>++>+++>>>>>>>>>>+<<<<<<<<<<[->>>>>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<<<<<]");
+        let check = lir(&".>".repeat(30));
+
+        let mut runner = super::BatchRunner::new(RunConfig::default());
+        runner.run(&dirty, std::io::sink(), [].as_slice()).unwrap();
+        let mut stdout = Vec::new();
+        runner.run(&check, &mut stdout, [].as_slice()).unwrap();
+
+        assert_eq!(stdout, [0; 30]);
+
+        // `AddOffset(-1, 1)` on the first cell writes the last one without moving the pointer
+        runner
+            .run(&lir("<+>"), std::io::sink(), [].as_slice())
+            .unwrap();
+        let mut stdout = Vec::new();
+        runner.run(&lir("<."), &mut stdout, [].as_slice()).unwrap();
+
+        assert_eq!(stdout, [0]);
+    }
+
+    /// Records the size of every flushed chunk
+    #[derive(Default)]
+    struct ChunkRecorder {
+        pending: usize,
+        chunks: Vec<usize>,
+    }
+
+    impl Write for ChunkRecorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.pending += buf.len();
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            if self.pending > 0 {
+                self.chunks.push(self.pending);
+                self.pending = 0;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn out_buffer_flushes_in_chunks() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+.......".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig {
+            out_buffer: 3,
+            ..RunConfig::default()
+        };
+        let mut stdout = ChunkRecorder::default();
+        super::run(&lir, &mut stdout, [].as_slice(), &config, |_| {}).unwrap();
+
+        assert_eq!(stdout.chunks, [3, 3, 1]);
+    }
+
+    #[test]
+    fn output_flushed_before_input_and_on_output() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+..,..".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = ChunkRecorder::default();
+        super::run(
+            &lir,
+            &mut stdout,
+            b"a".as_slice(),
+            &RunConfig::default(),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(stdout.chunks, [2, 2]);
+
+        let config = RunConfig {
+            flush_on_output: true,
+            ..RunConfig::default()
+        };
+        let mut stdout = ChunkRecorder::default();
+        super::run(&lir, &mut stdout, b"a".as_slice(), &config, |_| {}).unwrap();
+        assert_eq!(stdout.chunks, [1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn cancel_stops_infinite_loop() {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let config = RunConfig {
+            cancel: Some(cancel.clone()),
+            ..RunConfig::default()
+        };
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            cancel.store(true, Ordering::Relaxed);
+        });
+        let result = run_stats("+[]", &config);
+        canceller.join().unwrap();
+
+        assert_eq!(result, Err(RuntimeError::Cancelled));
+    }
+
+    #[test]
+    fn fuel_stops_infinite_loop() {
+        let config = RunConfig {
+            fuel: Some(100_000),
+            ..RunConfig::default()
+        };
+        assert_eq!(run_stats("+[]", &config), Err(RuntimeError::OutOfFuel));
+    }
+
+    #[test]
+    fn exact_fuel_finishes() {
+        let bf = "++[>+++<-]>.";
+        let needed = run_stats(bf, &RunConfig::default())
+            .unwrap()
+            .instructions_executed;
+
+        let config = RunConfig {
+            fuel: Some(needed),
+            ..RunConfig::default()
+        };
+        assert_eq!(
+            run_stats(bf, &config).unwrap().instructions_executed,
+            needed
+        );
+
+        let config = RunConfig {
+            fuel: Some(needed - 1),
+            ..RunConfig::default()
+        };
+        assert_eq!(run_stats(bf, &config), Err(RuntimeError::OutOfFuel));
+    }
+
+    #[test]
+    fn strict_move_mul_changes_nothing_off_tape() {
+        let alloc = Bump::new();
+        // a `MoveMulTo` to the cells 1 and 4, the second one is off the tape
+        let ast = crate::parse::parse(&alloc, "+++[->++>>>+<<<<]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig {
+            tape_mode: TapeMode::Strict,
+            mem_size: 4,
+            ..RunConfig::default()
+        };
+        let mut interpreter = Interpreter::new(&lir, std::io::sink(), [].as_slice(), &config);
+        let result = loop {
+            match interpreter.step() {
+                Ok(StepResult::Continue) => {}
+                result => break result,
+            }
+        };
+
+        assert!(matches!(
+            result,
+            Err(RuntimeError::PointerOutOfBounds { offset: 4, .. })
+        ));
+        let tape = interpreter
+            .mem()
+            .iter()
+            .map(|cell| cell.0)
+            .collect::<Vec<_>>();
+        assert_eq!(tape, [3, 0, 0, 0]);
+    }
+
+    #[test]
+    fn step_by_step() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "++>+.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig::default();
+        let mut stdout = Vec::new();
+        let mut interpreter = Interpreter::new(&lir, &mut stdout, [].as_slice(), &config);
+
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.mem()[0].0, 2);
+        assert_eq!(interpreter.step(), Ok(StepResult::Continue));
+        assert_eq!(interpreter.ptr(), 1);
+        assert_eq!(interpreter.ip(), 2);
+        while interpreter.step() == Ok(StepResult::Continue) {}
+
+        // stepping a finished program must not run off the end of the code
+        assert_eq!(interpreter.step(), Ok(StepResult::Halted));
+        assert_eq!(interpreter.stats().instructions_executed, 4);
+        drop(interpreter);
+        assert_eq!(stdout, [1]);
+    }
+
+    #[test]
+    fn step_after_error() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "<+".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig {
+            tape_mode: TapeMode::Strict,
+            ..RunConfig::default()
+        };
+        let mut interpreter = Interpreter::new(&lir, std::io::sink(), std::io::empty(), &config);
+
+        let err = interpreter.step().unwrap_err();
+        assert_eq!(interpreter.step(), Err(err));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let alloc = Bump::new();
+        // the loop isn't optimized away, the `.` is at byte 4 and the `]` at byte 6
+        let bf = "+++[.-]>+.";
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig::default();
+        let mut interpreter = Interpreter::new(&lir, std::io::sink(), std::io::empty(), &config);
+        let breakpoints = HashSet::from([4, 9]);
+
+        for value in [2, 1, 0] {
+            assert_eq!(
+                interpreter.run_to_breakpoint(&breakpoints),
+                Ok(StepResult::Breakpoint(Span::single(4)))
+            );
+            assert_eq!(interpreter.mem()[0].0, value + 1);
+        }
+        assert_eq!(
+            interpreter.run_to_breakpoint(&breakpoints),
+            Ok(StepResult::Breakpoint(Span::single(9)))
+        );
+        assert_eq!(interpreter.ptr(), 1);
+        assert_eq!(
+            interpreter.run_to_breakpoint(&breakpoints),
+            Ok(StepResult::Halted)
+        );
+
+        let mut interpreter = Interpreter::new(&lir, std::io::sink(), std::io::empty(), &config);
+        let breakpoints = HashSet::from([6]);
+        assert_eq!(
+            interpreter.run_to_breakpoint(&breakpoints),
+            Ok(StepResult::Breakpoint(Span::start_end(3, 7)))
+        );
+        assert_eq!(interpreter.mem()[0].0, 2);
+    }
+
+    #[test]
+    fn breakpoints_without_spans() {
+        let alloc = Bump::new();
+        // `++[-]`, the statements have empty spans, so no breakpoint can be hit
+        let stmts = [
+            Stmt::Add(2),
+            Stmt::JmpIfZero(4),
+            Stmt::Sub(1),
+            Stmt::JmpIfNonZero(2),
+            Stmt::End,
+        ];
+        let lir = crate::lir::Lir::new_checked(&alloc, &stmts).unwrap();
+
+        let config = RunConfig::default();
+        let mut interpreter = Interpreter::new(&lir, std::io::sink(), std::io::empty(), &config);
+        assert_eq!(
+            interpreter.run_to_breakpoint(&HashSet::from([0, 1, 2])),
+            Ok(StepResult::Halted)
+        );
+    }
+
+    #[test]
+    fn compiled_fn_resets_tape() {
+        let alloc = Bump::new();
+        // adds the input byte to the cell and prints it
+        let ast = crate::parse::parse(&alloc, ",[->+<]>+.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let mut run = super::compile_to_fn(&alloc, &hir);
+
+        assert_eq!(run(b"A"), b"B");
+        assert_eq!(run(b"A"), b"B");
+        assert_eq!(run(b"a"), b"b");
     }
 }
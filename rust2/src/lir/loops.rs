@@ -0,0 +1,129 @@
+//! rebuilding the nested loops from the jumps, for the backends that emit structured code
+//!
+//! A loop is a `JmpIfZero` to the statement after its `JmpIfNonZero`, which jumps back to the
+//! statement after the `JmpIfZero`. Jumps that don't pair up like that can't be turned into loops.
+
+use std::mem;
+
+use crate::lir::{Lir, LirError, Stmt};
+
+/// A block of statements with the loops in it nested as their own blocks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopTree {
+    pub nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Node {
+    /// Any statement except for the jumps, which are the loops
+    Stmt(Stmt),
+    /// The statements between a `JmpIfZero` and its `JmpIfNonZero`, which run while the current
+    /// cell isn't zero
+    Loop(LoopTree),
+}
+
+/// Pairs the jumps of the code into loops. The `End` stays at the end of the outermost block.
+pub fn reconstruct_loops(code: &Lir<'_>) -> Result<LoopTree, LirError> {
+    let stmts = code.stmts();
+    // the index of the `JmpIfZero` of every open loop, with the block around it
+    let mut open = Vec::new();
+    let mut block = Vec::new();
+
+    for (idx, &stmt) in stmts.iter().enumerate() {
+        match stmt {
+            Stmt::JmpIfZero(_) => open.push((idx, mem::take(&mut block))),
+            Stmt::JmpIfNonZero(body) => {
+                let Some((start, outer)) = open.pop() else {
+                    return Err(LirError::UnpairedJump { idx });
+                };
+                let paired =
+                    body as usize == start + 1 && stmts[start] == Stmt::JmpIfZero(idx as u32 + 1);
+                if !paired {
+                    return Err(LirError::UnpairedJump { idx: start });
+                }
+                let body = mem::replace(&mut block, outer);
+                block.push(Node::Loop(LoopTree { nodes: body }));
+            }
+            stmt => block.push(Node::Stmt(stmt)),
+        }
+    }
+
+    match open.pop() {
+        Some((start, _)) => Err(LirError::UnpairedJump { idx: start }),
+        None => Ok(LoopTree { nodes: block }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use super::{LoopTree, Node};
+    use crate::lir::{Lir, LirError, Stmt};
+
+    #[test]
+    fn nested_loops() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",[>,[-<+>]<.]+[.[-]]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let tree = super::reconstruct_loops(&lir).unwrap();
+
+        let loops = |block: &LoopTree| {
+            block
+                .nodes
+                .iter()
+                .filter(|node| matches!(node, Node::Loop(_)))
+                .count()
+        };
+        assert_eq!(loops(&tree), 2);
+        assert_eq!(tree.nodes.last(), Some(&Node::Stmt(Stmt::End)));
+        let outer = match tree.nodes.iter().find(|node| matches!(node, Node::Loop(_))) {
+            Some(Node::Loop(outer)) => outer,
+            _ => unreachable!(),
+        };
+        assert_eq!(loops(outer), 0);
+        assert!(outer
+            .nodes
+            .contains(&Node::Stmt(Stmt::MoveAddTo { offset: -1 })));
+    }
+
+    #[test]
+    fn unpaired_jumps() {
+        let alloc = Bump::new();
+        let tree =
+            |stmts: &[Stmt]| super::reconstruct_loops(&Lir::new_checked(&alloc, stmts).unwrap());
+
+        // a loop that is never closed
+        assert_eq!(
+            tree(&[Stmt::JmpIfZero(2), Stmt::Out, Stmt::End]),
+            Err(LirError::UnpairedJump { idx: 0 })
+        );
+        // a jump back without a loop
+        assert_eq!(
+            tree(&[Stmt::Out, Stmt::JmpIfNonZero(0), Stmt::End]),
+            Err(LirError::UnpairedJump { idx: 1 })
+        );
+        // jumps that are in bounds, but go to the wrong statements
+        assert_eq!(
+            tree(&[
+                Stmt::JmpIfZero(4),
+                Stmt::Out,
+                Stmt::JmpIfNonZero(1),
+                Stmt::Out,
+                Stmt::End
+            ]),
+            Err(LirError::UnpairedJump { idx: 0 })
+        );
+        assert_eq!(
+            tree(&[
+                Stmt::JmpIfZero(3),
+                Stmt::Out,
+                Stmt::JmpIfNonZero(0),
+                Stmt::End
+            ]),
+            Err(LirError::UnpairedJump { idx: 0 })
+        );
+    }
+}
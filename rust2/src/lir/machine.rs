@@ -0,0 +1,309 @@
+//! a machine for debuggers that can be stopped in the middle of the program and inspected
+//!
+//! The machine drives an [`Interpreter`] one statement at a time, so the code behaves exactly
+//! like in the interpreter with the same `RunConfig`. Before each statement, it works out which
+//! cells the statement is going to write, to stop at watched cells.
+
+use std::{
+    io::{Read, Write},
+    num::Wrapping,
+};
+
+use crate::lir::{
+    interpreter::{Interpreter, RunConfig, RuntimeError, StepResult},
+    Lir, Stmt,
+};
+
+/// Stopped because a watched cell was written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watch {
+    pub cell: usize,
+    /// the value of the cell after the write
+    pub value: u8,
+    /// the statement that wrote the cell
+    pub ip: usize,
+}
+
+/// Whether the program is done after [`Machine::run_for`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStatus {
+    Running,
+    Finished,
+}
+
+pub struct Machine<'lir, W, R> {
+    code: &'lir Lir<'lir>,
+    interpreter: Interpreter<'lir, W, R>,
+    watchpoints: Vec<usize>,
+    /// the cells written by the current statement, reused between statements
+    written: Vec<usize>,
+}
+
+impl<'lir, W: Write, R: Read> Machine<'lir, W, R> {
+    /// The output is buffered like `config` says, set `RunConfig::flush_on_output` to see it
+    /// after every `.`
+    pub fn new(code: &'lir Lir<'lir>, stdout: W, stdin: R, config: &'lir RunConfig) -> Self {
+        Self {
+            code,
+            interpreter: Interpreter::new(code, stdout, stdin, config),
+            watchpoints: Vec::new(),
+            written: Vec::new(),
+        }
+    }
+
+    /// The statement that will be executed next
+    pub fn ip(&self) -> usize {
+        self.interpreter.ip()
+    }
+
+    pub fn ptr(&self) -> usize {
+        self.interpreter.ptr()
+    }
+
+    pub fn tape(&self) -> &[Wrapping<u8>] {
+        self.interpreter.mem()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(self.code.stmts()[self.ip()], Stmt::End)
+    }
+
+    /// Makes [`Machine::run_until_watch`] stop after every write to the cell at `cell`
+    pub fn watch(&mut self, cell: usize) {
+        if !self.watchpoints.contains(&cell) {
+            self.watchpoints.push(cell);
+        }
+    }
+
+    pub fn unwatch(&mut self, cell: usize) {
+        self.watchpoints.retain(|&watched| watched != cell);
+    }
+
+    /// Runs until a watched cell is written, or returns `None` once the program has finished
+    pub fn run_until_watch(&mut self) -> Result<Option<Watch>, RuntimeError> {
+        loop {
+            let ip = self.ip();
+            self.collect_written();
+            if let StepResult::Halted = self.interpreter.step()? {
+                return Ok(None);
+            }
+
+            let hit = self
+                .written
+                .iter()
+                .find(|cell| self.watchpoints.contains(cell));
+            if let Some(&cell) = hit {
+                return Ok(Some(Watch {
+                    cell,
+                    value: self.tape()[cell].0,
+                    ip,
+                }));
+            }
+        }
+    }
+
+    /// Executes at most `max_steps` statements, so that a caller like an event loop can do other
+    /// work in between. Watchpoints are ignored.
+    pub fn run_for(&mut self, max_steps: u64) -> Result<RunStatus, RuntimeError> {
+        for _ in 0..max_steps {
+            if let StepResult::Halted = self.interpreter.step()? {
+                return Ok(RunStatus::Finished);
+            }
+        }
+        Ok(RunStatus::Running)
+    }
+
+    /// Fills `written` with the cells the next statement writes
+    fn collect_written(&mut self) {
+        self.written.clear();
+        if self.watchpoints.is_empty() {
+            return;
+        }
+
+        let code = self.code;
+        let ptr = self.ptr();
+        // the moves don't write anything on a zero cell, like the loop they replace
+        let moves = self.tape()[ptr].0 != 0;
+        match code.stmts()[self.ip()] {
+            Stmt::Add(_) | Stmt::Sub(_) | Stmt::SetN(_) | Stmt::In => self.written.push(ptr),
+            Stmt::AddOffset { offset, .. }
+            | Stmt::SubOffset { offset, .. }
+            | Stmt::SetNOffset { offset, .. } => self.push_written(offset),
+            Stmt::MoveAddTo { offset } | Stmt::MoveSubTo { offset } if moves => {
+                self.written.push(ptr);
+                self.push_written(offset);
+            }
+            Stmt::MoveMulTo(idx) if moves => {
+                self.written.push(ptr);
+                for &(offset, _) in code.mul_targets(idx) {
+                    self.push_written(offset);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Cells at an offset that is an error are never written, the statement fails instead
+    fn push_written(&mut self, offset: i32) {
+        self.written
+            .extend(self.interpreter.offset_idx(offset).ok());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{ErrorKind, Write},
+        num::Wrapping,
+    };
+
+    use bumpalo::Bump;
+
+    use super::{Machine, RunStatus, Watch};
+    use crate::lir::interpreter::{EofBehavior, RunConfig, RuntimeError, TapeMode};
+
+    #[test]
+    fn stops_at_watched_write() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "++>+<[->>+<<]>>.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        // Add(2) | AddOffset(1, 1) | MoveAddTo(2) | Right(2) | Out | End
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig::default();
+        let mut machine = Machine::new(&lir, std::io::sink(), [].as_slice(), &config);
+        machine.watch(2);
+        machine.watch(1);
+
+        let watch = Watch {
+            cell: 1,
+            value: 1,
+            ip: 1,
+        };
+        assert_eq!(machine.run_until_watch().unwrap(), Some(watch));
+        let watch = Watch {
+            cell: 2,
+            value: 2,
+            ip: 2,
+        };
+        assert_eq!(machine.run_until_watch().unwrap(), Some(watch));
+        assert_eq!(machine.tape()[..3], [Wrapping(0), Wrapping(1), Wrapping(2)]);
+
+        assert_eq!(machine.run_until_watch().unwrap(), None);
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn move_of_zero_cell_writes_nothing() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",[->+<]".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        // In | MoveAddTo(1) | End
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig::default();
+        let mut machine = Machine::new(&lir, std::io::sink(), [].as_slice(), &config);
+        machine.watch(1);
+
+        assert_eq!(machine.run_until_watch().unwrap(), None);
+        assert!(machine.is_finished());
+    }
+
+    #[test]
+    fn write_error() {
+        struct Broken;
+
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::Error::new(ErrorKind::BrokenPipe, "broken"))
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, ",.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig::default();
+        let mut machine = Machine::new(&lir, Broken, b"a".as_slice(), &config);
+        assert_eq!(
+            machine.run_for(100),
+            Err(RuntimeError::Io {
+                kind: ErrorKind::BrokenPipe,
+                message: "broken".to_owned()
+            })
+        );
+    }
+
+    #[test]
+    fn same_errors_as_interpreter() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+<".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let config = RunConfig {
+            tape_mode: TapeMode::Strict,
+            ..RunConfig::default()
+        };
+        let expected =
+            crate::lir::interpreter::run(&lir, std::io::sink(), [].as_slice(), &config, |_| {});
+        let mut machine = Machine::new(&lir, std::io::sink(), [].as_slice(), &config);
+
+        assert!(matches!(
+            expected,
+            Err(RuntimeError::PointerOutOfBounds { .. })
+        ));
+        assert_eq!(machine.run_for(100).map(|_| ()), expected.map(|_| ()));
+    }
+
+    #[test]
+    fn run_in_slices() {
+        let bf = include_str!("../../benches/fizzbuzz.bf");
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let expected = crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap();
+
+        let mut stdout = Vec::new();
+        let config = RunConfig::default();
+        let mut machine = Machine::new(&lir, &mut stdout, [].as_slice(), &config);
+        let mut slices = 1;
+        while machine.run_for(100).unwrap() == RunStatus::Running {
+            slices += 1;
+        }
+
+        assert!(slices > 1);
+        assert_eq!(stdout, expected);
+    }
+
+    #[test]
+    fn eof_behavior() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+++,.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let output = |eof_behavior| {
+            let mut stdout = Vec::new();
+            let config = RunConfig {
+                eof_behavior,
+                ..RunConfig::default()
+            };
+            let mut machine = Machine::new(&lir, &mut stdout, [].as_slice(), &config);
+            while machine.run_for(100).unwrap() == RunStatus::Running {}
+            drop(machine);
+            stdout
+        };
+
+        assert_eq!(output(EofBehavior::Unchanged), [3]);
+        assert_eq!(output(EofBehavior::Zero), [0]);
+        assert_eq!(output(EofBehavior::NegativeOne), [255]);
+    }
+}
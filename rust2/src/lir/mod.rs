@@ -15,32 +15,85 @@
 //! a needless indirection.
 //!
 //! this module must not produce out of bounds jumps and always put the `End` instruction at the
-//! end
+//! end. LIR from anywhere else goes through [`Lir::new_checked`], which checks that.
 
+#[cfg(all(feature = "asm-jit", target_arch = "x86_64", unix))]
+pub mod asm_jit;
+pub mod cell;
+pub mod dump;
+pub mod input;
 pub mod interpreter;
+mod loops;
+pub mod machine;
+pub mod output;
+pub mod profile;
+mod serialize;
+#[cfg(feature = "threaded-dispatch")]
+pub mod threaded;
+mod transpile;
+#[cfg(feature = "wasm")]
+pub mod wasm;
 
-use std::fmt::{Debug, Formatter};
+use std::{
+    fmt::{Debug, Display, Formatter},
+    io,
+};
 
 use bumpalo::Bump;
 
+pub use self::{
+    loops::{reconstruct_loops, LoopTree, Node},
+    serialize::{deserialize, serialize},
+    transpile::{transpile_c, transpile_rust},
+};
 use crate::{
     hir::{Hir, Stmt as HirStmt, StmtKind as HirStmtKind},
     parse::Span,
     BumpVec,
 };
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Stmt {
     Add(u8),
     Sub(u8),
-    AddOffset { offset: i32, n: u8 },
-    SubOffset { offset: i32, n: u8 },
-    MoveAddTo { offset: i32 },
+    AddOffset {
+        offset: i32,
+        n: u8,
+    },
+    SubOffset {
+        offset: i32,
+        n: u8,
+    },
+    MoveAddTo {
+        offset: i32,
+    },
+    MoveSubTo {
+        offset: i32,
+    },
+    /// Multiplies the current cell into the targets, the index into [`Lir::mul_targets`]
+    MoveMulTo(u32),
     Right(u32),
     Left(u32),
+    ScanRight(u32),
+    ScanLeft(u32),
     Out,
+    /// Writes the byte that `Out` writes for a cell containing `n`, without reading the tape
+    OutConst(u8),
+    /// Writes the byte of `OutConst(byte)` `count` times
+    OutRepeat {
+        byte: u8,
+        count: u32,
+    },
     In,
     SetN(u8),
+    SetNOffset {
+        offset: i32,
+        n: u8,
+    },
+    /// Writes a constant string, the index into [`Lir::out_bytes`]
+    OutBytes(u32),
+    /// Writes the cells around the pointer to stdout, see [`dump::debug_dump`]
+    Debug,
     JmpIfZero(u32),
     JmpIfNonZero(u32),
     End,
@@ -52,6 +105,13 @@ const _: [(); 8] = [(); std::mem::size_of::<Stmt>()];
 pub struct Lir<'lir> {
     stmts: BumpVec<'lir, Stmt>,
     debug: BumpVec<'lir, Span>,
+    /// the amount of loops around every statement. The jump back of a loop is executed on every
+    /// iteration, so it is counted as part of the loop body, unlike the jump into the loop
+    depths: BumpVec<'lir, u32>,
+    /// the strings written by `OutBytes`
+    out_bytes: BumpVec<'lir, &'lir [u8]>,
+    /// the `(offset, factor)` targets of `MoveMulTo`
+    mul_targets: BumpVec<'lir, &'lir [(i32, u8)]>,
 }
 
 impl Debug for Lir<'_> {
@@ -60,7 +120,146 @@ impl Debug for Lir<'_> {
     }
 }
 
-impl Lir<'_> {
+/// Disassembles the code, one statement per line, prefixed by its index
+///
+/// ```text
+/// 0000: Add(2)
+/// 0001: JmpIfZero -> 0004
+/// 0002: Sub(1)
+/// 0003: JmpIfNonZero -> 0002
+/// 0004: End
+/// ```
+impl Display for Lir<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (idx, stmt) in self.stmts.iter().enumerate() {
+            match stmt {
+                Stmt::JmpIfZero(target) => writeln!(f, "{idx:04}: JmpIfZero -> {target:04}")?,
+                Stmt::JmpIfNonZero(target) => writeln!(f, "{idx:04}: JmpIfNonZero -> {target:04}")?,
+                Stmt::OutBytes(bytes) => {
+                    let bytes = self.out_bytes(*bytes).escape_ascii();
+                    writeln!(f, "{idx:04}: OutBytes(\"{bytes}\")")?
+                }
+                Stmt::MoveMulTo(targets) => {
+                    let targets = self.mul_targets(*targets);
+                    writeln!(f, "{idx:04}: MoveMulTo({targets:?})")?
+                }
+                stmt => writeln!(f, "{idx:04}: {stmt:?}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Why [`Lir::new_checked`] or [`deserialize`] rejected the LIR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LirError {
+    /// The jump at `idx` goes to `target`, which isn't a statement
+    JumpOutOfBounds { idx: usize, target: u32 },
+    /// The last statement isn't an `End`, or there are no statements at all
+    MissingEnd,
+    /// The jump at `idx` doesn't form a loop with another jump, see [`reconstruct_loops`]
+    UnpairedJump { idx: usize },
+    /// The `OutBytes` or `MoveMulTo` at `idx` refers to data that doesn't exist
+    MissingData { idx: usize },
+    /// Reading the serialized LIR failed, including when it ends too early
+    Io {
+        kind: io::ErrorKind,
+        message: String,
+    },
+    /// The serialized LIR is not in the format written by [`serialize`]
+    Invalid(&'static str),
+}
+
+impl Display for LirError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::JumpOutOfBounds { idx, target } => {
+                write!(
+                    f,
+                    "The jump at {idx} goes to {target}, which is out of bounds"
+                )
+            }
+            Self::MissingEnd => f.write_str("The code doesn't end with `End`"),
+            Self::UnpairedJump { idx } => {
+                write!(f, "The jump at {idx} is not part of a loop")
+            }
+            Self::MissingData { idx } => {
+                write!(
+                    f,
+                    "The statement at {idx} refers to data that doesn't exist"
+                )
+            }
+            Self::Io { message, .. } => write!(f, "Failed to read LIR: {message}"),
+            Self::Invalid(reason) => write!(f, "Invalid LIR: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for LirError {
+    fn from(err: io::Error) -> Self {
+        Self::Io {
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+}
+
+impl<'lir> Lir<'lir> {
+    /// Creates LIR from statements that weren't generated by this module, checking that it can
+    /// be run safely: every jump stays in bounds and the code ends with `End`. `OutBytes` and
+    /// `MoveMulTo` can't be used, because there is no data for them.
+    ///
+    /// The statements have no spans, and their loop depths are taken from the jumps.
+    pub fn new_checked(alloc: &'lir Bump, stmts: &[Stmt]) -> Result<Self, LirError> {
+        let mut lir = Self::new_in(alloc);
+        let mut depth = 0u32;
+        for &stmt in stmts {
+            // the jump back is still part of the loop body, see `Lir::depths`
+            lir.push(stmt, Span::default(), depth);
+            match stmt {
+                Stmt::JmpIfZero(_) => depth += 1,
+                Stmt::JmpIfNonZero(_) => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        lir.check()?;
+        Ok(lir)
+    }
+
+    /// Checks the guarantees of this module for LIR that was built from the outside
+    pub(crate) fn check(&self) -> Result<(), LirError> {
+        if self.stmts.last() != Some(&Stmt::End) {
+            return Err(LirError::MissingEnd);
+        }
+        for (idx, &stmt) in self.stmts.iter().enumerate() {
+            match stmt {
+                Stmt::JmpIfZero(target) | Stmt::JmpIfNonZero(target)
+                    if target as usize >= self.stmts.len() =>
+                {
+                    return Err(LirError::JumpOutOfBounds { idx, target });
+                }
+                Stmt::OutBytes(data) if data as usize >= self.out_bytes.len() => {
+                    return Err(LirError::MissingData { idx });
+                }
+                Stmt::MoveMulTo(data) if data as usize >= self.mul_targets.len() => {
+                    return Err(LirError::MissingData { idx });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn new_in(alloc: &'lir Bump) -> Self {
+        Self {
+            stmts: Vec::new_in(alloc),
+            debug: Vec::new_in(alloc),
+            depths: Vec::new_in(alloc),
+            out_bytes: Vec::new_in(alloc),
+            mul_targets: Vec::new_in(alloc),
+        }
+    }
+
     pub fn stmts(&self) -> &[Stmt] {
         &self.stmts
     }
@@ -68,30 +267,105 @@ impl Lir<'_> {
     pub fn debug(&self) -> &[Span] {
         &self.debug
     }
+
+    /// The loop nesting depth of every statement
+    pub fn depths(&self) -> &[u32] {
+        &self.depths
+    }
+
+    /// The string written by `OutBytes(idx)`
+    pub fn out_bytes(&self, idx: u32) -> &'lir [u8] {
+        self.out_bytes[idx as usize]
+    }
+
+    /// The `(offset, factor)` targets of `MoveMulTo(idx)`
+    pub fn mul_targets(&self, idx: u32) -> &'lir [(i32, u8)] {
+        self.mul_targets[idx as usize]
+    }
+
+    /// The furthest any statement reaches left or right of the pointer, as `(left, right)`
+    pub fn max_offsets(&self) -> (usize, usize) {
+        let (mut left, mut right) = (0, 0);
+        let mut visit = |offset: i32| {
+            if offset < 0 {
+                left = left.max(offset.unsigned_abs() as usize);
+            } else {
+                right = right.max(offset as usize);
+            }
+        };
+        for stmt in self.stmts.iter() {
+            match *stmt {
+                Stmt::AddOffset { offset, .. }
+                | Stmt::SubOffset { offset, .. }
+                | Stmt::SetNOffset { offset, .. }
+                | Stmt::MoveAddTo { offset }
+                | Stmt::MoveSubTo { offset } => visit(offset),
+                Stmt::MoveMulTo(idx) => {
+                    for &(offset, _) in self.mul_targets(idx) {
+                        visit(offset);
+                    }
+                }
+                _ => {}
+            }
+        }
+        (left, right)
+    }
+
+    /// Pushes a `MoveMulTo` to `targets`, like [`Lir::push`]
+    pub(crate) fn push_move_mul_to(
+        &mut self,
+        targets: &[(i32, u8)],
+        span: Span,
+        depth: u32,
+    ) -> usize {
+        let alloc: &'lir Bump = self.mul_targets.allocator();
+        self.mul_targets.push(alloc.alloc_slice_copy(targets));
+        let idx = u32::try_from(self.mul_targets.len() - 1).unwrap();
+        self.push(Stmt::MoveMulTo(idx), span, depth)
+    }
+
+    /// Pushes an `OutBytes` writing `bytes`, like [`Lir::push`]
+    pub(crate) fn push_out_bytes(&mut self, bytes: &[u8], span: Span, depth: u32) -> usize {
+        let alloc: &'lir Bump = self.out_bytes.allocator();
+        self.out_bytes.push(alloc.alloc_slice_copy(bytes));
+        let idx = u32::try_from(self.out_bytes.len() - 1).unwrap();
+        self.push(Stmt::OutBytes(idx), span, depth)
+    }
+
+    /// Pushes a new statement nested in `depth` loops and returns its index
+    pub(crate) fn push(&mut self, stmt: Stmt, span: Span, depth: u32) -> usize {
+        self.stmts.push(stmt);
+        self.debug.push(span);
+        self.depths.push(depth);
+        self.stmts.len() - 1
+    }
+
+    /// Replaces the statement at `idx`, used to fix up placeholder jumps
+    pub(crate) fn set(&mut self, idx: usize, stmt: Stmt) {
+        self.stmts[idx] = stmt;
+    }
 }
 
 pub fn generate<'lir>(alloc: &'lir Bump, ir: &Hir<'_>) -> Lir<'lir> {
-    let stmts = Vec::new_in(alloc);
-    let debug = Vec::new_in(alloc);
-    let mut lir = Lir { stmts, debug };
+    let mut lir = Lir::new_in(alloc);
 
-    hir_to_lir(&mut lir, &ir.stmts);
-    lir.stmts.push(Stmt::End);
-    lir.debug.push(Span::default());
+    hir_to_lir(&mut lir, &ir.stmts, 0);
+    lir.push(Stmt::End, Span::default(), 0);
 
     assert_eq!(lir.stmts.len(), lir.debug.len());
+    assert_eq!(lir.stmts.len(), lir.depths.len());
 
     lir
 }
 
-fn hir_to_lir<'lir>(lir: &mut Lir<'lir>, ir: &[HirStmt<'_>]) {
+fn hir_to_lir<'lir>(lir: &mut Lir<'lir>, ir: &[HirStmt<'_>], depth: u32) {
     for ir_stmt in ir {
-        hir_stmt_to_lir_stmt(lir, ir_stmt);
+        hir_stmt_to_lir_stmt(lir, ir_stmt, depth);
     }
     debug_assert_eq!(lir.stmts.len(), lir.debug.len());
 }
 
-fn hir_stmt_to_lir_stmt<'lir>(lir: &mut Lir<'lir>, ir_stmt: &HirStmt<'_>) {
+fn hir_stmt_to_lir_stmt<'lir>(lir: &mut Lir<'lir>, ir_stmt: &HirStmt<'_>, depth: u32) {
     let stmt = match &ir_stmt.kind {
         HirStmtKind::Add(0, n) => Stmt::Add(*n),
         HirStmtKind::Sub(0, n) => Stmt::Sub(*n),
@@ -104,34 +378,152 @@ fn hir_stmt_to_lir_stmt<'lir>(lir: &mut Lir<'lir>, ir_stmt: &HirStmt<'_>) {
             n: *n,
         },
         HirStmtKind::MoveAddTo { offset } => Stmt::MoveAddTo { offset: *offset },
+        HirStmtKind::MoveSubTo { offset } => Stmt::MoveSubTo { offset: *offset },
+        HirStmtKind::MoveMulTo { targets } => {
+            lir.push_move_mul_to(targets, ir_stmt.span, depth);
+            return;
+        }
         HirStmtKind::Right(n) => Stmt::Right(u32::try_from(*n).unwrap()),
         HirStmtKind::Left(n) => Stmt::Left(u32::try_from(*n).unwrap()),
+        HirStmtKind::ScanRight(n) => Stmt::ScanRight(u32::try_from(*n).unwrap()),
+        HirStmtKind::ScanLeft(n) => Stmt::ScanLeft(u32::try_from(*n).unwrap()),
         HirStmtKind::Out => Stmt::Out,
+        HirStmtKind::OutConst(n) => Stmt::OutConst(*n),
+        HirStmtKind::OutRepeat { byte, count } => Stmt::OutRepeat {
+            byte: *byte,
+            count: u32::try_from(*count).unwrap(),
+        },
         HirStmtKind::In => Stmt::In,
         HirStmtKind::SetN(n) => Stmt::SetN(*n),
+        HirStmtKind::SetNOffset { offset, n } => Stmt::SetNOffset {
+            offset: *offset,
+            n: *n,
+        },
+        HirStmtKind::Debug => Stmt::Debug,
+        // removed by the optimizer already, but there is nothing to generate for them anyways
+        HirStmtKind::Nop => return,
         HirStmtKind::Loop(instr) => {
-            let skip_jmp_idx = lir.stmts.len();
-            lir.stmts.push(Stmt::JmpIfZero(0)); // placeholder
-            lir.debug.push(ir_stmt.span);
+            let skip_jmp_idx = lir.push(Stmt::JmpIfZero(0), ir_stmt.span, depth); // placeholder
 
             // compile the loop body now
-            hir_to_lir(lir, &instr.stmts);
+            hir_to_lir(lir, &instr.stmts, depth + 1);
             // if the loop body is empty, we jmp to ourselves, which is an infinite loop - as expected
             let first_loop_body_idx = skip_jmp_idx + 1;
-            lir.stmts
-                .push(Stmt::JmpIfNonZero(first_loop_body_idx.try_into().unwrap()));
-            lir.debug.push(ir_stmt.span);
+            let jmp_back_idx = lir.push(
+                Stmt::JmpIfNonZero(first_loop_body_idx.try_into().unwrap()),
+                ir_stmt.span,
+                depth + 1,
+            );
 
             // there will always at least be an `End` instruction after the loop
-            let after_loop_idx = lir.stmts.len();
+            let after_loop_idx = jmp_back_idx + 1;
 
             // fix the placeholder with the actual index
-            lir.stmts[skip_jmp_idx] = Stmt::JmpIfZero(after_loop_idx.try_into().unwrap());
+            lir.set(
+                skip_jmp_idx,
+                Stmt::JmpIfZero(after_loop_idx.try_into().unwrap()),
+            );
 
             return;
         }
     };
 
-    lir.stmts.push(stmt);
-    lir.debug.push(ir_stmt.span);
+    lir.push(stmt, ir_stmt.span, depth);
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    fn disassemble(bf: &str) -> String {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = super::generate(&alloc, &hir);
+        lir.to_string()
+    }
+
+    #[test]
+    fn add_sub_offset_signs() {
+        use super::Stmt;
+
+        let alloc = Bump::new();
+        for (code, back, expected_stmt, expected_cell) in [
+            (">>+++<<", ">>", Stmt::AddOffset { offset: 2, n: 3 }, 3),
+            ("<<+++>>", "<<", Stmt::AddOffset { offset: -2, n: 3 }, 3),
+            (">>---<<", ">>", Stmt::SubOffset { offset: 2, n: 3 }, 253),
+            ("<<--->>", "<<", Stmt::SubOffset { offset: -2, n: 3 }, 253),
+        ] {
+            // the `,`s keep the moves to the changed cell from being merged with the others
+            let bf = format!(">>,{code},{back}.");
+            let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+            let hir = crate::hir::optimized_hir(&alloc, &ast);
+            let lir = super::generate(&alloc, &hir);
+
+            assert_eq!(lir.stmts()[2], expected_stmt, "{code}");
+            let out = crate::lir::interpreter::run_to_vec(&lir, b"xx").unwrap();
+            assert_eq!(out, [expected_cell], "{code}");
+        }
+    }
+
+    #[test]
+    fn new_checked() {
+        use super::{Lir, LirError, Stmt};
+
+        let alloc = Bump::new();
+        // `,[-.]`
+        let stmts = [
+            Stmt::In,
+            Stmt::JmpIfZero(5),
+            Stmt::Sub(1),
+            Stmt::Out,
+            Stmt::JmpIfNonZero(2),
+            Stmt::End,
+        ];
+        let lir = Lir::new_checked(&alloc, &stmts).unwrap();
+        assert_eq!(lir.depths(), [0, 0, 1, 1, 1, 0]);
+        let out = crate::lir::interpreter::run_to_vec(&lir, &[3]).unwrap();
+        assert_eq!(out, [2, 1, 0]);
+
+        let mut out_of_bounds = stmts;
+        out_of_bounds[1] = Stmt::JmpIfZero(6);
+        assert_eq!(
+            Lir::new_checked(&alloc, &out_of_bounds).unwrap_err(),
+            LirError::JumpOutOfBounds { idx: 1, target: 6 }
+        );
+        assert_eq!(
+            Lir::new_checked(&alloc, &stmts[..5]).unwrap_err(),
+            LirError::MissingEnd
+        );
+        assert_eq!(
+            Lir::new_checked(&alloc, &[]).unwrap_err(),
+            LirError::MissingEnd
+        );
+        assert_eq!(
+            Lir::new_checked(&alloc, &[Stmt::OutBytes(0), Stmt::End]).unwrap_err(),
+            LirError::MissingData { idx: 0 }
+        );
+    }
+
+    // `hanoi.bf` and `mandelbrot.bf` are left out, their snapshots would be too big to review
+
+    #[test]
+    fn bench() {
+        insta::assert_snapshot!(disassemble(include_str!("../../benches/bench.bf")));
+    }
+
+    #[test]
+    fn bottles() {
+        insta::assert_snapshot!(disassemble(include_str!("../../benches/bottles.bf")));
+    }
+
+    #[test]
+    fn fizzbuzz() {
+        insta::assert_snapshot!(disassemble(include_str!("../../benches/fizzbuzz.bf")));
+    }
+
+    #[test]
+    fn twinkle() {
+        insta::assert_snapshot!(disassemble(include_str!("../../benches/twinkle.bf")));
+    }
 }
@@ -0,0 +1,158 @@
+//! how the value of a cell is turned into bytes on stdout by `.`
+
+use std::io::{self, Write};
+
+/// Turns the value of the cell under the pointer into output, every time a `.` is executed
+pub trait OutputEncoder {
+    fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()>;
+}
+
+/// Writes the lowest byte of the cell as is, so that programs can write binary data. This is what
+/// the interpreter does by default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawByte;
+
+impl OutputEncoder for RawByte {
+    #[inline]
+    fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()> {
+        out.write_all(&[cell as u8])
+    }
+}
+
+/// Writes the value of the cell as a decimal number, followed by a space
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Decimal;
+
+impl OutputEncoder for Decimal {
+    fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "{cell} ")
+    }
+}
+
+/// Like [`Decimal`], but reads the lowest byte of the cell as a two's complement number, so
+/// 255 is written as `-1`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Signed;
+
+impl OutputEncoder for Signed {
+    fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "{} ", cell as u8 as i8)
+    }
+}
+
+/// Writes the cell as the UTF-8 encoding of the code point with its value. Invalid code points
+/// are written as `U+FFFD`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Utf8;
+
+impl OutputEncoder for Utf8 {
+    #[inline]
+    fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()> {
+        let char = u32::try_from(cell)
+            .ok()
+            .and_then(char::from_u32)
+            .unwrap_or(char::REPLACEMENT_CHARACTER);
+        let mut buf = [0; 4];
+        out.write_all(char.encode_utf8(&mut buf).as_bytes())
+    }
+}
+
+/// Writes printable ASCII as is and escapes everything else, like `\n` or `\x1b`. Useful for
+/// looking at the output of programs that write control characters.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Escaped;
+
+impl OutputEncoder for Escaped {
+    fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()> {
+        let escaped = std::ascii::escape_default(cell as u8).collect::<Vec<_>>();
+        out.write_all(&escaped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+
+    use super::{Decimal, Escaped, OutputEncoder, RawByte, Signed, Utf8};
+
+    fn encode_all(mut encoder: impl OutputEncoder, cells: &[u64]) -> Vec<u8> {
+        let mut out = Vec::new();
+        for &cell in cells {
+            encoder.encode(cell, &mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn builtin_encoders() {
+        let cells = [72, 10, 255];
+        assert_eq!(encode_all(RawByte, &cells), [72, 10, 255]);
+        assert_eq!(encode_all(Decimal, &cells), b"72 10 255 ");
+        assert_eq!(encode_all(Signed, &cells), b"72 10 -1 ");
+        assert_eq!(encode_all(Utf8, &cells), "H\nÿ".as_bytes());
+        assert_eq!(encode_all(Escaped, &cells), br"H\n\xff");
+    }
+
+    struct Hex;
+
+    impl OutputEncoder for Hex {
+        fn encode(&mut self, cell: u64, out: &mut impl Write) -> io::Result<()> {
+            write!(out, "{cell:02x}")
+        }
+    }
+
+    #[test]
+    fn custom_hex_encoder() {
+        let alloc = bumpalo::Bump::new();
+        let bf = format!("{}.", "+".repeat(0x48));
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = Vec::new();
+        let config = crate::lir::interpreter::RunConfig::default();
+        crate::lir::interpreter::run_with_encoder(
+            &lir,
+            &mut stdout,
+            [].as_slice(),
+            &config,
+            Hex,
+            |_| {},
+        )
+        .unwrap();
+
+        assert_eq!(stdout, b"48");
+    }
+
+    #[test]
+    fn decimal_wide_cells() {
+        use crate::lir::{
+            cell::{ArithPolicy, CellWidth},
+            interpreter::RunConfig,
+        };
+
+        let alloc = bumpalo::Bump::new();
+        let bf = format!("{}.+.", "+".repeat(256));
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir =
+            crate::hir::optimized_hir_for_cells(&alloc, &ast, CellWidth::U16, ArithPolicy::Wrap);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = Vec::new();
+        let config = RunConfig {
+            cell_width: CellWidth::U16,
+            ..RunConfig::default()
+        };
+        crate::lir::interpreter::run_with_encoder(
+            &lir,
+            &mut stdout,
+            [].as_slice(),
+            &config,
+            Decimal,
+            |_| {},
+        )
+        .unwrap();
+
+        assert_eq!(stdout, b"256 257 ");
+    }
+}
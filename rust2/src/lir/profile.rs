@@ -0,0 +1,324 @@
+//! profiles that are collected while running the interpreter
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{Read, Write},
+};
+
+use crate::{
+    lir::{
+        interpreter::{self, Access, ProfileEvent, RunConfig, RuntimeError},
+        Lir, Stmt,
+    },
+    parse::Span,
+};
+
+/// How many statements were executed at each loop nesting depth
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DepthProfile {
+    /// indexed by the depth
+    counts: Vec<u64>,
+}
+
+impl DepthProfile {
+    /// The amount of executed statements at every depth, starting with the top level
+    pub fn counts(&self) -> &[u64] {
+        &self.counts
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The fraction of all executed statements that were executed at `depth`
+    pub fn fraction(&self, depth: usize) -> f64 {
+        let total = self.total();
+        if total == 0 {
+            return 0.0;
+        }
+        self.counts.get(depth).copied().unwrap_or(0) as f64 / total as f64
+    }
+
+    fn record(&mut self, depth: u32) {
+        let depth = depth as usize;
+        if depth >= self.counts.len() {
+            self.counts.resize(depth + 1, 0);
+        }
+        self.counts[depth] += 1;
+    }
+}
+
+/// Runs the code and counts how many statements were executed at each loop nesting depth
+pub fn run_depth_profiled<W, R>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+) -> Result<DepthProfile, RuntimeError>
+where
+    W: Write,
+    R: Read,
+{
+    let depths = code.depths();
+    let mut profile = DepthProfile::default();
+
+    // the collector gets the ip of the next statement, so the first one would be missed
+    profile.record(depths[0]);
+    interpreter::run(code, stdout, stdin, config, |ip| profile.record(depths[ip]))?;
+
+    Ok(profile)
+}
+
+/// Which loops were entered at least once during a run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LoopCoverage {
+    /// the span of every loop in the code, and whether it was entered
+    loops: Vec<(Span, bool)>,
+}
+
+impl LoopCoverage {
+    pub fn loops(&self) -> &[(Span, bool)] {
+        &self.loops
+    }
+
+    /// The loops whose body was executed at least once
+    pub fn covered(&self) -> impl Iterator<Item = Span> + '_ {
+        self.loops
+            .iter()
+            .filter(|(_, entered)| *entered)
+            .map(|(span, _)| *span)
+    }
+
+    /// The loops that were always skipped
+    pub fn uncovered(&self) -> impl Iterator<Item = Span> + '_ {
+        self.loops
+            .iter()
+            .filter(|(_, entered)| !*entered)
+            .map(|(span, _)| *span)
+    }
+}
+
+/// Runs the code and records which loops were entered
+pub fn run_loop_coverage<W, R>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+) -> Result<LoopCoverage, RuntimeError>
+where
+    W: Write,
+    R: Read,
+{
+    let mut executed = vec![false; code.stmts().len()];
+    interpreter::run(code, stdout, stdin, config, |ip| executed[ip] = true)?;
+
+    // the first statement of the body can only be reached by entering the loop, this also holds
+    // for empty loops, where it's the jump back
+    let loops = code
+        .stmts()
+        .iter()
+        .zip(code.debug())
+        .enumerate()
+        .filter(|(_, (stmt, _))| matches!(stmt, Stmt::JmpIfZero(_)))
+        .map(|(idx, (_, span))| (*span, executed[idx + 1]))
+        .collect();
+
+    Ok(LoopCoverage { loops })
+}
+
+/// Runs the code and returns a profile in the folded stack format that `inferno` and
+/// `flamegraph.pl` take as their input.
+///
+/// The loops around a statement are its stack, from the outermost `main` to the innermost loop
+/// called `loop@start..end` after its span. Every executed statement is a sample.
+///
+/// ```text
+/// main 3
+/// main;loop@2..16 12
+/// main;loop@2..16;loop@6..13 24
+/// ```
+pub fn run_folded_stacks<W, R>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+) -> Result<String, RuntimeError>
+where
+    W: Write,
+    R: Read,
+{
+    let mut counts = vec![0u64; code.stmts().len()];
+    // the collector gets the ip of the next statement, so the first one would be missed
+    counts[0] += 1;
+    interpreter::run(code, stdout, stdin, config, |ip| counts[ip] += 1)?;
+
+    let mut samples = BTreeMap::new();
+    let mut stack = String::from("main");
+    // the length of `stack` before every open loop was pushed
+    let mut frame_starts = Vec::new();
+
+    for ((stmt, span), count) in code.stmts().iter().zip(code.debug()).zip(&counts) {
+        // like for the depth, the jump into the loop belongs to the outer loop and the jump back
+        // to the loop itself
+        if let Stmt::JmpIfZero(_) = stmt {
+            *samples.entry(stack.clone()).or_insert(0) += count;
+            frame_starts.push(stack.len());
+            write!(stack, ";loop@{}..{}", span.start(), span.end()).unwrap();
+            continue;
+        }
+
+        *samples.entry(stack.clone()).or_insert(0) += count;
+
+        if let Stmt::JmpIfNonZero(_) = stmt {
+            let frame_start = frame_starts.pop().unwrap();
+            stack.truncate(frame_start);
+        }
+    }
+
+    let mut folded = String::new();
+    for (stack, count) in samples {
+        if count > 0 {
+            writeln!(folded, "{stack} {count}").unwrap();
+        }
+    }
+    Ok(folded)
+}
+
+/// How often every cell of the tape was read and written
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TapeHeatmap {
+    /// indexed by the cell, only as long as the highest accessed cell
+    reads: Vec<u64>,
+    writes: Vec<u64>,
+}
+
+impl TapeHeatmap {
+    pub fn reads(&self) -> &[u64] {
+        &self.reads
+    }
+
+    pub fn writes(&self) -> &[u64] {
+        &self.writes
+    }
+
+    /// The amount of reads and writes of `cell`
+    pub fn accesses(&self, cell: usize) -> u64 {
+        self.reads.get(cell).copied().unwrap_or(0) + self.writes.get(cell).copied().unwrap_or(0)
+    }
+
+    fn record(&mut self, event: ProfileEvent) {
+        let counts = match event.access {
+            Access::Read => &mut self.reads,
+            Access::Write => &mut self.writes,
+        };
+        if event.ptr >= counts.len() {
+            counts.resize(event.ptr + 1, 0);
+        }
+        counts[event.ptr] += 1;
+    }
+}
+
+/// Runs the code and counts how often every cell was read and written
+pub fn run_tape_heatmap<W, R>(
+    code: &Lir<'_>,
+    stdout: W,
+    stdin: R,
+    config: &RunConfig,
+) -> Result<TapeHeatmap, RuntimeError>
+where
+    W: Write,
+    R: Read,
+{
+    let mut heatmap = TapeHeatmap::default();
+    interpreter::run_with_tape_profile(
+        code,
+        stdout,
+        stdin,
+        config,
+        |_| {},
+        |event| heatmap.record(event),
+    )?;
+
+    Ok(heatmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::lir::{interpreter::RunConfig, Lir};
+
+    fn lir<'lir>(alloc: &'lir Bump, bf: &str) -> Lir<'lir> {
+        let ast = crate::parse::parse(alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(alloc, &ast);
+        crate::lir::generate(alloc, &hir)
+    }
+
+    #[test]
+    fn hot_inner_loop() {
+        // the inner loop runs 64 times, the outer one only 8 times. The `.` keeps the inner loop
+        // from being optimized into a `MoveMulTo`.
+        let alloc = Bump::new();
+        let lir = lir(&alloc, "++++++++[>++++++++[>++++.<-]<-]");
+
+        let profile =
+            super::run_depth_profiled(&lir, std::io::sink(), [].as_slice(), &RunConfig::default())
+                .unwrap();
+
+        assert_eq!(profile.counts().len(), 3);
+        assert!(profile.fraction(2) > 0.5, "{profile:?}");
+        assert!(profile.fraction(2) > profile.fraction(1));
+    }
+
+    #[test]
+    fn skipped_loop_uncovered() {
+        let alloc = Bump::new();
+        // reading a zero skips the first loop
+        let lir = lir(&alloc, ",[.-]+[.-]");
+
+        let coverage =
+            super::run_loop_coverage(&lir, std::io::sink(), [0].as_slice(), &RunConfig::default())
+                .unwrap();
+
+        let start_end = |span: crate::parse::Span| (span.start(), span.end());
+        let uncovered = coverage.uncovered().map(start_end).collect::<Vec<_>>();
+        let covered = coverage.covered().map(start_end).collect::<Vec<_>>();
+        assert_eq!(uncovered, [(1, 5)]);
+        assert_eq!(covered, [(6, 10)]);
+    }
+
+    #[test]
+    fn folded_nested_loops() {
+        let alloc = Bump::new();
+        let lir = lir(&alloc, "++[>++[>+.<-]<-]");
+
+        let folded =
+            super::run_folded_stacks(&lir, std::io::sink(), [].as_slice(), &RunConfig::default())
+                .unwrap();
+
+        assert_eq!(
+            folded,
+            "main 3\nmain;loop@2..16 12\nmain;loop@2..16;loop@6..13 24\n"
+        );
+    }
+
+    #[test]
+    fn scan_reads_every_cell() {
+        let alloc = Bump::new();
+        let lir = lir(&alloc, ",>,>,<<[>].");
+
+        let heatmap = super::run_tape_heatmap(
+            &lir,
+            std::io::sink(),
+            [1, 2, 3].as_slice(),
+            &RunConfig::default(),
+        )
+        .unwrap();
+
+        assert_eq!(heatmap.writes(), [1, 1, 1]);
+        // the scan checks the cells up to the zero at 3, which is printed afterwards
+        assert_eq!(heatmap.reads(), [1, 1, 1, 2]);
+        assert_eq!(heatmap.accesses(3), 2);
+    }
+}
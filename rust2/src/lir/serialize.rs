@@ -0,0 +1,349 @@
+//! a compact binary format for LIR, to cache compiled programs and run them somewhere else
+//!
+//! After the magic bytes, there are the `OutBytes` strings and the `MoveMulTo` targets, and then
+//! every statement with its span and loop depth. A statement is a one byte tag followed by its
+//! operands. Numbers are LEB128 varints, signed ones are zigzag encoded first, and the `u8`s of
+//! statements are written as they are. Jump targets are the absolute indices of statements.
+
+use std::io::{self, Read, Write};
+
+use bumpalo::Bump;
+
+use crate::{
+    lir::{Lir, LirError, Stmt},
+    parse::Span,
+};
+
+/// the last byte is the version of the format
+const MAGIC: &[u8; 4] = b"bfl\x01";
+
+/// Writes the LIR in the binary format, see [`deserialize`]
+pub fn serialize(lir: &Lir<'_>, out: &mut impl Write) -> io::Result<()> {
+    let mut out = Encoder { out };
+    out.out.write_all(MAGIC)?;
+
+    out.len(lir.out_bytes.len())?;
+    for bytes in &lir.out_bytes {
+        out.len(bytes.len())?;
+        out.out.write_all(bytes)?;
+    }
+
+    out.len(lir.mul_targets.len())?;
+    for targets in &lir.mul_targets {
+        out.len(targets.len())?;
+        for &(offset, factor) in targets.iter() {
+            out.i32(offset)?;
+            out.u8(factor)?;
+        }
+    }
+
+    out.len(lir.stmts.len())?;
+    for ((stmt, span), &depth) in lir.stmts.iter().zip(&lir.debug).zip(&lir.depths) {
+        out.stmt(*stmt)?;
+        out.len(span.start())?;
+        out.len(span.len())?;
+        out.u32(depth)?;
+    }
+    Ok(())
+}
+
+/// Reads LIR written by [`serialize`]. It is checked like [`Lir::new_checked`] does, so that it
+/// can be run even if it was changed or doesn't come from `serialize` at all.
+pub fn deserialize<'lir>(alloc: &'lir Bump, input: &mut impl Read) -> Result<Lir<'lir>, LirError> {
+    let mut input = Decoder { input };
+    let mut lir = Lir::new_in(alloc);
+
+    let mut magic = [0; 4];
+    input.input.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(LirError::Invalid("not serialized LIR of this version"));
+    }
+
+    // the lengths aren't used as capacities, a broken file could claim anything
+    for _ in 0..input.u32()? {
+        let mut bytes = Vec::new();
+        for _ in 0..input.u32()? {
+            bytes.push(input.u8()?);
+        }
+        lir.out_bytes.push(alloc.alloc_slice_copy(&bytes));
+    }
+
+    for _ in 0..input.u32()? {
+        let mut targets = Vec::new();
+        for _ in 0..input.u32()? {
+            targets.push((input.i32()?, input.u8()?));
+        }
+        lir.mul_targets.push(alloc.alloc_slice_copy(&targets));
+    }
+
+    for _ in 0..input.u32()? {
+        let stmt = input.stmt()?;
+        let start = input.u32()? as usize;
+        let len = input.u32()? as usize;
+        let depth = input.u32()?;
+        lir.push(stmt, Span::start_end(start, start + len), depth);
+    }
+
+    lir.check()?;
+    Ok(lir)
+}
+
+struct Encoder<'a, W> {
+    out: &'a mut W,
+}
+
+impl<W: Write> Encoder<'_, W> {
+    fn stmt(&mut self, stmt: Stmt) -> io::Result<()> {
+        match stmt {
+            Stmt::Add(n) => self.tagged_u8(0, n),
+            Stmt::Sub(n) => self.tagged_u8(1, n),
+            Stmt::AddOffset { offset, n } => {
+                self.u8(2)?;
+                self.i32(offset)?;
+                self.u8(n)
+            }
+            Stmt::SubOffset { offset, n } => {
+                self.u8(3)?;
+                self.i32(offset)?;
+                self.u8(n)
+            }
+            Stmt::MoveAddTo { offset } => {
+                self.u8(4)?;
+                self.i32(offset)
+            }
+            Stmt::MoveSubTo { offset } => {
+                self.u8(5)?;
+                self.i32(offset)
+            }
+            Stmt::MoveMulTo(idx) => self.tagged_u32(6, idx),
+            Stmt::Right(n) => self.tagged_u32(7, n),
+            Stmt::Left(n) => self.tagged_u32(8, n),
+            Stmt::ScanRight(n) => self.tagged_u32(9, n),
+            Stmt::ScanLeft(n) => self.tagged_u32(10, n),
+            Stmt::Out => self.u8(11),
+            Stmt::OutConst(n) => self.tagged_u8(12, n),
+            Stmt::OutRepeat { byte, count } => {
+                self.tagged_u8(21, byte)?;
+                self.u32(count)
+            }
+            Stmt::In => self.u8(13),
+            Stmt::SetN(n) => self.tagged_u8(14, n),
+            Stmt::SetNOffset { offset, n } => {
+                self.u8(15)?;
+                self.i32(offset)?;
+                self.u8(n)
+            }
+            Stmt::OutBytes(idx) => self.tagged_u32(16, idx),
+            Stmt::Debug => self.u8(17),
+            Stmt::JmpIfZero(target) => self.tagged_u32(18, target),
+            Stmt::JmpIfNonZero(target) => self.tagged_u32(19, target),
+            Stmt::End => self.u8(20),
+        }
+    }
+
+    fn tagged_u8(&mut self, tag: u8, n: u8) -> io::Result<()> {
+        self.out.write_all(&[tag, n])
+    }
+
+    fn tagged_u32(&mut self, tag: u8, n: u32) -> io::Result<()> {
+        self.u8(tag)?;
+        self.u32(n)
+    }
+
+    fn u8(&mut self, n: u8) -> io::Result<()> {
+        self.out.write_all(&[n])
+    }
+
+    fn len(&mut self, len: usize) -> io::Result<()> {
+        self.u32(u32::try_from(len).unwrap())
+    }
+
+    fn u32(&mut self, mut n: u32) -> io::Result<()> {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                return self.u8(byte);
+            }
+            self.u8(byte | 0x80)?;
+        }
+    }
+
+    fn i32(&mut self, n: i32) -> io::Result<()> {
+        // zigzag, so that small negative numbers are small as well
+        self.u32(((n << 1) ^ (n >> 31)) as u32)
+    }
+}
+
+struct Decoder<'a, R> {
+    input: &'a mut R,
+}
+
+impl<R: Read> Decoder<'_, R> {
+    fn stmt(&mut self) -> Result<Stmt, LirError> {
+        let stmt = match self.u8()? {
+            0 => Stmt::Add(self.u8()?),
+            1 => Stmt::Sub(self.u8()?),
+            2 => Stmt::AddOffset {
+                offset: self.i32()?,
+                n: self.u8()?,
+            },
+            3 => Stmt::SubOffset {
+                offset: self.i32()?,
+                n: self.u8()?,
+            },
+            4 => Stmt::MoveAddTo {
+                offset: self.i32()?,
+            },
+            5 => Stmt::MoveSubTo {
+                offset: self.i32()?,
+            },
+            6 => Stmt::MoveMulTo(self.u32()?),
+            7 => Stmt::Right(self.u32()?),
+            8 => Stmt::Left(self.u32()?),
+            9 => Stmt::ScanRight(self.u32()?),
+            10 => Stmt::ScanLeft(self.u32()?),
+            11 => Stmt::Out,
+            12 => Stmt::OutConst(self.u8()?),
+            13 => Stmt::In,
+            14 => Stmt::SetN(self.u8()?),
+            15 => Stmt::SetNOffset {
+                offset: self.i32()?,
+                n: self.u8()?,
+            },
+            16 => Stmt::OutBytes(self.u32()?),
+            17 => Stmt::Debug,
+            18 => Stmt::JmpIfZero(self.u32()?),
+            19 => Stmt::JmpIfNonZero(self.u32()?),
+            20 => Stmt::End,
+            21 => Stmt::OutRepeat {
+                byte: self.u8()?,
+                count: self.u32()?,
+            },
+            _ => return Err(LirError::Invalid("unknown statement")),
+        };
+        Ok(stmt)
+    }
+
+    fn u8(&mut self) -> Result<u8, LirError> {
+        let mut buf = [0; 1];
+        self.input.read_exact(&mut buf)?;
+        Ok(buf[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, LirError> {
+        let mut n = 0u32;
+        for shift in (0..32).step_by(7) {
+            let byte = self.u8()?;
+            let bits = u32::from(byte & 0x7f);
+            if bits << shift >> shift != bits {
+                return Err(LirError::Invalid("number too big"));
+            }
+            n |= bits << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(LirError::Invalid("number too big"))
+    }
+
+    fn i32(&mut self) -> Result<i32, LirError> {
+        let n = self.u32()?;
+        Ok((n >> 1) as i32 ^ -((n & 1) as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::lir::{Lir, LirError};
+
+    fn round_trip<'lir>(alloc: &'lir Bump, lir: &Lir<'_>) -> Lir<'lir> {
+        let mut bytes = Vec::new();
+        super::serialize(lir, &mut bytes).unwrap();
+        super::deserialize(alloc, &mut bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn same_after_round_trip() {
+        let alloc = Bump::new();
+        for bf in [
+            include_str!("../../benches/fizzbuzz.bf"),
+            include_str!("../../benches/bottles.bf"),
+            include_str!("../../benches/hanoi.bf"),
+        ] {
+            let ast = crate::parse::parse_bytes(&alloc, bf.as_bytes()).unwrap();
+            let hir = crate::hir::optimized_hir(&alloc, &ast);
+            let lir = crate::lir::generate(&alloc, &hir);
+
+            let loaded = round_trip(&alloc, &lir);
+
+            assert_eq!(loaded.stmts(), lir.stmts());
+            assert_eq!(loaded.debug(), lir.debug());
+            assert_eq!(loaded.depths(), lir.depths());
+            // shows the strings and targets as well
+            assert_eq!(loaded.to_string(), lir.to_string());
+            assert_eq!(
+                crate::lir::interpreter::run_to_vec(&loaded, &[]).unwrap(),
+                crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn negative_offsets_and_big_numbers() {
+        use crate::lir::Stmt;
+
+        let alloc = Bump::new();
+        let stmts = [
+            Stmt::AddOffset {
+                offset: i32::MIN,
+                n: 255,
+            },
+            Stmt::MoveAddTo { offset: -1 },
+            Stmt::SetNOffset {
+                offset: i32::MAX,
+                n: 0,
+            },
+            Stmt::Right(u32::MAX),
+            Stmt::End,
+        ];
+        let lir = Lir::new_checked(&alloc, &stmts).unwrap();
+        assert_eq!(round_trip(&alloc, &lir).stmts(), stmts);
+    }
+
+    #[test]
+    fn invalid() {
+        let alloc = Bump::new();
+        let load = |bytes: &[u8]| super::deserialize(&alloc, &mut &*bytes).map(drop);
+
+        let ast = crate::parse::parse_bytes(&alloc, b"+[->+<]").unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+        let mut bytes = Vec::new();
+        super::serialize(&lir, &mut bytes).unwrap();
+
+        assert!(matches!(
+            load(&bytes[..bytes.len() - 1]),
+            Err(LirError::Io {
+                kind: std::io::ErrorKind::UnexpectedEof,
+                ..
+            })
+        ));
+        assert_eq!(
+            load(b"bfl\x00"),
+            Err(LirError::Invalid("not serialized LIR of this version"))
+        );
+        // no strings, no targets, one statement with tag 99
+        assert_eq!(
+            load(b"bfl\x01\x00\x00\x01\x63"),
+            Err(LirError::Invalid("unknown statement"))
+        );
+        // a jump to 5 and no `End`
+        assert_eq!(
+            load(b"bfl\x01\x00\x00\x01\x12\x05\x00\x00\x00"),
+            Err(LirError::MissingEnd)
+        );
+    }
+}
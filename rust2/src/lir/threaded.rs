@@ -0,0 +1,366 @@
+//! an alternative interpreter using threaded dispatch
+//!
+//! Instead of `match`ing on each `Stmt` in the loop, the `Lir` is first translated into a
+//! sequence of `(handler, operand)` pairs, where the handler is a function pointer specialized
+//! for the kind of statement. Executing a statement is then a single indirect call, without having
+//! to go through the jump table of the `match` first.
+//!
+//! Real token threading would have each handler tail-call the next one, but stable Rust doesn't
+//! guarantee tail calls, so the handlers return to a tiny driver loop instead.
+//!
+//! Measured with `cargo bench --features threaded-dispatch --bench opts -- bench` on a single core
+//! Intel Xeon VM, `bench_threaded` takes ~420-470ms per run of `bench.bf`, and `bench`, which
+//! compiles `bench.bf` and runs it with the `match` loop in `interpreter`, takes ~720-800ms. The
+//! timings of the `match` loop vary a lot between builds on that machine though, the same `bench`
+//! took ~405ms in a build with `--features asm-jit`. This only supports the default `RunConfig`,
+//! so it stays behind the `threaded-dispatch` feature and the `match` loop stays the default.
+
+use std::{
+    io::{Read, Write},
+    num::Wrapping,
+    ops::ControlFlow,
+};
+
+use crate::lir::{
+    dump::debug_dump,
+    interpreter::{read_byte, EofBehavior, RunConfig, RuntimeError, MEM_SIZE},
+    Lir, Stmt,
+};
+
+#[derive(Debug, Clone, Copy)]
+struct Operand {
+    offset: i32,
+    n: u32,
+}
+
+/// Breaks when the program stopped, with the error if it failed
+type Flow = ControlFlow<Result<(), RuntimeError>>;
+
+type Handler<'lir, W, R> = fn(&mut Interpreter<'lir, W, R>, Operand) -> Flow;
+
+struct Interpreter<'lir, W, R> {
+    code: &'lir Lir<'lir>,
+    ip: usize,
+    ptr: usize,
+    mem: [Wrapping<u8>; MEM_SIZE],
+    out_buf: Vec<u8>,
+    /// Like `RunConfig::out_buffer`, the default of the match loop
+    out_buffer: usize,
+    stdout: W,
+    stdin: R,
+}
+
+/// Runs the code like the interpreter with the default config, `,` leaves the cell unchanged at
+/// the end of the input
+pub fn run<'lir, W, R>(code: &'lir Lir<'lir>, stdout: W, stdin: R) -> Result<(), RuntimeError>
+where
+    W: Write,
+    R: Read,
+{
+    let threaded = code
+        .stmts()
+        .iter()
+        .map(|stmt| thread(*stmt))
+        .collect::<Vec<_>>();
+
+    let out_buffer = RunConfig::default().out_buffer;
+    let mut interpreter = Interpreter {
+        code,
+        ip: 0,
+        ptr: 0,
+        mem: [Wrapping(0u8); MEM_SIZE],
+        out_buf: Vec::with_capacity(out_buffer),
+        out_buffer,
+        stdout,
+        stdin,
+    };
+
+    loop {
+        let (handler, operand) = if cfg!(debug_assertions) {
+            *threaded.get(interpreter.ip).unwrap_or_else(|| {
+                panic!(
+                    "instruction pointer {} out of bounds of the code (ptr: {})",
+                    interpreter.ip, interpreter.ptr
+                )
+            })
+        } else {
+            // SAFETY: `Lir` can only be produced by the `crate::lir` module, which is trusted to
+            // not produce out of bounds jumps and put the `End` at the end
+            unsafe { *threaded.get_unchecked(interpreter.ip) }
+        };
+        interpreter.ip += 1;
+        if let ControlFlow::Break(result) = handler(&mut interpreter, operand) {
+            interpreter.flush_output();
+            return result;
+        }
+    }
+}
+
+fn thread<'lir, W: Write, R: Read>(stmt: Stmt) -> (Handler<'lir, W, R>, Operand) {
+    let operand = |offset, n| Operand { offset, n };
+
+    match stmt {
+        Stmt::Add(n) => (add, operand(0, n.into())),
+        Stmt::Sub(n) => (sub, operand(0, n.into())),
+        Stmt::AddOffset { offset, n } => (add, operand(offset, n.into())),
+        Stmt::SubOffset { offset, n } => (sub, operand(offset, n.into())),
+        Stmt::MoveAddTo { offset } => (move_add_to, operand(offset, 0)),
+        Stmt::MoveSubTo { offset } => (move_sub_to, operand(offset, 0)),
+        Stmt::MoveMulTo(idx) => (move_mul_to, operand(0, idx)),
+        Stmt::Right(n) => (right, operand(0, n)),
+        Stmt::Left(n) => (left, operand(0, n)),
+        Stmt::ScanRight(n) => (scan_right, operand(0, n)),
+        Stmt::ScanLeft(n) => (scan_left, operand(0, n)),
+        Stmt::Out => (out, operand(0, 0)),
+        Stmt::OutConst(n) => (out_const, operand(0, n.into())),
+        // the byte doesn't need the offset
+        Stmt::OutRepeat { byte, count } => (out_repeat, operand(byte.into(), count)),
+        Stmt::OutBytes(idx) => (out_bytes, operand(0, idx)),
+        Stmt::In => (input, operand(0, 0)),
+        Stmt::SetN(n) => (set_n, operand(0, n.into())),
+        Stmt::SetNOffset { offset, n } => (set_n, operand(offset, n.into())),
+        Stmt::Debug => (debug, operand(0, 0)),
+        Stmt::JmpIfZero(pos) => (jmp_if_zero, operand(0, pos)),
+        Stmt::JmpIfNonZero(pos) => (jmp_if_non_zero, operand(0, pos)),
+        Stmt::End => (end, operand(0, 0)),
+    }
+}
+
+fn add<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    *i.elem_mut_offset(op.offset) += op.n as u8;
+    ControlFlow::Continue(())
+}
+
+fn sub<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    *i.elem_mut_offset(op.offset) -= op.n as u8;
+    ControlFlow::Continue(())
+}
+
+fn move_add_to<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    let value = *i.elem_mut_offset(0);
+    if value.0 != 0 {
+        *i.elem_mut_offset(0) = Wrapping(0);
+        *i.elem_mut_offset(op.offset) += value;
+    }
+    ControlFlow::Continue(())
+}
+
+fn move_sub_to<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    let value = *i.elem_mut_offset(0);
+    if value.0 != 0 {
+        *i.elem_mut_offset(0) = Wrapping(0);
+        *i.elem_mut_offset(op.offset) -= value;
+    }
+    ControlFlow::Continue(())
+}
+
+fn move_mul_to<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    let value = *i.elem_mut_offset(0);
+    if value.0 != 0 {
+        *i.elem_mut_offset(0) = Wrapping(0);
+        for &(offset, factor) in i.code.mul_targets(op.n) {
+            *i.elem_mut_offset(offset) += value * Wrapping(factor);
+        }
+    }
+    ControlFlow::Continue(())
+}
+
+fn right<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    i.ptr = (i.ptr + op.n as usize) % MEM_SIZE;
+    ControlFlow::Continue(())
+}
+
+fn left<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    let ptr = i.ptr as isize - op.n as isize;
+    i.ptr = ptr.rem_euclid(MEM_SIZE as isize) as usize;
+    ControlFlow::Continue(())
+}
+
+fn scan_right<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    while i.elem() != 0 {
+        let _ = right(i, op);
+    }
+    ControlFlow::Continue(())
+}
+
+fn scan_left<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    while i.elem() != 0 {
+        let _ = left(i, op);
+    }
+    ControlFlow::Continue(())
+}
+
+fn out<W: Write, R>(i: &mut Interpreter<'_, W, R>, _: Operand) -> Flow {
+    let byte = i.elem();
+    i.out_buf.push(byte);
+    i.flush_if_full();
+    ControlFlow::Continue(())
+}
+
+fn out_const<W: Write, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    i.out_buf.push(op.n as u8);
+    i.flush_if_full();
+    ControlFlow::Continue(())
+}
+
+fn out_repeat<W: Write, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    let len = i.out_buf.len() + op.n as usize;
+    i.out_buf.resize(len, op.offset as u8);
+    i.flush_if_full();
+    ControlFlow::Continue(())
+}
+
+fn out_bytes<W: Write, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    i.out_buf.extend_from_slice(i.code.out_bytes(op.n));
+    i.flush_if_full();
+    ControlFlow::Continue(())
+}
+
+fn input<W: Write, R: Read>(i: &mut Interpreter<'_, W, R>, _: Operand) -> Flow {
+    // a prompt must be visible before waiting for the answer
+    i.flush_output();
+    let byte = match read_byte(&mut i.stdin) {
+        Ok(byte) => byte,
+        Err(err) => return ControlFlow::Break(Err(err.into())),
+    };
+    let cell = i.elem_mut_offset(0);
+    match byte {
+        Some(byte) => *cell = Wrapping(byte),
+        None => cell.0 = EofBehavior::default().byte_at_eof(cell.0),
+    }
+    ControlFlow::Continue(())
+}
+
+fn set_n<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    *i.elem_mut_offset(op.offset) = Wrapping(op.n as u8);
+    ControlFlow::Continue(())
+}
+
+fn debug<W: Write, R>(i: &mut Interpreter<'_, W, R>, _: Operand) -> Flow {
+    let dump = debug_dump(i.mem.iter().map(|cell| cell.0), i.ptr);
+    i.out_buf.extend_from_slice(dump.as_bytes());
+    i.flush_output();
+    ControlFlow::Continue(())
+}
+
+fn jmp_if_zero<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    if i.elem() == 0 {
+        i.ip = op.n as usize;
+    }
+    ControlFlow::Continue(())
+}
+
+fn jmp_if_non_zero<W, R>(i: &mut Interpreter<'_, W, R>, op: Operand) -> Flow {
+    if i.elem() != 0 {
+        i.ip = op.n as usize;
+    }
+    ControlFlow::Continue(())
+}
+
+fn end<W, R>(_: &mut Interpreter<'_, W, R>, _: Operand) -> Flow {
+    ControlFlow::Break(Ok(()))
+}
+
+impl<W: Write, R> Interpreter<'_, W, R> {
+    fn flush_if_full(&mut self) {
+        if self.out_buf.len() >= self.out_buffer {
+            self.flush_output();
+        }
+    }
+
+    fn flush_output(&mut self) {
+        if !self.out_buf.is_empty() {
+            self.stdout.write_all(&self.out_buf).unwrap();
+            self.stdout.flush().unwrap();
+            self.out_buf.clear();
+        }
+    }
+}
+
+impl<W, R> Interpreter<'_, W, R> {
+    /// The cell at `offset` from the pointer, wrapping around the ends of the tape like the
+    /// pointer does
+    fn elem_mut_offset(&mut self, offset: i32) -> &mut Wrapping<u8> {
+        let mut idx = self.ptr as isize + offset as isize;
+        // the division is only needed at the ends of the tape
+        if !(0..MEM_SIZE as isize).contains(&idx) {
+            idx = idx.rem_euclid(MEM_SIZE as isize);
+        }
+        &mut self.mem[idx as usize]
+    }
+
+    fn elem(&self) -> u8 {
+        // SAFETY: `self.ptr` is never out of bounds
+        debug_assert!(self.ptr < self.mem.len());
+        unsafe { self.mem.get_unchecked(self.ptr).0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    #[test]
+    fn same_output_as_match_loop() {
+        let bf = include_str!("../../benches/fizzbuzz.bf");
+
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let expected = crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap();
+        let mut actual = Vec::new();
+        super::run(&lir, &mut actual, [].as_slice()).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn wrapping_pointer_same_as_match_loop() {
+        // marks the cells at both ends of the tape, then moves off them by more than one cell
+        let bf = "+<++<+++>>>>.<<<.>>[>]+>.<<<<<<<<.";
+
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let expected = crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap();
+        let mut actual = Vec::new();
+        super::run(&lir, &mut actual, [].as_slice()).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn wrapping_offsets_same_as_match_loop() {
+        // statements with offsets and moves across both ends of the tape
+        let bf =
+            "<+>++[-<+>]<.>>>+<<++[->>+<<]>>.<<<-<++++[->>>+<<<]>>>.<<<<+++[->++>>>+<<<<]>.>>>.";
+
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let expected = crate::lir::interpreter::run_to_vec(&lir, &[]).unwrap();
+        let mut actual = Vec::new();
+        super::run(&lir, &mut actual, [].as_slice()).unwrap();
+
+        assert_eq!(expected, actual);
+    }
+
+    #[test]
+    fn eof_leaves_cell_unchanged() {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, "+++,.>,.".bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+
+        let mut stdout = Vec::new();
+        super::run(&lir, &mut stdout, [].as_slice()).unwrap();
+
+        assert_eq!(stdout, [3, 0]);
+    }
+}
@@ -0,0 +1,529 @@
+//! turning the code back into source code of other languages, to compile it ahead of time
+//!
+//! The loops are rebuilt from the jumps with [`reconstruct_loops`], LIR with jumps that don't pair
+//! up into loops can't be transpiled.
+//! The tape wraps around at both ends like [`Stmt::Right`] and [`Stmt::Left`] with the default
+//! config of the interpreter, for the pointer and for the cells at an offset from it.
+
+use std::{
+    fmt::Write as _,
+    io::{self, Write},
+};
+
+use crate::lir::{
+    dump::DEBUG_WINDOW, interpreter::MEM_SIZE, reconstruct_loops, Lir, LirError, LoopTree, Node,
+    Stmt,
+};
+
+/// Writes a standalone C program that runs the code, with the tape in a global array
+///
+/// ```c
+/// while (mem[p]) {
+///     mem[p] -= 1;
+///     putchar(mem[p]);
+/// }
+/// ```
+///
+/// Reading past the end of the input leaves the cell unchanged.
+pub fn transpile_c<W: Write>(code: &Lir<'_>, out: &mut W) -> io::Result<()> {
+    let loops = reconstruct_loops(code)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+    let has_debug = code.stmts().iter().any(|stmt| matches!(stmt, Stmt::Debug));
+
+    writeln!(out, "#include <stdio.h>")?;
+    writeln!(out)?;
+    writeln!(out, "#define MEM_SIZE {MEM_SIZE}")?;
+    writeln!(out)?;
+    writeln!(out, "static unsigned char mem[MEM_SIZE];")?;
+    writeln!(out, "static size_t p = 0;")?;
+    if has_debug {
+        writeln!(out)?;
+        writeln!(out, "#define WINDOW {DEBUG_WINDOW}")?;
+        writeln!(out)?;
+        out.write_all(C_DEBUG_DUMP.as_bytes())?;
+    }
+    writeln!(out)?;
+    writeln!(out, "int main(void) {{")?;
+
+    c_block(code, out, &loops, 0)?;
+
+    writeln!(out, "    return 0;")?;
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+fn c_block<W: Write>(
+    code: &Lir<'_>,
+    out: &mut W,
+    block: &LoopTree,
+    depth: usize,
+) -> io::Result<()> {
+    let indent = "    ".repeat(depth + 1);
+    let wrapped = |n: u32| n as usize % MEM_SIZE;
+    for node in &block.nodes {
+        let stmt = match node {
+            Node::Stmt(stmt) => *stmt,
+            Node::Loop(body) => {
+                writeln!(out, "{indent}while (mem[p]) {{")?;
+                c_block(code, out, body, depth + 1)?;
+                writeln!(out, "{indent}}}")?;
+                continue;
+            }
+        };
+        match stmt {
+            Stmt::Add(n) => writeln!(out, "{indent}mem[p] += {n};")?,
+            Stmt::Sub(n) => writeln!(out, "{indent}mem[p] -= {n};")?,
+            Stmt::AddOffset { offset, n } => writeln!(out, "{indent}{} += {n};", c_cell(offset))?,
+            Stmt::SubOffset { offset, n } => writeln!(out, "{indent}{} -= {n};", c_cell(offset))?,
+            // adding zero to the other cell does nothing, so the current cell isn't checked
+            Stmt::MoveAddTo { offset } => {
+                writeln!(out, "{indent}{} += mem[p];", c_cell(offset))?;
+                writeln!(out, "{indent}mem[p] = 0;")?;
+            }
+            Stmt::MoveSubTo { offset } => {
+                writeln!(out, "{indent}{} -= mem[p];", c_cell(offset))?;
+                writeln!(out, "{indent}mem[p] = 0;")?;
+            }
+            Stmt::MoveMulTo(idx) => {
+                for &(offset, factor) in code.mul_targets(idx) {
+                    writeln!(out, "{indent}{} += mem[p] * {factor};", c_cell(offset))?;
+                }
+                writeln!(out, "{indent}mem[p] = 0;")?;
+            }
+            Stmt::Right(n) => writeln!(out, "{indent}p = (p + {}) % MEM_SIZE;", wrapped(n))?,
+            Stmt::Left(n) => writeln!(
+                out,
+                "{indent}p = (p + MEM_SIZE - {}) % MEM_SIZE;",
+                wrapped(n)
+            )?,
+            Stmt::ScanRight(n) => writeln!(
+                out,
+                "{indent}while (mem[p]) p = (p + {}) % MEM_SIZE;",
+                wrapped(n)
+            )?,
+            Stmt::ScanLeft(n) => writeln!(
+                out,
+                "{indent}while (mem[p]) p = (p + MEM_SIZE - {}) % MEM_SIZE;",
+                wrapped(n)
+            )?,
+            Stmt::Out => writeln!(out, "{indent}putchar(mem[p]);")?,
+            Stmt::OutConst(n) => writeln!(out, "{indent}putchar({n});")?,
+            Stmt::OutRepeat { byte, count } => {
+                writeln!(out, "{indent}for (int i = 0; i < {count}; i++) {{")?;
+                writeln!(out, "{indent}    putchar({byte});")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            Stmt::OutBytes(idx) => {
+                let bytes = code.out_bytes(idx);
+                let len = bytes.len();
+                writeln!(
+                    out,
+                    "{indent}fwrite(\"{}\", 1, {len}, stdout);",
+                    c_string(bytes)
+                )?
+            }
+            Stmt::In => {
+                // a prompt must be visible before waiting for the answer
+                writeln!(out, "{indent}fflush(stdout);")?;
+                writeln!(out, "{indent}{{")?;
+                writeln!(out, "{indent}    int c = getchar();")?;
+                writeln!(out, "{indent}    if (c != EOF) mem[p] = c;")?;
+                writeln!(out, "{indent}}}")?;
+            }
+            Stmt::SetN(n) => writeln!(out, "{indent}mem[p] = {n};")?,
+            Stmt::SetNOffset { offset, n } => writeln!(out, "{indent}{} = {n};", c_cell(offset))?,
+            Stmt::Debug => writeln!(out, "{indent}debug_dump();")?,
+            Stmt::JmpIfZero(_) | Stmt::JmpIfNonZero(_) => unreachable!("the jumps are loops"),
+            Stmt::End => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// The same dump as [`super::dump::debug_dump`], only emitted if the code contains a `Debug`
+const C_DEBUG_DUMP: &str = r#"static void debug_row(const char *name, int row) {
+    size_t start = p < WINDOW ? 0 : p - WINDOW;
+    size_t end = p + WINDOW + 1 < MEM_SIZE ? p + WINDOW + 1 : MEM_SIZE;
+    printf("%-5s", name);
+    for (size_t i = start; i < end; i++) {
+        char cell[24];
+        if (row == 0) {
+            snprintf(cell, sizeof cell, "%zu", i);
+        } else if (row == 1) {
+            snprintf(cell, sizeof cell, "%u", mem[i]);
+        } else {
+            snprintf(cell, sizeof cell, "%c", mem[i] >= ' ' && mem[i] <= '~' ? mem[i] : '.');
+        }
+        if (i == p) {
+            printf("[%3s]", cell);
+        } else {
+            printf(i + 1 == end ? " %3s" : " %3s ", cell);
+        }
+    }
+    putchar('\n');
+}
+
+static void debug_dump(void) {
+    debug_row("cell", 0);
+    debug_row("dec", 1);
+    debug_row("char", 2);
+    putchar('\n');
+    fflush(stdout);
+}
+"#;
+
+fn c_cell(offset: i32) -> String {
+    format!("mem[{}]", cell_index(offset))
+}
+
+/// The index of the cell at `offset` from `p`, which wraps around like `p` itself
+fn cell_index(offset: i32) -> String {
+    let wrapped = offset.unsigned_abs() as usize % MEM_SIZE;
+    match offset {
+        0 => "p".to_owned(),
+        offset if offset < 0 => format!("(p + MEM_SIZE - {wrapped}) % MEM_SIZE"),
+        _ => format!("(p + {wrapped}) % MEM_SIZE"),
+    }
+}
+
+/// Escapes the bytes for a C string literal. Hex escapes don't have a maximum length in C, so
+/// octal escapes are used instead.
+fn c_string(bytes: &[u8]) -> String {
+    let mut string = String::new();
+    for &byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                string.push('\\');
+                string.push(char::from(byte));
+            }
+            // `??` starts a trigraph
+            b'?' => string.push_str("\\?"),
+            b' '..=b'~' => string.push(char::from(byte)),
+            _ => string.push_str(&format!("\\{byte:03o}")),
+        }
+    }
+    string
+}
+
+/// Writes a standalone Rust program that runs the code, with the tape in a local array of
+/// `Wrapping<u8>`. It uses the same offsets as the code, so it can be compiled with `rustc -O` to
+/// compare against the interpreter.
+///
+/// ```text
+/// loop {
+///     if tape[p].0 == 0 {
+///         break;
+///     }
+///     tape[p] -= Wrapping(1);
+/// }
+/// ```
+pub fn transpile_rust(code: &Lir<'_>) -> Result<String, LirError> {
+    let loops = reconstruct_loops(code)?;
+    let has_debug = code.stmts().iter().any(|stmt| matches!(stmt, Stmt::Debug));
+    // unused imports and variables would be warned about
+    let has_input = code.stmts().iter().any(|stmt| matches!(stmt, Stmt::In));
+
+    let mut out = String::new();
+    match has_input {
+        true => writeln!(out, "use std::io::{{Read, Write}};").unwrap(),
+        false => writeln!(out, "use std::io::Write;").unwrap(),
+    }
+    writeln!(out, "use std::num::Wrapping;").unwrap();
+    writeln!(out).unwrap();
+    writeln!(out, "const MEM_SIZE: usize = {MEM_SIZE};").unwrap();
+    if has_debug {
+        writeln!(out).unwrap();
+        writeln!(out, "const WINDOW: usize = {DEBUG_WINDOW};").unwrap();
+        writeln!(out).unwrap();
+        out.push_str(RUST_DEBUG_DUMP);
+    }
+    writeln!(out).unwrap();
+    writeln!(out, "fn main() {{").unwrap();
+    writeln!(out, "    let mut tape = vec![Wrapping(0u8); MEM_SIZE];").unwrap();
+    writeln!(out, "    let mut p: usize = 0;").unwrap();
+    writeln!(out, "    let stdout = std::io::stdout();").unwrap();
+    writeln!(out, "    let mut out = stdout.lock();").unwrap();
+    if has_input {
+        writeln!(out, "    let stdin = std::io::stdin();").unwrap();
+        writeln!(out, "    let mut input = stdin.lock();").unwrap();
+    }
+    writeln!(out).unwrap();
+
+    rust_block(code, &mut out, &loops, 0);
+
+    writeln!(out, "    out.flush().unwrap();").unwrap();
+    writeln!(out, "}}").unwrap();
+    Ok(out)
+}
+
+fn rust_block(code: &Lir<'_>, out: &mut String, block: &LoopTree, depth: usize) {
+    let indent = "    ".repeat(depth + 1);
+    let wrapped = |n: u32| n as usize % MEM_SIZE;
+    for node in &block.nodes {
+        let stmt = match node {
+            Node::Stmt(stmt) => *stmt,
+            Node::Loop(body) => {
+                writeln!(out, "{indent}loop {{").unwrap();
+                writeln!(out, "{indent}    if tape[p].0 == 0 {{").unwrap();
+                writeln!(out, "{indent}        break;").unwrap();
+                writeln!(out, "{indent}    }}").unwrap();
+                rust_block(code, out, body, depth + 1);
+                writeln!(out, "{indent}}}").unwrap();
+                continue;
+            }
+        };
+        match stmt {
+            Stmt::Add(n) => writeln!(out, "{indent}tape[p] += Wrapping({n});").unwrap(),
+            Stmt::Sub(n) => writeln!(out, "{indent}tape[p] -= Wrapping({n});").unwrap(),
+            Stmt::AddOffset { offset, n } => {
+                writeln!(out, "{indent}{} += Wrapping({n});", rust_cell(offset)).unwrap()
+            }
+            Stmt::SubOffset { offset, n } => {
+                writeln!(out, "{indent}{} -= Wrapping({n});", rust_cell(offset)).unwrap()
+            }
+            Stmt::MoveAddTo { offset } => {
+                writeln!(out, "{indent}{{").unwrap();
+                writeln!(out, "{indent}    let value = tape[p];").unwrap();
+                writeln!(out, "{indent}    {} += value;", rust_cell(offset)).unwrap();
+                writeln!(out, "{indent}    tape[p] = Wrapping(0);").unwrap();
+                writeln!(out, "{indent}}}").unwrap();
+            }
+            Stmt::MoveSubTo { offset } => {
+                writeln!(out, "{indent}{{").unwrap();
+                writeln!(out, "{indent}    let value = tape[p];").unwrap();
+                writeln!(out, "{indent}    {} -= value;", rust_cell(offset)).unwrap();
+                writeln!(out, "{indent}    tape[p] = Wrapping(0);").unwrap();
+                writeln!(out, "{indent}}}").unwrap();
+            }
+            Stmt::MoveMulTo(idx) => {
+                writeln!(out, "{indent}{{").unwrap();
+                writeln!(out, "{indent}    let value = tape[p];").unwrap();
+                for &(offset, factor) in code.mul_targets(idx) {
+                    let cell = rust_cell(offset);
+                    writeln!(out, "{indent}    {cell} += value * Wrapping({factor});").unwrap();
+                }
+                writeln!(out, "{indent}    tape[p] = Wrapping(0);").unwrap();
+                writeln!(out, "{indent}}}").unwrap();
+            }
+            Stmt::Right(n) => {
+                writeln!(out, "{indent}p = (p + {}) % MEM_SIZE;", wrapped(n)).unwrap()
+            }
+            Stmt::Left(n) => writeln!(
+                out,
+                "{indent}p = (p + MEM_SIZE - {}) % MEM_SIZE;",
+                wrapped(n)
+            )
+            .unwrap(),
+            Stmt::ScanRight(n) => {
+                writeln!(out, "{indent}while tape[p].0 != 0 {{").unwrap();
+                writeln!(out, "{indent}    p = (p + {}) % MEM_SIZE;", wrapped(n)).unwrap();
+                writeln!(out, "{indent}}}").unwrap();
+            }
+            Stmt::ScanLeft(n) => {
+                writeln!(out, "{indent}while tape[p].0 != 0 {{").unwrap();
+                writeln!(
+                    out,
+                    "{indent}    p = (p + MEM_SIZE - {}) % MEM_SIZE;",
+                    wrapped(n)
+                )
+                .unwrap();
+                writeln!(out, "{indent}}}").unwrap();
+            }
+            Stmt::Out => writeln!(out, "{indent}out.write_all(&[tape[p].0]).unwrap();").unwrap(),
+            Stmt::OutConst(n) => writeln!(out, "{indent}out.write_all(&[{n}]).unwrap();").unwrap(),
+            Stmt::OutRepeat { byte, count } => writeln!(
+                out,
+                "{indent}out.write_all(&vec![{byte}; {count}]).unwrap();"
+            )
+            .unwrap(),
+            Stmt::OutBytes(idx) => {
+                let bytes = code.out_bytes(idx).escape_ascii();
+                writeln!(out, "{indent}out.write_all(b\"{bytes}\").unwrap();").unwrap()
+            }
+            Stmt::In => {
+                // a prompt must be visible before waiting for the answer
+                writeln!(out, "{indent}out.flush().unwrap();").unwrap();
+                writeln!(out, "{indent}{{").unwrap();
+                writeln!(out, "{indent}    let mut buf = [0];").unwrap();
+                writeln!(out, "{indent}    if input.read_exact(&mut buf).is_ok() {{").unwrap();
+                writeln!(out, "{indent}        tape[p] = Wrapping(buf[0]);").unwrap();
+                writeln!(out, "{indent}    }}").unwrap();
+                writeln!(out, "{indent}}}").unwrap();
+            }
+            Stmt::SetN(n) => writeln!(out, "{indent}tape[p] = Wrapping({n});").unwrap(),
+            Stmt::SetNOffset { offset, n } => {
+                writeln!(out, "{indent}{} = Wrapping({n});", rust_cell(offset)).unwrap()
+            }
+            Stmt::Debug => writeln!(out, "{indent}debug_dump(&tape, p, &mut out);").unwrap(),
+            Stmt::JmpIfZero(_) | Stmt::JmpIfNonZero(_) => unreachable!("the jumps are loops"),
+            Stmt::End => {}
+        }
+    }
+}
+
+/// The same dump as [`super::dump::debug_dump`], only emitted if the code contains a `Debug`
+const RUST_DEBUG_DUMP: &str = r#"fn debug_dump(tape: &[Wrapping<u8>], p: usize, out: &mut impl Write) {
+    let cells = p.saturating_sub(WINDOW)..(p + WINDOW + 1).min(tape.len());
+    let mut dump = String::new();
+    let rows: [(&str, &dyn Fn(usize) -> String); 3] = [
+        ("cell", &|idx| idx.to_string()),
+        ("dec", &|idx| tape[idx].0.to_string()),
+        ("char", &|idx| match tape[idx].0 {
+            byte @ b' '..=b'~' => char::from(byte).to_string(),
+            _ => ".".to_owned(),
+        }),
+    ];
+    for (name, fmt_cell) in rows {
+        dump.push_str(&format!("{name:<5}"));
+        for idx in cells.clone() {
+            let cell = fmt_cell(idx);
+            if idx == p {
+                dump.push_str(&format!("[{cell:>3}]"));
+            } else {
+                dump.push_str(&format!(" {cell:>3} "));
+            }
+        }
+        dump.truncate(dump.trim_end().len());
+        dump.push('\n');
+    }
+    dump.push('\n');
+    out.write_all(dump.as_bytes()).unwrap();
+    out.flush().unwrap();
+}
+"#;
+
+fn rust_cell(offset: i32) -> String {
+    format!("tape[{}]", cell_index(offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    use bumpalo::Bump;
+
+    use crate::lir::{Lir, LirError, Stmt};
+
+    fn lir<'lir>(alloc: &'lir Bump, bf: &str) -> Lir<'lir> {
+        let ast = crate::parse::parse(alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(alloc, &ast);
+        crate::lir::generate(alloc, &hir)
+    }
+
+    fn transpile_c(bf: &str) -> String {
+        let alloc = Bump::new();
+        let mut out = Vec::new();
+        super::transpile_c(&lir(&alloc, bf), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn c_fizzbuzz() {
+        insta::assert_snapshot!(transpile_c(include_str!("../../benches/fizzbuzz.bf")));
+    }
+
+    #[test]
+    fn c_nested_loops() {
+        let c = transpile_c(",[>,[-<+>]<.]");
+        let body = c.split_once("int main(void) {\n").unwrap().1;
+        assert_eq!(
+            body,
+            "    fflush(stdout);
+    {
+        int c = getchar();
+        if (c != EOF) mem[p] = c;
+    }
+    while (mem[p]) {
+        p = (p + 1) % MEM_SIZE;
+        fflush(stdout);
+        {
+            int c = getchar();
+            if (c != EOF) mem[p] = c;
+        }
+        mem[(p + MEM_SIZE - 1) % MEM_SIZE] += mem[p];
+        mem[p] = 0;
+        p = (p + MEM_SIZE - 1) % MEM_SIZE;
+        putchar(mem[p]);
+    }
+    return 0;
+}
+"
+        );
+    }
+
+    #[test]
+    fn unpaired_jumps() {
+        let alloc = Bump::new();
+        let lir = Lir::new_checked(&alloc, &[Stmt::JmpIfZero(2), Stmt::Out, Stmt::End]).unwrap();
+
+        let err = super::transpile_c(&lir, &mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        assert_eq!(
+            super::transpile_rust(&lir),
+            Err(LirError::UnpairedJump { idx: 0 })
+        );
+    }
+
+    /// Compiles the Rust program with `rustc -O` and runs it
+    fn run_transpiled_rust(bf: &str, input: &[u8]) -> Vec<u8> {
+        let alloc = Bump::new();
+        let rust = super::transpile_rust(&lir(&alloc, bf)).unwrap();
+
+        let dir = std::env::temp_dir().join(format!("bf-transpile-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let src = dir.join(format!("{}.rs", input.len()));
+        let bin = dir.join(format!("{}.bin", input.len()));
+        std::fs::write(&src, rust).unwrap();
+
+        let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+        let status = Command::new(rustc)
+            .arg("-O")
+            .arg(&src)
+            .arg("-o")
+            .arg(&bin)
+            .status()
+            .unwrap();
+        assert!(status.success(), "the transpiled program doesn't compile");
+
+        let mut child = Command::new(&bin)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        output.stdout
+    }
+
+    fn run(bf: &str, input: &[u8]) -> Vec<u8> {
+        let alloc = Bump::new();
+        let lir = lir(&alloc, bf);
+        crate::lir::interpreter::run_to_vec(&lir, input).unwrap()
+    }
+
+    #[test]
+    fn rust_same_output() {
+        let bench = include_str!("../../benches/bench.bf");
+        assert_eq!(run_transpiled_rust(bench, b""), run(bench, b""));
+
+        // moves the cell off the start of the tape, which wraps around to the end
+        let bf = "+[-<+>]<.";
+        assert_eq!(run_transpiled_rust(bf, b""), run(bf, b""));
+
+        // dumps the tape and echoes the input
+        let bf = "++++[>+++++<-]>[>++>+++<<-]>>+#<<<+#.>>,[.[-],]";
+        assert_eq!(run_transpiled_rust(bf, b"hey"), run(bf, b"hey"));
+    }
+
+    #[test]
+    fn c_string_escapes() {
+        assert_eq!(super::c_string(b"a\"b\\?\n\x01"), r#"a\"b\\\?\012\001"#);
+    }
+}
@@ -0,0 +1,564 @@
+//! compiling the code to a WebAssembly module, to run programs in the browser
+//!
+//! The module exports its `memory`, an `input_start` global and a function
+//! `run(stdin_ptr: i32, stdin_len: i32) -> i32`. The host writes the input to the memory at
+//! `input_start` (growing it if needed) and calls `run`, which returns a pointer to the output:
+//! its length as a little endian `u32`, followed by the bytes. The output is written right after
+//! the input and the memory grows as needed.
+//!
+//! The memory starts with the tape, which isn't reset, so `run` can only be called once per
+//! instance. The pointer and the cells at an offset from it wrap around the ends of the tape like
+//! in the interpreter, and reading past the end of the input leaves the cell unchanged.
+//! `#` is skipped, the dump would need formatting numbers in wasm.
+//!
+//! `OutRepeat` uses `memory.fill`, so the runtime has to support the bulk memory operations, which
+//! all major browsers and Node.js do.
+
+use crate::lir::{interpreter::MEM_SIZE, reconstruct_loops, Lir, LirError, LoopTree, Node, Stmt};
+
+const PAGE_SIZE: usize = 65536;
+
+// the parameters and locals of `run`
+const IN_PTR: u32 = 0;
+const IN_END: u32 = 1;
+/// the index of the current cell on the tape, which is also its address
+const PTR: u32 = 2;
+const OUT_START: u32 = 3;
+const OUT_PTR: u32 = 4;
+const VALUE: u32 = 5;
+/// the address of a cell at an offset from the pointer, see `Encoder::cell_addr`
+const ADDR: u32 = 6;
+
+/// Compiles the code to the binary format of a WebAssembly module
+pub fn compile_wasm(code: &Lir<'_>) -> Result<Vec<u8>, LirError> {
+    let loops = reconstruct_loops(code)?;
+    let input_start = MEM_SIZE;
+    // at least one more page for the input and output
+    let pages = input_start / PAGE_SIZE + 1;
+
+    let mut module = Vec::new();
+    module.extend_from_slice(b"\0asm");
+    module.extend_from_slice(&1u32.to_le_bytes());
+
+    // type section: (i32, i32) -> i32
+    section(&mut module, 1, &[0x01, 0x60, 0x02, 0x7f, 0x7f, 0x01, 0x7f]);
+    // function section: the function has the type above
+    section(&mut module, 3, &[0x01, 0x00]);
+    // memory section: no maximum
+    let mut memory = Encoder::default();
+    memory.emit(&[0x01, 0x00]);
+    memory.emit_u32(pages as u32);
+    section(&mut module, 5, &memory.code);
+    // global section: an immutable i32
+    let mut global = Encoder::default();
+    global.emit(&[0x01, 0x7f, 0x00, 0x41]);
+    global.emit_i32(input_start as i32);
+    global.emit(&[0x0b]);
+    section(&mut module, 6, &global.code);
+    // export section
+    let mut exports = Encoder::default();
+    exports.emit_u32(3);
+    exports.emit_name("memory");
+    exports.emit(&[0x02, 0x00]);
+    exports.emit_name("run");
+    exports.emit(&[0x00, 0x00]);
+    exports.emit_name("input_start");
+    exports.emit(&[0x03, 0x00]);
+    section(&mut module, 7, &exports.code);
+    // code section
+    let body = compile_run(code, &loops);
+    let mut function = Encoder::default();
+    function.emit_u32(body.len() as u32);
+    function.emit(&body);
+    let mut code_section = Encoder::default();
+    code_section.emit_u32(1);
+    code_section.emit(&function.code);
+    section(&mut module, 10, &code_section.code);
+
+    Ok(module)
+}
+
+/// The body of `run`, with its locals
+fn compile_run(code: &Lir<'_>, loops: &LoopTree) -> Vec<u8> {
+    let mut wasm = Encoder::default();
+    // five i32 locals after the two parameters
+    wasm.emit(&[0x01, 0x05, 0x7f]);
+
+    // the input is turned into a range, `stdin_ptr` moves forward while reading
+    wasm.local_get(IN_PTR);
+    wasm.local_get(IN_END);
+    wasm.emit(&[0x6a]); // i32.add
+    wasm.local_tee(IN_END);
+    wasm.local_tee(OUT_START);
+    wasm.i32_const(4);
+    wasm.emit(&[0x6a]); // i32.add
+    wasm.local_set(OUT_PTR);
+
+    compile_block(&mut wasm, code, loops);
+
+    // the length of the output in front of it
+    wasm.local_get(OUT_START);
+    wasm.local_get(OUT_PTR);
+    wasm.local_get(OUT_START);
+    wasm.emit(&[0x6b]); // i32.sub
+    wasm.add_const_i32(-4);
+    wasm.emit(&[0x36, 0x00, 0x00]); // i32.store
+    wasm.local_get(OUT_START);
+    wasm.emit(&[0x0b]); // end
+
+    wasm.code
+}
+
+/// Appends the code for the statements of `block`, with the loops turned into `block` and `loop`
+fn compile_block(wasm: &mut Encoder, code: &Lir<'_>, block: &LoopTree) {
+    for node in &block.nodes {
+        let stmt = match node {
+            Node::Stmt(stmt) => *stmt,
+            Node::Loop(body) => {
+                wasm.emit(&[0x02, 0x40, 0x03, 0x40]); // block, loop
+                wasm.load_cell(0);
+                wasm.emit(&[0x45]); // i32.eqz
+                wasm.emit(&[0x0d, 0x01]); // br_if the block
+                compile_block(wasm, code, body);
+                wasm.emit(&[0x0c, 0x00]); // br the loop
+                wasm.emit(&[0x0b, 0x0b]); // end, end
+                continue;
+            }
+        };
+        match stmt {
+            Stmt::Add(n) => wasm.update_cell(0, |wasm| wasm.add_const(n)),
+            Stmt::Sub(n) => wasm.update_cell(0, |wasm| wasm.sub_const(n)),
+            Stmt::AddOffset { offset, n } => wasm.update_cell(offset, |wasm| wasm.add_const(n)),
+            Stmt::SubOffset { offset, n } => wasm.update_cell(offset, |wasm| wasm.sub_const(n)),
+            // adding zero to the other cell does nothing, so there is no need to check the cell
+            // first
+            Stmt::MoveAddTo { offset } => {
+                wasm.load_cell(0);
+                wasm.local_set(VALUE);
+                wasm.update_cell(offset, |wasm| {
+                    wasm.local_get(VALUE);
+                    wasm.emit(&[0x6a]); // i32.add
+                });
+                wasm.store_cell(0, |wasm| wasm.i32_const(0));
+            }
+            Stmt::MoveSubTo { offset } => {
+                wasm.load_cell(0);
+                wasm.local_set(VALUE);
+                wasm.update_cell(offset, |wasm| {
+                    wasm.local_get(VALUE);
+                    wasm.emit(&[0x6b]); // i32.sub
+                });
+                wasm.store_cell(0, |wasm| wasm.i32_const(0));
+            }
+            Stmt::MoveMulTo(idx) => {
+                wasm.load_cell(0);
+                wasm.local_set(VALUE);
+                for &(offset, factor) in code.mul_targets(idx) {
+                    wasm.update_cell(offset, |wasm| {
+                        wasm.local_get(VALUE);
+                        wasm.i32_const(factor.into());
+                        wasm.emit(&[0x6c]); // i32.mul
+                        wasm.emit(&[0x6a]); // i32.add
+                    });
+                }
+                wasm.store_cell(0, |wasm| wasm.i32_const(0));
+            }
+            Stmt::Right(n) => wasm.right(n),
+            Stmt::Left(n) => wasm.left(n),
+            Stmt::ScanRight(n) => wasm.scan(|wasm| wasm.right(n)),
+            Stmt::ScanLeft(n) => wasm.scan(|wasm| wasm.left(n)),
+            Stmt::Out => {
+                wasm.reserve_output(1);
+                wasm.local_get(OUT_PTR);
+                wasm.load_cell(0);
+                wasm.store8(0);
+                wasm.advance_output(1);
+            }
+            Stmt::OutConst(n) => {
+                wasm.reserve_output(1);
+                wasm.local_get(OUT_PTR);
+                wasm.i32_const(n.into());
+                wasm.store8(0);
+                wasm.advance_output(1);
+            }
+            Stmt::OutRepeat { byte, count } => {
+                let count = count as usize;
+                wasm.reserve_output(count);
+                wasm.local_get(OUT_PTR);
+                wasm.i32_const(byte.into());
+                wasm.i32_const(count as i32);
+                wasm.emit(&[0xfc, 0x0b, 0x00]); // memory.fill
+                wasm.advance_output(count);
+            }
+            Stmt::OutBytes(idx) => {
+                let bytes = code.out_bytes(idx);
+                wasm.reserve_output(bytes.len());
+                for (i, &byte) in bytes.iter().enumerate() {
+                    wasm.local_get(OUT_PTR);
+                    wasm.i32_const(byte.into());
+                    wasm.store8(i as u32);
+                }
+                wasm.advance_output(bytes.len());
+            }
+            Stmt::In => {
+                wasm.local_get(IN_PTR);
+                wasm.local_get(IN_END);
+                wasm.emit(&[0x49]); // i32.lt_u
+                wasm.emit(&[0x04, 0x40]); // if
+                wasm.store_cell(0, |wasm| {
+                    wasm.local_get(IN_PTR);
+                    wasm.load8(0);
+                });
+                wasm.local_get(IN_PTR);
+                wasm.add_const(1);
+                wasm.local_set(IN_PTR);
+                wasm.emit(&[0x0b]); // end
+            }
+            Stmt::SetN(n) => wasm.store_cell(0, |wasm| wasm.i32_const(n.into())),
+            Stmt::SetNOffset { offset, n } => {
+                wasm.store_cell(offset, |wasm| wasm.i32_const(n.into()))
+            }
+            // there is no dump in wasm, see the module docs
+            Stmt::Debug => {}
+            Stmt::JmpIfZero(_) | Stmt::JmpIfNonZero(_) => unreachable!("the jumps are loops"),
+            Stmt::End => {}
+        }
+    }
+}
+
+/// Appends a section with its id and size
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    let mut header = Encoder::default();
+    header.emit(&[id]);
+    header.emit_u32(contents.len() as u32);
+    module.extend_from_slice(&header.code);
+    module.extend_from_slice(contents);
+}
+
+#[derive(Default)]
+struct Encoder {
+    code: Vec<u8>,
+}
+
+impl Encoder {
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// unsigned LEB128
+    fn emit_u32(&mut self, mut n: u32) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            if n == 0 {
+                self.code.push(byte);
+                return;
+            }
+            self.code.push(byte | 0x80);
+        }
+    }
+
+    /// signed LEB128
+    fn emit_i32(&mut self, mut n: i32) {
+        loop {
+            let byte = (n & 0x7f) as u8;
+            n >>= 7;
+            let sign_bit = byte & 0x40 != 0;
+            if (n == 0 && !sign_bit) || (n == -1 && sign_bit) {
+                self.code.push(byte);
+                return;
+            }
+            self.code.push(byte | 0x80);
+        }
+    }
+
+    fn emit_name(&mut self, name: &str) {
+        self.emit_u32(name.len() as u32);
+        self.emit(name.as_bytes());
+    }
+
+    fn local_get(&mut self, local: u32) {
+        self.emit(&[0x20]);
+        self.emit_u32(local);
+    }
+
+    fn local_set(&mut self, local: u32) {
+        self.emit(&[0x21]);
+        self.emit_u32(local);
+    }
+
+    fn local_tee(&mut self, local: u32) {
+        self.emit(&[0x22]);
+        self.emit_u32(local);
+    }
+
+    fn i32_const(&mut self, n: i32) {
+        self.emit(&[0x41]);
+        self.emit_i32(n);
+    }
+
+    fn add_const(&mut self, n: u8) {
+        self.add_const_i32(n.into());
+    }
+
+    fn add_const_i32(&mut self, n: i32) {
+        self.i32_const(n);
+        self.emit(&[0x6a]); // i32.add
+    }
+
+    fn sub_const(&mut self, n: u8) {
+        self.i32_const(n.into());
+        self.emit(&[0x6b]); // i32.sub
+    }
+
+    /// `i32.load8_u` from the address on the stack plus `offset`
+    fn load8(&mut self, offset: u32) {
+        self.emit(&[0x2d, 0x00]);
+        self.emit_u32(offset);
+    }
+
+    /// `i32.store8` of the value to the address below it on the stack plus `offset`, which only
+    /// keeps the low byte, so the cells wrap around
+    fn store8(&mut self, offset: u32) {
+        self.emit(&[0x3a, 0x00]);
+        self.emit_u32(offset);
+    }
+
+    /// Pushes the address of the cell at `offset` from the pointer, which wraps around the ends
+    /// of the tape like the pointer does. Returns the local that holds the address as well.
+    fn cell_addr(&mut self, offset: i32) -> u32 {
+        if offset == 0 {
+            self.local_get(PTR);
+            return PTR;
+        }
+        // the pointer is on the tape, so a single subtraction is enough to wrap the sum
+        let wrapped = offset.rem_euclid(MEM_SIZE as i32);
+        self.local_get(PTR);
+        self.add_const_i32(wrapped);
+        self.local_tee(ADDR);
+        self.add_const_i32(-(MEM_SIZE as i32));
+        self.local_get(ADDR);
+        self.local_get(ADDR);
+        self.i32_const(MEM_SIZE as i32);
+        self.emit(&[0x4f]); // i32.ge_u
+        self.emit(&[0x1b]); // select
+        self.local_tee(ADDR);
+        ADDR
+    }
+
+    fn load_cell(&mut self, offset: i32) {
+        self.cell_addr(offset);
+        self.load8(0);
+    }
+
+    fn store_cell(&mut self, offset: i32, emit_value: impl FnOnce(&mut Self)) {
+        self.cell_addr(offset);
+        emit_value(self);
+        self.store8(0);
+    }
+
+    /// Replaces the cell with the result of `emit_update`, which gets the old value on the stack
+    fn update_cell(&mut self, offset: i32, emit_update: impl FnOnce(&mut Self)) {
+        let addr = self.cell_addr(offset);
+        self.local_get(addr);
+        self.load8(0);
+        emit_update(self);
+        self.store8(0);
+    }
+
+    fn right(&mut self, n: u32) {
+        self.local_get(PTR);
+        self.add_const_i32((n as usize % MEM_SIZE) as i32);
+        self.local_tee(PTR);
+        self.i32_const(MEM_SIZE as i32);
+        self.emit(&[0x4f]); // i32.ge_u
+        self.emit(&[0x04, 0x40]); // if
+        self.local_get(PTR);
+        self.add_const_i32(-(MEM_SIZE as i32));
+        self.local_set(PTR);
+        self.emit(&[0x0b]); // end
+    }
+
+    fn left(&mut self, n: u32) {
+        self.local_get(PTR);
+        self.add_const_i32(-((n as usize % MEM_SIZE) as i32));
+        self.local_tee(PTR);
+        self.i32_const(0);
+        self.emit(&[0x48]); // i32.lt_s
+        self.emit(&[0x04, 0x40]); // if
+        self.local_get(PTR);
+        self.add_const_i32(MEM_SIZE as i32);
+        self.local_set(PTR);
+        self.emit(&[0x0b]); // end
+    }
+
+    /// Repeats the pointer move emitted by `emit_move` until the current cell is zero
+    fn scan(&mut self, emit_move: impl FnOnce(&mut Self)) {
+        self.emit(&[0x02, 0x40, 0x03, 0x40]); // block, loop
+        self.load_cell(0);
+        self.emit(&[0x45]); // i32.eqz
+        self.emit(&[0x0d, 0x01]); // br_if the block
+        emit_move(self);
+        self.emit(&[0x0c, 0x00]); // br the loop
+        self.emit(&[0x0b, 0x0b]); // end, end
+    }
+
+    /// Grows the memory if the next `len` bytes of output don't fit into it
+    fn reserve_output(&mut self, len: usize) {
+        self.local_get(OUT_PTR);
+        self.add_const_i32(len as i32);
+        self.emit(&[0x3f, 0x00]); // memory.size
+        self.i32_const(16);
+        self.emit(&[0x74]); // i32.shl, the size in bytes
+        self.emit(&[0x4b]); // i32.gt_u
+        self.emit(&[0x04, 0x40]); // if
+        self.i32_const((len / PAGE_SIZE + 1) as i32);
+        self.emit(&[0x40, 0x00]); // memory.grow
+        self.emit(&[0x1a]); // drop
+        self.emit(&[0x0b]); // end
+    }
+
+    fn advance_output(&mut self, len: usize) {
+        self.local_get(OUT_PTR);
+        self.add_const_i32(len as i32);
+        self.local_set(OUT_PTR);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        process::{Command, Stdio},
+    };
+
+    use bumpalo::Bump;
+
+    fn compile(bf: &str) -> Vec<u8> {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+        super::compile_wasm(&lir).unwrap()
+    }
+
+    #[test]
+    fn module_header_and_exports() {
+        let wasm = compile(include_str!("../../benches/fizzbuzz.bf"));
+
+        assert_eq!(&wasm[..8], b"\0asm\x01\0\0\0");
+        for export in [&b"\x06memory"[..], b"\x03run", b"\x0binput_start"] {
+            assert!(wasm.windows(export.len()).any(|window| window == export));
+        }
+    }
+
+    #[test]
+    fn sections() {
+        let wasm = compile(include_str!("../../benches/fizzbuzz.bf"));
+
+        let mut ids = Vec::new();
+        let mut rest = &wasm[8..];
+        while let [id, after_id @ ..] = rest {
+            let (size, after_size) = read_u32(after_id);
+            ids.push(*id);
+            // the sizes must add up to exactly the length of the module
+            rest = &after_size[size as usize..];
+        }
+
+        // type, function, memory, global, export and code
+        assert_eq!(ids, [1, 3, 5, 6, 7, 10]);
+    }
+
+    /// Reads an unsigned LEB128 number and returns the bytes after it
+    fn read_u32(bytes: &[u8]) -> (u32, &[u8]) {
+        let mut n = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            n |= u32::from(byte & 0x7f) << (7 * i);
+            if byte & 0x80 == 0 {
+                return (n, &bytes[i + 1..]);
+            }
+        }
+        panic!("unterminated LEB128 number");
+    }
+
+    /// Instantiates the module like a host would and prints the output
+    const NODE_HOST: &str = r#"
+const fs = require("fs");
+const input = fs.readFileSync(0);
+WebAssembly.instantiate(fs.readFileSync(process.argv[1])).then(({ instance }) => {
+    const { memory, run, input_start } = instance.exports;
+    const start = input_start.value;
+    const missing = start + input.length - memory.buffer.byteLength;
+    if (missing > 0) memory.grow(Math.ceil(missing / 65536));
+    new Uint8Array(memory.buffer, start, input.length).set(input);
+    const out = run(start, input.length);
+    const len = new DataView(memory.buffer).getUint32(out, true);
+    process.stdout.write(new Uint8Array(memory.buffer, out + 4, len));
+});
+"#;
+
+    /// Runs the module with `node`, or with the command in `$NODE`
+    fn run_in_node(bf: &str, input: &[u8]) -> Vec<u8> {
+        let dir = std::env::temp_dir().join(format!("bf-wasm-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let module = dir.join(format!("{}.wasm", input.len()));
+        std::fs::write(&module, compile(bf)).unwrap();
+
+        let node = std::env::var("NODE").unwrap_or_else(|_| "node".to_owned());
+        let child = Command::new(node)
+            .arg("-e")
+            .arg(NODE_HOST)
+            .arg(&module)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = child.unwrap_or_else(|err| panic!("failed to start node: {err}"));
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output = child.wait_with_output().unwrap();
+        assert!(output.status.success(), "the module failed to run");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        output.stdout
+    }
+
+    fn run(bf: &str, input: &[u8]) -> Vec<u8> {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+        let lir = crate::lir::generate(&alloc, &hir);
+        crate::lir::interpreter::run_to_vec(&lir, input).unwrap()
+    }
+
+    #[test]
+    #[ignore = "needs node, run with `cargo test --features wasm -- --ignored`"]
+    fn same_output_as_interpreter() {
+        let programs = [
+            (include_str!("../../benches/fizzbuzz.bf"), &b""[..]),
+            (include_str!("../../benches/bench.bf"), b""),
+            // echoes the input
+            (",[.[-],]+++.", b"hey"),
+            // wraps around the start of the tape
+            ("+<<+++[>++<-]>>.<<.", b""),
+            // statements with offsets and moves across both ends of the tape
+            (
+                "<+>++[-<+>]<.>>>+<<++[->>+<<]>>.<<<-<++++[->>>+<<<]>>>.<<<<+++[->++>>>+<<<<]>.>>>.",
+                b"",
+            ),
+        ];
+        for (bf, input) in programs {
+            assert_eq!(run_in_node(bf, input), run(bf, input), "for {bf:?}");
+        }
+    }
+
+    #[test]
+    fn leb128() {
+        let mut encoder = super::Encoder::default();
+        encoder.emit_u32(624485);
+        encoder.emit_i32(-123456);
+        encoder.emit_i32(64);
+        assert_eq!(
+            encoder.code,
+            [0xe5, 0x8e, 0x26, 0xc0, 0xbb, 0x78, 0xc0, 0x00]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+//! building blocks for a brainfuck language server
+//!
+//! This is not a language server itself, but everything a server needs to analyze documents.
+//! A server keeps a [`Document`] per open file, applies the edits from the client to it and
+//! asks it for diagnostics and hover info.
+
+use std::ops::Range;
+
+use bumpalo::Bump;
+
+use crate::{
+    hir::{self, Hir, StmtKind},
+    parse::{self, ParseError, Span},
+};
+
+/// An open document, with a line index to map byte offsets to lines and columns
+#[derive(Debug, Clone)]
+pub struct Document {
+    src: String,
+    /// the byte offset of the start of every line
+    line_starts: Vec<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub span: Span,
+    /// zero based
+    pub line: usize,
+    /// zero based, counted in characters
+    pub col: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hover {
+    /// the source code the optimized statement was created from
+    pub span: Span,
+    pub text: String,
+}
+
+impl Document {
+    pub fn new(src: String) -> Self {
+        let mut doc = Self {
+            src,
+            line_starts: Vec::new(),
+        };
+        doc.index_lines();
+        doc
+    }
+
+    pub fn src(&self) -> &str {
+        &self.src
+    }
+
+    /// Replaces the bytes in `range` with `text`, like an incremental change sent by the client
+    pub fn edit(&mut self, range: Range<usize>, text: &str) {
+        self.src.replace_range(range, text);
+        self.index_lines();
+    }
+
+    fn index_lines(&mut self) {
+        self.line_starts.clear();
+        self.line_starts.push(0);
+        let line_starts = self.src.match_indices('\n').map(|(idx, _)| idx + 1);
+        self.line_starts.extend(line_starts);
+    }
+
+    /// The zero based line and column of a byte offset
+    pub fn line_col(&self, offset: usize) -> (usize, usize) {
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        let col = self.src[self.line_starts[line]..offset].chars().count();
+        (line, col)
+    }
+
+    /// All unmatched brackets in the document
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        let alloc = Bump::new();
+        let (_, errors) = parse::parse_recover(&alloc, self.src.bytes().enumerate());
+
+        errors
+            .iter()
+            .map(|err| match err {
+                ParseError::UnmatchedClose { span } => {
+                    self.diagnostic(span.start(), "Unmatched closing bracket")
+                }
+                ParseError::UnmatchedOpen { span } => {
+                    self.diagnostic(span.start(), "Unclosed opening bracket")
+                }
+                // `parse_recover` has no depth limit
+                ParseError::TooDeeplyNested { span, .. } => {
+                    self.diagnostic(span.start(), &err.to_string())
+                }
+            })
+            .collect()
+    }
+
+    fn diagnostic(&self, idx: usize, message: &str) -> Diagnostic {
+        let (line, col) = self.line_col(idx);
+        Diagnostic {
+            span: Span::single(idx),
+            line,
+            col,
+            message: message.to_owned(),
+        }
+    }
+
+    /// Every optimized statement next to the source code it was created from, innermost
+    /// statements after their loop. Empty if the document doesn't parse.
+    pub fn optimization_trace(&self) -> Vec<(Span, String)> {
+        let alloc = Bump::new();
+        let ast = match parse::parse_bytes(&alloc, self.src.as_bytes()) {
+            Ok(ast) => ast,
+            Err(_) => return Vec::new(),
+        };
+        let hir = hir::optimized_hir(&alloc, &ast);
+
+        let mut trace = Vec::new();
+        trace_stmts(&hir, &mut trace);
+        trace
+    }
+
+    /// The innermost optimized statement at the byte `offset`
+    pub fn hover(&self, offset: usize) -> Option<Hover> {
+        self.optimization_trace()
+            .into_iter()
+            .filter(|(span, _)| span.contains(offset))
+            .min_by_key(|(span, _)| span.len())
+            .map(|(span, text)| Hover { span, text })
+    }
+}
+
+fn trace_stmts(hir: &Hir<'_>, trace: &mut Vec<(Span, String)>) {
+    for stmt in &hir.stmts {
+        match stmt.kind() {
+            StmtKind::Loop(body) => {
+                trace.push((stmt.span, "Loop".to_owned()));
+                trace_stmts(body, trace);
+            }
+            kind => trace.push((stmt.span, format!("{kind:?}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Document;
+
+    #[test]
+    fn diagnostics_with_line_col() {
+        let doc = Document::new("+[\n>[-]\n]]\n[".to_owned());
+
+        let diagnostics = doc.diagnostics();
+        let positions = diagnostics
+            .iter()
+            .map(|d| (d.line, d.col, d.message.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            positions,
+            [
+                (2, 1, "Unmatched closing bracket"),
+                (3, 0, "Unclosed opening bracket")
+            ]
+        );
+    }
+
+    #[test]
+    fn edit_updates_diagnostics() {
+        let mut doc = Document::new("+[\n>".to_owned());
+        assert_eq!(doc.diagnostics().len(), 1);
+
+        doc.edit(4..4, "]");
+        assert_eq!(doc.diagnostics(), []);
+    }
+
+    #[test]
+    fn hover() {
+        let doc = Document::new("+++ clear: [-]".to_owned());
+
+        let hover = doc.hover(12).unwrap();
+        assert_eq!(hover.text, "SetN(0)");
+        assert_eq!((hover.span.start(), hover.span.end()), (11, 14));
+
+        assert_eq!(doc.hover(1).unwrap().text, "Add(0, 3)");
+        assert_eq!(doc.hover(5), None);
+    }
+}
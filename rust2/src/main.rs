@@ -24,8 +24,8 @@ fn main() {
         process::exit(1);
     });
 
-    brainfuck::run(&src, stdout, stdin, &args).unwrap_or_else(|_| {
-        eprintln!("error: Failed to parse brainfuck code");
+    brainfuck::run(&src, stdout, stdin, &args).unwrap_or_else(|err| {
+        eprintln!("error: {err}");
         process::exit(1);
     });
 }
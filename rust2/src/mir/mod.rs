@@ -24,6 +24,7 @@ use bumpalo::Bump;
 
 use crate::{
     hir::{Hir, StmtKind as HirStmtKind},
+    lir::{Lir, Stmt as LirStmt},
     mir::state::{MemoryState, Store},
     parse::Span,
     BumpVec,
@@ -66,12 +67,23 @@ enum StmtKind<'mir> {
         store_set_null: Store,
         store_move: Store,
     },
+    /// Like `MoveAddTo`, but the cell at `offset` is overwritten instead of added to. Unlike
+    /// `MoveAddTo`, it also writes to it if the current cell is zero.
+    MoveTo {
+        offset: Offset,
+        store_set_null: Store,
+        store_move: Store,
+    },
     /// Left or Right pointer move (`<>`)
     PointerMove(Offset),
     Loop(Mir<'mir>),
     Out,
+    /// Writes a constant string, like a sequence of `Out`s whose cells are known
+    OutBytes(BumpVec<'mir, u8>),
     In(Store),
     SetN(u8, Store),
+    /// Dumps the cells around the pointer, so it reads all of them
+    Debug,
 }
 
 #[tracing::instrument(skip(alloc, hir))]
@@ -84,7 +96,12 @@ pub fn optimized_mir<'mir>(alloc: &'mir Bump, hir: &Hir<'_>) -> Mir<'mir> {
 /// compiles hir down to a minimal mir
 fn hir_to_mir<'mir>(alloc: &'mir Bump, hir: &Hir<'_>) -> Mir<'mir> {
     let mut stmts = Vec::new_in(alloc);
-    let iter = hir.stmts.iter().map(|hir_stmt| {
+    for hir_stmt in &hir.stmts {
+        let stmt = |kind| Stmt {
+            kind,
+            span: hir_stmt.span,
+            state: MemoryState::empty(alloc),
+        };
         let kind = match *hir_stmt.kind() {
             HirStmtKind::Add(offset, n) => StmtKind::AddSub {
                 offset,
@@ -101,20 +118,214 @@ fn hir_to_mir<'mir>(alloc: &'mir Bump, hir: &Hir<'_>) -> Mir<'mir> {
                 store_set_null: Store::dead(),
                 store_move: Store::dead(),
             },
+            HirStmtKind::MoveSubTo { offset } => {
+                let body = [(0, -1), (offset, -1)].map(|(offset, n)| StmtKind::AddSub {
+                    offset,
+                    n,
+                    store: Store::dead(),
+                });
+                original_loop(alloc, body, hir_stmt.span)
+            }
+            HirStmtKind::MoveMulTo { ref targets } => {
+                let sub = (0, -1);
+                let adds = targets
+                    .iter()
+                    .map(|&(offset, factor)| (offset, i16::from(factor)));
+                let body = std::iter::once(sub)
+                    .chain(adds)
+                    .map(|(offset, n)| StmtKind::AddSub {
+                        offset,
+                        n,
+                        store: Store::dead(),
+                    });
+                original_loop(alloc, body, hir_stmt.span)
+            }
             HirStmtKind::Right(n) => StmtKind::PointerMove(i32::try_from(n).unwrap()),
             HirStmtKind::Left(n) => StmtKind::PointerMove(-i32::try_from(n).unwrap()),
+            HirStmtKind::ScanRight(n) => {
+                let body = [StmtKind::PointerMove(i32::try_from(n).unwrap())];
+                original_loop(alloc, body, hir_stmt.span)
+            }
+            HirStmtKind::ScanLeft(n) => {
+                let body = [StmtKind::PointerMove(-i32::try_from(n).unwrap())];
+                original_loop(alloc, body, hir_stmt.span)
+            }
             HirStmtKind::Loop(ref body) => StmtKind::Loop(hir_to_mir(alloc, body)),
             HirStmtKind::Out => StmtKind::Out,
+            HirStmtKind::OutConst(n) => {
+                let mut bytes = Vec::new_in(alloc);
+                bytes.push(n);
+                StmtKind::OutBytes(bytes)
+            }
+            HirStmtKind::OutRepeat { byte, count } => {
+                let mut bytes = Vec::new_in(alloc);
+                bytes.resize(count, byte);
+                StmtKind::OutBytes(bytes)
+            }
             HirStmtKind::In => StmtKind::In(Store::dead()),
             HirStmtKind::SetN(n) => StmtKind::SetN(n, Store::dead()),
+            // MIR only sets the current cell, so this goes back to the moves around the `SetN`
+            HirStmtKind::SetNOffset { offset, n } => {
+                stmts.extend(
+                    [
+                        StmtKind::PointerMove(offset),
+                        StmtKind::SetN(n, Store::dead()),
+                        StmtKind::PointerMove(-offset),
+                    ]
+                    .map(stmt),
+                );
+                continue;
+            }
+            HirStmtKind::Debug => StmtKind::Debug,
+            HirStmtKind::Nop => continue,
         };
-        Stmt {
-            kind,
-            span: hir_stmt.span,
-            state: MemoryState::empty(alloc),
-        }
-    });
-    stmts.extend(iter);
+        stmts.push(stmt(kind));
+    }
 
     Mir { stmts }
 }
+
+/// MIR can't multiply, subtract cells or scan yet, so `MoveSubTo`, `MoveMulTo` and the scans go
+/// back to the loops they came from
+fn original_loop<'mir>(
+    alloc: &'mir Bump,
+    body: impl IntoIterator<Item = StmtKind<'mir>>,
+    span: Span,
+) -> StmtKind<'mir> {
+    let mut stmts = Vec::new_in(alloc);
+    stmts.extend(body.into_iter().map(|kind| Stmt {
+        kind,
+        span,
+        state: MemoryState::empty(alloc),
+    }));
+    StmtKind::Loop(Mir { stmts })
+}
+
+/// lowers the MIR down to flat LIR, the same way `lir::generate` does for HIR
+pub fn lower_to_lir<'lir>(alloc: &'lir Bump, mir: &Mir<'_>) -> Lir<'lir> {
+    let mut lir = Lir::new_in(alloc);
+    mir_to_lir(&mut lir, mir, 0);
+    lir.push(LirStmt::End, Span::default(), 0);
+    lir
+}
+
+fn mir_to_lir(lir: &mut Lir<'_>, mir: &Mir<'_>, depth: u32) {
+    for stmt in &mir.stmts {
+        let lir_stmt = match stmt.kind {
+            StmtKind::AddSub { offset: 0, n, .. } if n >= 0 => {
+                LirStmt::Add(u8::try_from(n).unwrap())
+            }
+            StmtKind::AddSub { offset: 0, n, .. } => LirStmt::Sub(u8::try_from(-n).unwrap()),
+            StmtKind::AddSub { offset, n, .. } if n >= 0 => LirStmt::AddOffset {
+                offset,
+                n: u8::try_from(n).unwrap(),
+            },
+            StmtKind::AddSub { offset, n, .. } => LirStmt::SubOffset {
+                offset,
+                n: u8::try_from(-n).unwrap(),
+            },
+            StmtKind::MoveAddTo { offset, .. } => LirStmt::MoveAddTo { offset },
+            // the cell at the offset is known to be zero already, so adding to it is the same
+            StmtKind::MoveTo { offset, .. } => LirStmt::MoveAddTo { offset },
+            StmtKind::PointerMove(n) if n >= 0 => LirStmt::Right(n.unsigned_abs()),
+            StmtKind::PointerMove(n) => LirStmt::Left(n.unsigned_abs()),
+            StmtKind::Loop(ref body) => {
+                let skip_jmp_idx = lir.push(LirStmt::JmpIfZero(0), stmt.span, depth); // placeholder
+                mir_to_lir(lir, body, depth + 1);
+                let first_loop_body_idx = skip_jmp_idx + 1;
+                let jmp_back_idx = lir.push(
+                    LirStmt::JmpIfNonZero(first_loop_body_idx.try_into().unwrap()),
+                    stmt.span,
+                    depth + 1,
+                );
+                let after_loop_idx = jmp_back_idx + 1;
+                lir.set(
+                    skip_jmp_idx,
+                    LirStmt::JmpIfZero(after_loop_idx.try_into().unwrap()),
+                );
+                continue;
+            }
+            StmtKind::Out => LirStmt::Out,
+            StmtKind::OutBytes(ref bytes) => {
+                lir.push_out_bytes(bytes, stmt.span, depth);
+                continue;
+            }
+            StmtKind::In(_) => LirStmt::In,
+            StmtKind::SetN(n, _) => LirStmt::SetN(n),
+            StmtKind::Debug => LirStmt::Debug,
+        };
+        lir.push(lir_stmt, stmt.span, depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::lir::{interpreter::run_to_vec, Lir};
+
+    fn run(lir: &Lir<'_>) -> Vec<u8> {
+        run_to_vec(lir, &[]).unwrap()
+    }
+
+    /// Runs the program through both the `hir -> lir` and `hir -> mir -> lir` pipelines and makes
+    /// sure they don't drift apart
+    fn assert_same_output(bf: &str) {
+        let alloc = Bump::new();
+        let ast = crate::parse::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(&alloc, &ast);
+
+        let hir_lir = crate::lir::generate(&alloc, &hir);
+        let mir = super::optimized_mir(&alloc, &hir);
+        let mir_lir = super::lower_to_lir(&alloc, &mir);
+
+        assert_eq!(run(&hir_lir), run(&mir_lir));
+    }
+
+    #[test]
+    fn bench() {
+        assert_same_output(include_str!("../../benches/bench.bf"));
+    }
+
+    #[test]
+    fn bottles() {
+        assert_same_output(include_str!("../../benches/bottles.bf"));
+    }
+
+    #[test]
+    fn fizzbuzz() {
+        assert_same_output(include_str!("../../benches/fizzbuzz.bf"));
+    }
+
+    #[test]
+    fn hanoi() {
+        assert_same_output(include_str!("../../benches/hanoi.bf"));
+    }
+
+    #[test]
+    fn mandelbrot() {
+        assert_same_output(include_str!("../../benches/mandelbrot.bf"));
+    }
+
+    #[test]
+    fn constant_prefix_restores_tape() {
+        // the loop with the `,` is never entered, but it stops the folding, so the cells written
+        // before have to be restored
+        assert_same_output("++++[>++<-]>.>+<<[,]>>.");
+    }
+
+    #[test]
+    fn const_output() {
+        assert_same_output(",[-]++++++++[>++++++++<-]>+.+.<+++.>>[-]++.<<[-].");
+    }
+
+    #[test]
+    fn redundant_loop_set_n() {
+        assert_same_output("+++++>[-]+++<[>[-]+++.<-]");
+    }
+
+    #[test]
+    fn twinkle() {
+        assert_same_output(include_str!("../../benches/twinkle.bf"));
+    }
+}
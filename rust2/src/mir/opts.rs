@@ -1,20 +1,177 @@
-use std::collections::{hash_map::Entry, HashMap};
+use std::collections::HashMap;
 
 use bumpalo::Bump;
 use tracing::info;
 
-use crate::mir::{
-    state::{CellState, MemoryState, MemoryStateChange, Store},
-    Mir, Offset, StmtKind,
+use crate::{
+    lir::interpreter::MEM_SIZE,
+    mir::{
+        state::{CellState, MemoryState, MemoryStateChange, Store},
+        Mir, Offset, Stmt, StmtKind,
+    },
 };
 
+/// the most statements the constant folding of the program start executes, so that compiling a
+/// program that runs for a long time without reading input doesn't take forever
+const MAX_FOLD_STEPS: u64 = 1_000_000;
+
 /// this pass fills out as much state info for all statements as possible
 #[tracing::instrument(skip(alloc, mir))]
 pub fn passes<'mir>(alloc: &'mir Bump, mir: &mut Mir<'mir>) {
+    pass_fold_constant_prefix(alloc, mir);
     pass_fill_state_info(alloc, mir);
+    pass_remove_redundant_loop_set_n(mir);
+    pass_move_to(mir);
+    pass_remove_dead_loops(mir);
     pass_const_propagation(mir);
+    pass_const_output(alloc, mir);
+    pass_hoist_loop_set_n(alloc, mir);
     pass_dead_store_elimination(mir);
+    // the passes above replaced and removed statements, the states have to match the final ones
+    pass_fill_state_info(alloc, mir);
+}
+/// pass that runs the start of the program at compile time, up to the first statement that reads
+/// input. The output of that part is replaced by a single `OutBytes`, and the tape it leaves
+/// behind is recreated with stores. Programs without any input, like hello world, are folded into
+/// nothing but their output.
+#[tracing::instrument(skip(alloc, mir))]
+fn pass_fold_constant_prefix<'mir>(alloc: &'mir Bump, mir: &mut Mir<'mir>) {
+    let mut eval = ConstEval::default();
+    let mut folded = 0;
+    for stmt in &mir.stmts {
+        if reads_input(stmt) {
+            break;
+        }
+        let before = eval.clone();
+        let in_bounds = |eval: &ConstEval| (0..MEM_SIZE as i64).contains(&eval.ptr);
+        if eval.stmt(stmt).is_none() || !in_bounds(&eval) {
+            eval = before;
+            break;
+        }
+        folded += 1;
+    }
+
+    let has_work = mir.stmts[..folded]
+        .iter()
+        .any(|stmt| matches!(stmt.kind, StmtKind::Loop(_) | StmtKind::Out));
+    if !has_work {
+        return;
+    }
+
+    info!(?folded, output = ?eval.out.escape_ascii().to_string(), "Folding constant prefix");
+    let span = mir.stmts[0].span.merge(mir.stmts[folded - 1].span);
+    let stmt = |kind| Stmt {
+        kind,
+        span,
+        state: MemoryState::empty(alloc),
+    };
+
+    let mut replacement = Vec::new();
+    if !eval.out.is_empty() {
+        let mut bytes = Vec::new_in(alloc);
+        bytes.extend_from_slice(&eval.out);
+        replacement.push(stmt(StmtKind::OutBytes(bytes)));
+    }
+    // the tape doesn't matter anymore if the program ends here
+    if folded < mir.stmts.len() {
+        for (idx, &value) in eval.tape.iter().enumerate() {
+            if value != 0 {
+                replacement.push(stmt(StmtKind::AddSub {
+                    offset: Offset::try_from(idx).unwrap(),
+                    n: i16::from(value),
+                    store: Store::dead(),
+                }));
+            }
+        }
+        if eval.ptr != 0 {
+            let ptr = Offset::try_from(eval.ptr).unwrap();
+            replacement.push(stmt(StmtKind::PointerMove(ptr)));
+        }
+    }
+
+    mir.stmts.splice(..folded, replacement);
+}
+
+fn reads_input(stmt: &Stmt<'_>) -> bool {
+    match &stmt.kind {
+        StmtKind::In(_) => true,
+        StmtKind::Loop(body) => body.stmts.iter().any(reads_input),
+        _ => false,
+    }
+}
+
+/// Runs MIR on a tape that starts out zeroed, for the parts of programs that don't read input
+#[derive(Debug, Clone, Default)]
+struct ConstEval {
+    /// only grows as far as it was used
+    tape: Vec<u8>,
+    ptr: i64,
+    out: Vec<u8>,
+    steps: u64,
+}
+
+impl ConstEval {
+    /// Returns `None` if the statement can't be run at compile time
+    fn stmt(&mut self, stmt: &Stmt<'_>) -> Option<()> {
+        self.step()?;
+        match &stmt.kind {
+            StmtKind::AddSub { offset, n, .. } => {
+                let cell = self.cell(*offset)?;
+                *cell = u8::try_from((i16::from(*cell) + n).rem_euclid(256)).unwrap();
+            }
+            StmtKind::MoveAddTo { offset, .. } => {
+                let value = *self.cell(0)?;
+                if value != 0 {
+                    *self.cell(0)? = 0;
+                    let dest = self.cell(*offset)?;
+                    *dest = dest.wrapping_add(value);
+                }
+            }
+            StmtKind::MoveTo { offset, .. } => {
+                let value = *self.cell(0)?;
+                *self.cell(0)? = 0;
+                *self.cell(*offset)? = value;
+            }
+            StmtKind::PointerMove(n) => self.ptr += i64::from(*n),
+            StmtKind::Loop(body) => {
+                while *self.cell(0)? != 0 {
+                    // an empty loop would never take a step otherwise
+                    self.step()?;
+                    for stmt in &body.stmts {
+                        self.stmt(stmt)?;
+                    }
+                }
+            }
+            StmtKind::Out => {
+                let value = *self.cell(0)?;
+                self.out.push(value);
+            }
+            StmtKind::OutBytes(bytes) => self.out.extend_from_slice(bytes),
+            // the dump is left to the runtime, it depends on how the tape is shown
+            StmtKind::In(_) | StmtKind::Debug => return None,
+            StmtKind::SetN(n, _) => *self.cell(0)? = *n,
+        }
+        Some(())
+    }
+
+    fn step(&mut self) -> Option<()> {
+        self.steps += 1;
+        (self.steps <= MAX_FOLD_STEPS).then(|| ())
+    }
+
+    /// The pointer wrapping around the tape isn't supported
+    fn cell(&mut self, offset: Offset) -> Option<&mut u8> {
+        let idx = usize::try_from(self.ptr + i64::from(offset)).ok()?;
+        if idx >= MEM_SIZE {
+            return None;
+        }
+        if idx >= self.tape.len() {
+            self.tape.resize(idx + 1, 0);
+        }
+        Some(&mut self.tape[idx])
+    }
 }
+
 /// this pass fills out as much state info for all statements as possible
 #[tracing::instrument(skip(alloc, mir))]
 pub fn pass_fill_state_info<'mir>(alloc: &'mir Bump, mir: &mut Mir<'mir>) {
@@ -35,7 +192,7 @@ fn pass_fill_state_info_inner<'mir>(
                 let prev_state = outer.state_for_offset(*offset);
                 let new_state = match prev_state {
                     CellState::WrittenToKnown(_, prev_n) => {
-                        let n = i16::from(prev_n).wrapping_add(*n);
+                        let n = (i16::from(prev_n) + *n).rem_euclid(256);
                         let n = u8::try_from(n).unwrap();
                         CellState::WrittenToKnown(store.clone(), n)
                     }
@@ -54,18 +211,35 @@ fn pass_fill_state_info_inner<'mir>(
                 offset,
                 store_set_null,
                 store_move,
-            } => MemoryState::double(
-                alloc,
-                outer,
-                MemoryStateChange::Change {
-                    offset: 0,
-                    new_state: CellState::WrittenToKnown(store_set_null.clone(), 0),
-                },
-                MemoryStateChange::Change {
-                    offset: *offset,
-                    new_state: CellState::WrittenToUnknown(store_move.clone()),
-                },
-            ),
+            }
+            | StmtKind::MoveTo {
+                offset,
+                store_set_null,
+                store_move,
+            } => {
+                // the destination ends up with the sum of both cells, so it is only nonzero if
+                // one of them was
+                let source = outer.state_for_offset(0).known_value();
+                let dest = outer.state_for_offset(*offset).known_value();
+                let new_state = match (source, dest) {
+                    (Some(source), Some(dest)) => {
+                        CellState::WrittenToKnown(store_move.clone(), source.wrapping_add(dest))
+                    }
+                    _ => CellState::WrittenToUnknown(store_move.clone()),
+                };
+                MemoryState::double(
+                    alloc,
+                    outer,
+                    MemoryStateChange::Change {
+                        offset: 0,
+                        new_state: CellState::WrittenToKnown(store_set_null.clone(), 0),
+                    },
+                    MemoryStateChange::Change {
+                        offset: *offset,
+                        new_state,
+                    },
+                )
+            }
             StmtKind::PointerMove(n) => {
                 MemoryState::single(alloc, outer, MemoryStateChange::Move(*n))
             }
@@ -84,7 +258,7 @@ fn pass_fill_state_info_inner<'mir>(
                     },
                 )
             }
-            StmtKind::Out => outer,
+            StmtKind::Out | StmtKind::OutBytes(_) | StmtKind::Debug => outer,
             StmtKind::In(store) => MemoryState::single(
                 alloc,
                 outer,
@@ -107,85 +281,355 @@ fn pass_fill_state_info_inner<'mir>(
     }
 }
 
-/// This pass eliminates dead stores. It should probably be run multiple times between other passes
-/// for cleanup
+/// pass that removes a `SetN(k)` at the start of a loop body if the cell already contains `k`
+/// before the loop and nothing else in the loop writes to it. The cell then contains `k` at the
+/// start of every iteration, so setting it again is redundant.
 #[tracing::instrument(skip(mir))]
-fn pass_dead_store_elimination(mir: &mut Mir<'_>) {
-    pass_dead_store_elimination_mark_dead_stores(mir)
+fn pass_remove_redundant_loop_set_n(mir: &mut Mir<'_>) {
+    pass_remove_redundant_loop_set_n_inner(mir)
 }
 
-#[tracing::instrument(skip(mir))]
-fn pass_dead_store_elimination_mark_dead_stores(mir: &Mir<'_>) {
-    fn mark_store(
-        potential_dead_stores: &mut HashMap<Offset, Store>,
-        offset: Offset,
-        store: &Store,
-    ) {
-        match potential_dead_stores.entry(offset) {
-            Entry::Occupied(mut entry) => {
-                let old = entry.insert(store.clone());
-                if old.is_maybe_dead() {
-                    // it's certainly dead
-                    info!("We have a dead one!!!");
-                    old.mark_dead();
-                } else {
-                    // it's alive and well, drop it and keep it marked alive
-                    drop(old);
-                }
+fn pass_remove_redundant_loop_set_n_inner(mir: &mut Mir<'_>) {
+    let mut prev_state = None;
+    for stmt in &mut mir.stmts {
+        if let StmtKind::Loop(body) = &mut stmt.kind {
+            if let Some(before_loop) = &prev_state {
+                remove_redundant_loop_set_n(body, before_loop);
             }
-            Entry::Vacant(entry) => {
-                entry.insert(store.clone());
+            pass_remove_redundant_loop_set_n_inner(body);
+        }
+        prev_state = Some(stmt.state.clone());
+    }
+}
+
+fn remove_redundant_loop_set_n(body: &mut Mir<'_>, before_loop: &MemoryState<'_>) {
+    let Some((set_n_idx, offset, value)) = invariant_loop_set_n(body) else {
+        return;
+    };
+
+    let known_before = matches!(
+        before_loop.state_for_offset(offset),
+        CellState::WrittenToKnown(_, known) if known == value
+    );
+    if !known_before {
+        return;
+    }
+
+    info!(?offset, ?value, "Removing redundant SetN in loop");
+    body.stmts.remove(set_n_idx);
+}
+
+/// A `SetN` at the start of the loop body that sets the same cell to the same value in every
+/// iteration, as `(index, offset, value)`. Only pointer moves and constant output may come
+/// before it, anything else could read the old value, and nothing else may write to the cell.
+fn invariant_loop_set_n(body: &Mir<'_>) -> Option<(usize, Offset, u8)> {
+    let mut offset = 0;
+    for (idx, stmt) in body.stmts.iter().enumerate() {
+        match stmt.kind {
+            StmtKind::PointerMove(n) => offset += n,
+            StmtKind::OutBytes(_) => {}
+            StmtKind::SetN(value, _) => {
+                return is_only_write_to(body, idx, offset).then(|| (idx, offset, value));
             }
+            _ => return None,
         }
     }
+    None
+}
 
-    let mut potential_dead_stores = HashMap::new();
-    let mut current_offset = 0;
+/// pass that moves a `SetN(k)` at the start of a loop body in front of the loop, if the loop is
+/// known to be entered and nothing else in the loop writes to the cell, like the `[-]` in
+/// `,[-]+[>[-]<.]`. The cell contains `k` in every iteration and after the loop just like before,
+/// but it is only set once.
+///
+/// Loops that might not be entered keep their `SetN`, setting the cell in front of them would
+/// change it even when they are skipped. So does a `SetN` of the current cell, which the loop
+/// checks before every iteration, like the `[-]` in `,[-]+[[-].]`. If the cell is already `k`
+/// before the loop, `pass_remove_redundant_loop_set_n` has removed the `SetN` already.
+#[tracing::instrument(skip(alloc, mir))]
+fn pass_hoist_loop_set_n<'mir>(alloc: &'mir Bump, mir: &mut Mir<'mir>) {
+    pass_hoist_loop_set_n_inner(alloc, mir)
+}
 
-    for stmt in &mir.stmts {
-        match &stmt.kind {
-            StmtKind::AddSub { store, offset, .. } => {
-                mark_store(&mut potential_dead_stores, current_offset + offset, store);
+fn pass_hoist_loop_set_n_inner<'mir>(alloc: &'mir Bump, mir: &mut Mir<'mir>) {
+    let mut prev_state: Option<MemoryState<'_>> = None;
+    let mut idx = 0;
+    while idx < mir.stmts.len() {
+        let is_entered = prev_state.as_ref().map_or(
+            false,
+            |state| matches!(state.state_for_offset(0).known_value(), Some(value) if value != 0),
+        );
+        let stmt = &mut mir.stmts[idx];
+        prev_state = Some(stmt.state.clone());
+        let StmtKind::Loop(body) = &mut stmt.kind else {
+            idx += 1;
+            continue;
+        };
+
+        let hoisted = match invariant_loop_set_n(body) {
+            Some((set_n_idx, offset, value)) if is_entered && offset != 0 => {
+                info!(?offset, ?value, "Hoisting SetN out of loop");
+                Some((offset, body.stmts.remove(set_n_idx)))
+            }
+            _ => None,
+        };
+        pass_hoist_loop_set_n_inner(alloc, body);
+
+        if let Some((offset, set_n)) = hoisted {
+            let span = set_n.span;
+            let pointer_move = |n| Stmt {
+                kind: StmtKind::PointerMove(n),
+                span,
+                state: MemoryState::empty(alloc),
+            };
+            let mut before_loop = Vec::new();
+            if offset != 0 {
+                before_loop.push(pointer_move(offset));
             }
+            before_loop.push(set_n);
+            if offset != 0 {
+                before_loop.push(pointer_move(-offset));
+            }
+            let inserted = before_loop.len();
+            mir.stmts.splice(idx..idx, before_loop);
+            idx += inserted;
+        }
+        idx += 1;
+    }
+}
+
+/// whether the statement at `write_idx` is the only one in the body writing to the cell at
+/// `offset` and the body leaves the pointer where it found it, so that the cell is the same one
+/// in every iteration
+fn is_only_write_to(body: &Mir<'_>, write_idx: usize, offset: Offset) -> bool {
+    let mut pos = 0;
+    for (idx, stmt) in body.stmts.iter().enumerate() {
+        let writes_to_offset = match stmt.kind {
+            StmtKind::AddSub { offset: o, .. } => pos + o == offset,
+            StmtKind::MoveAddTo { offset: o, .. } | StmtKind::MoveTo { offset: o, .. } => {
+                pos == offset || pos + o == offset
+            }
+            StmtKind::PointerMove(n) => {
+                pos += n;
+                false
+            }
+            // the loop is opaque, it might write to anything or move the pointer
+            StmtKind::Loop(_) => return false,
+            StmtKind::Out | StmtKind::OutBytes(_) | StmtKind::Debug => false,
+            StmtKind::In(_) | StmtKind::SetN(..) => pos == offset,
+        };
+        if writes_to_offset && idx != write_idx {
+            return false;
+        }
+    }
+    pos == 0
+}
+
+/// pass that replaces a `MoveAddTo` into a cell that is known to be zero with a `MoveTo`, like
+/// for `>[-]<[->+<]`, where adding to the cleared cell is just moving the value there
+#[tracing::instrument(skip(mir))]
+fn pass_move_to(mir: &mut Mir<'_>) {
+    pass_move_to_inner(mir)
+}
+
+fn pass_move_to_inner(mir: &mut Mir<'_>) {
+    let mut prev_state = None;
+    for stmt in &mut mir.stmts {
+        match &mut stmt.kind {
             StmtKind::MoveAddTo {
                 offset,
-                store_move,
                 store_set_null,
+                store_move,
             } => {
-                mark_store(&mut potential_dead_stores, current_offset, store_set_null);
-                mark_store(
-                    &mut potential_dead_stores,
-                    current_offset + offset,
-                    store_move,
-                );
+                let target_is_zero =
+                    prev_state
+                        .as_ref()
+                        .map_or(false, |state: &MemoryState<'_>| {
+                            matches!(
+                                state.state_for_offset(*offset),
+                                CellState::WrittenToKnown(_, 0)
+                            )
+                        });
+                if target_is_zero {
+                    info!(?offset, "Replacing MoveAddTo with MoveTo");
+                    stmt.kind = StmtKind::MoveTo {
+                        offset: *offset,
+                        store_set_null: store_set_null.clone(),
+                        store_move: store_move.clone(),
+                    };
+                }
             }
-            StmtKind::PointerMove(offset) => {
-                current_offset += offset; // ???
+            StmtKind::Loop(body) => pass_move_to_inner(body),
+            _ => {}
+        }
+        prev_state = Some(stmt.state.clone());
+    }
+}
+
+/// pass that removes loops on a cell that is known to be zero before the loop, since they are
+/// never entered, like the second loop in `[-]>[-]<[->+<]>[.-]`
+#[tracing::instrument(skip(mir))]
+fn pass_remove_dead_loops(mir: &mut Mir<'_>) {
+    pass_remove_dead_loops_inner(mir)
+}
+
+fn pass_remove_dead_loops_inner(mir: &mut Mir<'_>) {
+    let mut prev_state: Option<MemoryState<'_>> = None;
+    let mut idx = 0;
+    while idx < mir.stmts.len() {
+        let stmt = &mut mir.stmts[idx];
+        if let StmtKind::Loop(body) = &mut stmt.kind {
+            let is_dead = prev_state.as_ref().map_or(false, |state| {
+                state.state_for_offset(0).known_value() == Some(0)
+            });
+            if is_dead {
+                info!(span = ?stmt.span, "Removing dead loop");
+                // the state of the next statement is still derived from the state after the
+                // loop, which knows less than it could, but is still correct
+                mir.stmts.remove(idx);
+                continue;
             }
+            pass_remove_dead_loops_inner(body);
+        }
+        prev_state = Some(stmt.state.clone());
+        idx += 1;
+    }
+}
+
+/// pass that replaces every `Out` of a cell with a known value by an `OutBytes` of that value, like
+/// the `.`s in `,[-]++.+.`. Those are then merged into the `OutBytes` before them, so that
+/// programs printing constant banners write them all at once.
+///
+/// The bytes may only be moved before statements that don't interact with the outside. They stay
+/// behind loops, which might never terminate, and behind `In` and `Debug`. They also stay behind
+/// pointer moves and writes to other cells, which stop the program under `--strict-pointer` and
+/// `--bounds-check` when they leave the tape, so no output is written before the error. Running
+/// out of fuel can still happen a few writes to the current cell after the bytes were written.
+#[tracing::instrument(skip(alloc, mir))]
+fn pass_const_output<'mir>(alloc: &'mir Bump, mir: &mut Mir<'mir>) {
+    let stmts = std::mem::replace(&mut mir.stmts, Vec::new_in(alloc));
+    // the index of the `OutBytes` the next constant output can be merged into
+    let mut merge_into: Option<usize> = None;
+
+    for mut stmt in stmts {
+        let bytes = match &mut stmt.kind {
+            StmtKind::Out => match stmt.state.state_for_offset(0).known_value() {
+                Some(value) => {
+                    let mut bytes = Vec::new_in(alloc);
+                    bytes.push(value);
+                    Some(bytes)
+                }
+                None => {
+                    merge_into = None;
+                    None
+                }
+            },
+            StmtKind::OutBytes(bytes) => Some(bytes.clone()),
             StmtKind::Loop(body) => {
-                let store = potential_dead_stores.get(&current_offset);
-                if let Some(store) = store {
-                    store.add_load();
+                pass_const_output(alloc, body);
+                merge_into = None;
+                None
+            }
+            StmtKind::AddSub { offset: 0, .. } | StmtKind::SetN(..) => None,
+            StmtKind::AddSub { .. }
+            | StmtKind::MoveAddTo { .. }
+            | StmtKind::MoveTo { .. }
+            | StmtKind::PointerMove(_)
+            | StmtKind::In(_)
+            | StmtKind::Debug => {
+                merge_into = None;
+                None
+            }
+        };
+
+        if let Some(bytes) = bytes {
+            if let Some(idx) = merge_into {
+                let target = &mut mir.stmts[idx];
+                if let StmtKind::OutBytes(target_bytes) = &mut target.kind {
+                    target_bytes.extend_from_slice(&bytes);
                 }
+                target.span = target.span.merge(stmt.span);
+                continue;
+            }
+            stmt.kind = StmtKind::OutBytes(bytes);
+            merge_into = Some(mir.stmts.len());
+        }
 
-                pass_dead_store_elimination_mark_dead_stores(body);
+        mir.stmts.push(stmt);
+    }
+}
+
+/// pass that removes `AddSub` and `SetN` writes to a cell that is overwritten by a `SetN` before
+/// anything reads it, like the `++` in `,++[-].`
+///
+/// Loops are opaque, every write that is still pending before a loop might be read by it. The
+/// writes at the end of a loop body are read by the next iteration, and the ones at the end of
+/// the program stay as well, so that the tape is the same.
+#[tracing::instrument(skip(mir))]
+fn pass_dead_store_elimination(mir: &mut Mir<'_>) {
+    pass_dead_store_elimination_mark_dead_stores(mir);
+    pass_dead_store_elimination_remove_dead_stores(mir);
+}
+
+#[tracing::instrument(skip(mir))]
+fn pass_dead_store_elimination_mark_dead_stores(mir: &Mir<'_>) {
+    // the writes that nothing has read yet, by the offset of their cell from the start
+    let mut pending_stores: HashMap<Offset, Store> = HashMap::new();
+    let mut current_offset = 0;
+
+    let load = |pending_stores: &mut HashMap<Offset, Store>, offset| {
+        if let Some(store) = pending_stores.remove(&offset) {
+            store.add_load();
+        }
+    };
+    let clobber_all = |pending_stores: &mut HashMap<Offset, Store>| {
+        pending_stores
+            .drain()
+            .for_each(|(_, store)| store.clobber());
+    };
+
+    for stmt in &mir.stmts {
+        match &stmt.kind {
+            StmtKind::AddSub { store, offset, .. } => {
+                // adding reads the old value first
+                load(&mut pending_stores, current_offset + offset);
+                pending_stores.insert(current_offset + offset, store.clone());
             }
-            StmtKind::Out => {
-                let store = potential_dead_stores.get(&current_offset);
-                if let Some(store) = store {
-                    store.add_load();
+            StmtKind::SetN(_, store) => {
+                if let Some(old) = pending_stores.insert(current_offset, store.clone()) {
+                    info!(?current_offset, "Found dead store");
+                    old.mark_dead();
                 }
             }
-            StmtKind::In(store) | StmtKind::SetN(_, store) => {
-                mark_store(&mut potential_dead_stores, current_offset, store);
+            StmtKind::MoveAddTo { offset, .. } | StmtKind::MoveTo { offset, .. } => {
+                load(&mut pending_stores, current_offset);
+                load(&mut pending_stores, current_offset + offset);
             }
+            StmtKind::PointerMove(offset) => current_offset += offset,
+            StmtKind::Loop(body) => {
+                clobber_all(&mut pending_stores);
+                pass_dead_store_elimination_mark_dead_stores(body);
+            }
+            // the cell might be left unchanged at the end of the input
+            StmtKind::Out | StmtKind::In(_) => load(&mut pending_stores, current_offset),
+            StmtKind::OutBytes(_) => {}
+            // the dump shows the cells, so all of them are loaded
+            StmtKind::Debug => clobber_all(&mut pending_stores),
         }
+    }
+
+    clobber_all(&mut pending_stores);
+}
 
-        if stmt.state.has_forget_delta() {
-            // they might all have loads now
-            potential_dead_stores.values().for_each(Store::clobber);
+fn pass_dead_store_elimination_remove_dead_stores(mir: &mut Mir<'_>) {
+    mir.stmts.retain(|stmt| match &stmt.kind {
+        StmtKind::AddSub { store, .. } | StmtKind::SetN(_, store) => !store.is_dead(),
+        _ => true,
+    });
+    for stmt in &mut mir.stmts {
+        if let StmtKind::Loop(body) = &mut stmt.kind {
+            pass_dead_store_elimination_remove_dead_stores(body);
         }
-        info!(?potential_dead_stores, ?current_offset, "stores");
     }
 }
 
@@ -213,3 +657,239 @@ fn pass_const_propagation_inner(mir: &mut Mir<'_>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use bumpalo::Bump;
+
+    use crate::mir::{Mir, StmtKind};
+
+    /// Programs that should not be folded into their output at compile time start with `,`
+    fn optimized_mir<'mir>(alloc: &'mir Bump, bf: &str) -> Mir<'mir> {
+        let ast = crate::parse::parse(alloc, bf.bytes().enumerate()).unwrap();
+        let hir = crate::hir::optimized_hir(alloc, &ast);
+        crate::mir::optimized_mir(alloc, &hir)
+    }
+
+    fn loop_body<'a, 'mir>(mir: &'a Mir<'mir>) -> &'a Mir<'mir> {
+        mir.stmts
+            .iter()
+            .find_map(|stmt| match &stmt.kind {
+                StmtKind::Loop(body) => Some(body),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn redundant_loop_set_n_removed() {
+        let alloc = Bump::new();
+        // the neighbour is set to 3 before the loop and at the start of every iteration
+        let mir = optimized_mir(&alloc, ",>[-]+++<[>[-]+++.<-]");
+
+        let body = loop_body(&mir);
+        assert!(!body
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::SetN(..))));
+    }
+
+    #[test]
+    fn loop_set_n_hoisted() {
+        let alloc = Bump::new();
+        // the loop is always entered, so the neighbour can be cleared once before it
+        let mir = optimized_mir(&alloc, ",[-]+[>[-]<.]");
+
+        let body = loop_body(&mir);
+        assert!(!body
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::SetN(..))));
+        assert!(mir
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::SetN(0, _))));
+    }
+
+    #[test]
+    fn loop_set_n_not_hoisted() {
+        let alloc = Bump::new();
+        // the loop might be skipped, the cell read before it is set, or it is the one the loop
+        // checks
+        for bf in [",[>[-]<.]", ",[-]+[>.[-]<.]", ",[-]+[[-].]"] {
+            let mir = optimized_mir(&alloc, bf);
+
+            let body = loop_body(&mir);
+            assert!(
+                body.stmts
+                    .iter()
+                    .any(|stmt| matches!(stmt.kind, StmtKind::SetN(0, _))),
+                "{bf}"
+            );
+        }
+    }
+
+    #[test]
+    fn move_into_cleared_cell() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, ",>[-]<[->+<]>.");
+
+        assert!(mir
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::MoveTo { offset: 1, .. })));
+    }
+
+    #[test]
+    fn move_into_unknown_cell_kept() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, "+++>,<[->+<]>.");
+
+        assert!(mir
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::MoveAddTo { offset: 1, .. })));
+    }
+
+    fn contains_loop(mir: &Mir<'_>) -> bool {
+        mir.stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::Loop(_)))
+    }
+
+    #[test]
+    fn loop_on_moved_zero_removed() {
+        let alloc = Bump::new();
+        // both cells are zero, so the moved value is too
+        let mir = optimized_mir(&alloc, ",[-]>[-]<[->+<]>[.-]>.");
+
+        assert!(!contains_loop(&mir));
+    }
+
+    #[test]
+    fn loop_on_move_destination_kept() {
+        let alloc = Bump::new();
+        // the destination was zero before the move, but the moved value isn't
+        let mir = optimized_mir(&alloc, ",[-]+>[-]<[->+<]>[.-]>.");
+        assert!(contains_loop(&mir));
+
+        // the moved value is unknown
+        let mir = optimized_mir(&alloc, ",>[-]<[->+<]>[.-]>.");
+        assert!(contains_loop(&mir));
+    }
+
+    #[test]
+    fn hello_world_folded() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, HELLO_WORLD);
+
+        match &mir.stmts[..] {
+            [stmt] => match &stmt.kind {
+                StmtKind::OutBytes(bytes) => assert_eq!(bytes.as_slice(), b"Hello, World!"),
+                kind => panic!("expected OutBytes, found {kind:?}"),
+            },
+            stmts => panic!("expected a single statement, found {stmts:?}"),
+        }
+    }
+
+    const HELLO_WORLD: &str = "\
+        ++++++++[>+++++++++<-]>.+++++++++++++++++++++++++++++.+++++++..+++.-------------\
+        ------------------------------------------------------.------------.++++++++++++\
+        +++++++++++++++++++++++++++++++++++++++++++.++++++++++++++++++++++++.+++.------.\
+        --------.-------------------------------------------------------------------.";
+
+    fn contains_add_sub(mir: &Mir<'_>) -> bool {
+        mir.stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::AddSub { .. }))
+    }
+
+    #[test]
+    fn dead_store_removed() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, ",++[-].");
+        assert!(!contains_add_sub(&mir));
+    }
+
+    #[test]
+    fn read_store_kept() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, ",++.[-].");
+        assert!(contains_add_sub(&mir));
+
+        // the loop might read the cell
+        let mir = optimized_mir(&alloc, ",++>[<.>-]<[-].");
+        assert!(contains_add_sub(&mir));
+    }
+
+    #[test]
+    fn states_filled() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, ",[-]++.>,[.-]");
+
+        let [_, out, set_n, ..] = mir.stmts.as_slice() else {
+            panic!("{mir:?}");
+        };
+        assert!(matches!(&out.kind, StmtKind::OutBytes(bytes) if bytes.as_slice() == [2]));
+        assert!(matches!(set_n.kind, StmtKind::SetN(2, _)));
+        assert_eq!(set_n.state.state_for_offset(0).known_value(), Some(2));
+
+        insta::assert_debug_snapshot!(mir);
+    }
+
+    fn out_bytes<'a>(mir: &'a Mir<'_>) -> Vec<&'a [u8]> {
+        mir.stmts
+            .iter()
+            .filter_map(|stmt| match &stmt.kind {
+                StmtKind::OutBytes(bytes) => Some(bytes.as_slice()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn const_output_merged() {
+        let alloc = Bump::new();
+        let bf = format!(",[-]{}.+.+.", "+".repeat(65));
+        let mir = optimized_mir(&alloc, &bf);
+
+        assert_eq!(out_bytes(&mir), [b"ABC"]);
+        assert!(!mir
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::Out)));
+    }
+
+    #[test]
+    fn const_output_not_moved_before_input() {
+        let alloc = Bump::new();
+        let mir = optimized_mir(&alloc, ",.[-]+.,[-]++.");
+
+        assert!(matches!(mir.stmts[1].kind, StmtKind::Out));
+        assert_eq!(out_bytes(&mir), [[1], [2]]);
+    }
+
+    #[test]
+    fn const_output_not_moved_before_pointer_move() {
+        let alloc = Bump::new();
+        // the `>` might leave the tape under `--strict-pointer`
+        let bf = format!(",[-]{}.+.>+<+.", "+".repeat(65));
+        let mir = optimized_mir(&alloc, &bf);
+
+        assert_eq!(out_bytes(&mir), [&b"AB"[..], b"C"]);
+    }
+
+    #[test]
+    fn loop_set_n_modified_in_body_kept() {
+        let alloc = Bump::new();
+        // the `+` after the `.` changes the neighbour, so it has to be reset every iteration. it
+        // is folded into the `SetN` after the output is made constant.
+        let mir = optimized_mir(&alloc, ",>[-]+++<[>[-]+++.+<-]");
+
+        let body = loop_body(&mir);
+        assert!(body
+            .stmts
+            .iter()
+            .any(|stmt| matches!(stmt.kind, StmtKind::SetN(4, _))));
+    }
+}
@@ -24,6 +24,17 @@ pub enum CellState {
     WrittenToKnown(Store, u8),
 }
 
+impl CellState {
+    /// The value of the cell, if it is known
+    pub fn known_value(&self) -> Option<u8> {
+        match self {
+            CellState::LoopNull => Some(0),
+            CellState::WrittenToKnown(_, value) => Some(*value),
+            CellState::Unknown | CellState::WrittenToUnknown(_) => None,
+        }
+    }
+}
+
 /// A change in the known state of the memory caused by a single instruction
 #[derive(Debug, Clone)]
 pub enum MemoryStateChange {
@@ -113,13 +124,15 @@ struct MemoryStateInner<'mir> {
 impl<'mir> MemoryStateInner<'mir> {
     fn state_for_offset(&self, offset: Offset) -> CellState {
         let mut offset = offset;
-        for delta in &self.deltas {
+        // walk backwards in time, the last delta is the most recent one
+        for delta in self.deltas.iter().rev() {
             match delta {
                 MemoryStateChange::Change {
                     offset: write_offset,
                     new_state,
                 } if *write_offset == offset => return new_state.clone(),
-                MemoryStateChange::Move(change) => offset -= change,
+                // the cell that is at `offset` now was at `offset + change` before the move
+                MemoryStateChange::Move(change) => offset += change,
                 // we may not access the forbidden knowledge
                 MemoryStateChange::Forget => return CellState::Unknown,
                 _ => {}
@@ -158,8 +171,8 @@ impl Store {
         self.0.set(StoreInner { id: old.id, kind })
     }
 
-    pub fn is_maybe_dead(&self) -> bool {
-        matches!(self.inner().kind, StoreKind::Dead | StoreKind::Unknown)
+    pub fn is_dead(&self) -> bool {
+        matches!(self.inner().kind, StoreKind::Dead)
     }
 
     pub fn mark_dead(&self) {
@@ -1,10 +1,13 @@
 use std::{
     cmp,
-    fmt::{Debug, Formatter},
+    fmt::{Debug, Display, Formatter},
+    io::{BufReader, Read},
 };
 
 use bumpalo::Bump;
 
+use crate::BumpVec;
+
 #[derive(Clone, Copy, PartialEq, Eq, Default)]
 pub struct Span {
     start: u32,
@@ -12,7 +15,7 @@ pub struct Span {
 }
 
 impl Span {
-    fn single(idx: usize) -> Self {
+    pub(crate) fn single(idx: usize) -> Self {
         Self {
             start: idx.try_into().unwrap(),
             len: 1,
@@ -20,7 +23,7 @@ impl Span {
     }
 
     /// start..end
-    fn start_end(start: usize, end: usize) -> Span {
+    pub(crate) fn start_end(start: usize, end: usize) -> Span {
         Self {
             start: start.try_into().unwrap(),
             len: (end - start).try_into().unwrap(),
@@ -39,7 +42,7 @@ impl Span {
     pub fn until(&self, other: Self) -> Self {
         Self {
             start: self.start,
-            len: (other.start + other.len) - self.len,
+            len: (other.start + other.len) - self.start,
         }
     }
 
@@ -59,10 +62,52 @@ impl Span {
         self.len.try_into().unwrap()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
     /// ..end
     pub fn end(&self) -> usize {
         self.start() + self.len()
     }
+
+    /// Whether the byte at `offset` is part of the span, empty spans contain nothing
+    pub fn contains(&self, offset: usize) -> bool {
+        self.start() <= offset && offset < self.end()
+    }
+
+    /// Whether the spans share at least one byte. Spans that only touch don't overlap, and empty
+    /// spans don't overlap anything.
+    pub fn overlaps(&self, other: Span) -> bool {
+        !self.is_empty()
+            && !other.is_empty()
+            && self.start() < other.end()
+            && other.start() < self.end()
+    }
+
+    /// The one based line and column of the start of the span in `src`, the source code the span
+    /// was created from. Columns are counted in characters, and `\r\n` is a single line break.
+    pub fn line_col(&self, src: &str) -> (usize, usize) {
+        let before = &src.as_bytes()[..self.start()];
+        let line_start = before
+            .iter()
+            .rposition(|&byte| byte == b'\n')
+            .map_or(0, |idx| idx + 1);
+        let line = before.iter().filter(|&&byte| byte == b'\n').count() + 1;
+        // every character starts with a byte that isn't a UTF-8 continuation byte
+        let col = before[line_start..]
+            .iter()
+            .filter(|&&byte| byte & 0b1100_0000 != 0b1000_0000)
+            .count()
+            + 1;
+        (line, col)
+    }
+
+    /// The positions of the opening and closing bracket, if this is the span of an `Instr::Loop`.
+    /// Empty spans, like the ones of LIR that wasn't parsed from source code, have none.
+    pub fn loop_brackets(&self) -> Option<(usize, usize)> {
+        (!self.is_empty()).then(|| (self.start(), self.end() - 1))
+    }
 }
 
 impl Debug for Span {
@@ -82,74 +127,314 @@ pub enum Instr<'ast> {
     Out,
     In,
     Loop(Ast<'ast>),
+    /// `#`, dumps the cells around the pointer for debugging
+    Debug,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct ParseError;
+pub enum ParseError {
+    /// A `[` without a `]`
+    UnmatchedOpen {
+        /// the span of the innermost `[` that is never closed
+        span: Span,
+    },
+    /// A `]` without a `[`
+    UnmatchedClose {
+        /// the span of the `]`
+        span: Span,
+    },
+    /// The loops are nested deeper than the parser supports
+    TooDeeplyNested {
+        /// the span of the `[` that went over the limit
+        span: Span,
+        /// the amount of loops around the code after the `[`, including its own
+        depth: usize,
+    },
+}
+
+impl ParseError {
+    /// Where in the source code the error is
+    pub fn span(&self) -> Span {
+        match self {
+            Self::UnmatchedOpen { span }
+            | Self::UnmatchedClose { span }
+            | Self::TooDeeplyNested { span, .. } => *span,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnmatchedOpen { span } => {
+                write!(f, "Unmatched `[` at byte {}", span.start())
+            }
+            Self::UnmatchedClose { span } => {
+                write!(f, "Unmatched `]` at byte {}", span.start())
+            }
+            Self::TooDeeplyNested { span, depth } => write!(
+                f,
+                "Loops nested too deeply ({depth} levels) at byte {}",
+                span.start()
+            ),
+        }
+    }
+}
+
+/// Options for [`parse_with_options`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// The most loops that can be nested in each other, `None` for no limit. The parser doesn't
+    /// recurse, so even unlimited nesting doesn't overflow the stack.
+    pub max_depth: Option<u16>,
+    /// The bytes of the commands `+-><.,[]`, in this order, for dialects that use other
+    /// characters. All other bytes are comments, except for `#`, which stays a debug dump
+    /// unless it is one of the commands. If a byte is in here twice, the first command wins.
+    pub commands: [u8; 8],
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            max_depth: Some(1001),
+            commands: STANDARD_COMMANDS,
+        }
+    }
+}
+
+const STANDARD_COMMANDS: [u8; 8] = *b"+-><.,[]";
+
+/// Maps every byte to the standard command it stands for, `None` for comments
+fn command_table(commands: &[u8; 8]) -> [Option<u8>; 256] {
+    let mut table = [None; 256];
+    table[usize::from(b'#')] = Some(b'#');
+    // backwards, so that the first command for a byte overwrites the others
+    for (&byte, &standard) in commands.iter().zip(&STANDARD_COMMANDS).rev() {
+        table[usize::from(byte)] = Some(standard);
+    }
+    table
+}
+
+/// Parses the source code, the spans of the AST index into `src`
+///
+/// This is the entry point for code that is already in memory, like a file that was read or the
+/// input of a fuzzer. Any bytes that aren't commands are comments, so `src` doesn't have to be
+/// valid UTF-8.
+///
+/// ```
+/// let alloc = bumpalo::Bump::new();
+/// let ast = brainfuck::parse::parse_bytes(&alloc, b"+[-]>.").unwrap();
+/// assert_eq!(ast.len(), 4);
+/// ```
+pub fn parse_bytes<'ast>(alloc: &'ast Bump, src: &[u8]) -> Result<Ast<'ast>, ParseError> {
+    parse(alloc, src.iter().copied().enumerate())
+}
+
+/// Parses the code while it is read from `reader`, without reading all of it into memory first.
+/// The spans are the positions of the bytes in the stream.
+///
+/// The reader is buffered already, so it can be a `File` as is.
+pub fn parse_reader<R: Read>(alloc: &Bump, reader: R) -> Result<Ast<'_>, crate::Error> {
+    let mut io_error = None;
+    let bytes = BufReader::new(reader)
+        .bytes()
+        .map_while(|byte| byte.map_err(|err| io_error = Some(err)).ok());
+
+    let ast = parse(alloc, bytes.enumerate());
+    // the code is cut off at the error, so it doesn't matter whether it parsed
+    if let Some(err) = io_error {
+        return Err(err.into());
+    }
+    Ok(ast?)
+}
+
+/// Parses the code from an iterator of the bytes and their positions, for sources that aren't in
+/// one piece of memory. Most code should use [`parse_bytes`].
+pub fn parse<I>(alloc: &Bump, src: I) -> Result<Ast<'_>, ParseError>
+where
+    I: Iterator<Item = (usize, u8)>,
+{
+    parse_with_options(alloc, src, ParseOptions::default())
+}
 
-pub fn parse<I>(alloc: &Bump, mut src: I) -> Result<Ast<'_>, ParseError>
+pub fn parse_with_options<I>(
+    alloc: &Bump,
+    src: I,
+    opts: ParseOptions,
+) -> Result<Ast<'_>, ParseError>
 where
     I: Iterator<Item = (usize, u8)>,
 {
+    // the loops are kept on this stack instead of recursing for every `[`, so that deeply nested
+    // code can't overflow the native stack. the innermost open loop is at the end, with the index
+    // of its `[` and the body so far
+    let mut open_loops: BumpVec<'_, (usize, Ast<'_>)> = Vec::new_in(alloc);
     let mut instrs = Vec::new_in(alloc);
+    let commands = command_table(&opts.commands);
 
-    loop {
-        match src.next() {
-            Some((idx, b'+')) => instrs.push((Instr::Add, Span::single(idx))),
-            Some((idx, b'-')) => instrs.push((Instr::Sub, Span::single(idx))),
-            Some((idx, b'>')) => instrs.push((Instr::Right, Span::single(idx))),
-            Some((idx, b'<')) => instrs.push((Instr::Left, Span::single(idx))),
-            Some((idx, b'.')) => instrs.push((Instr::Out, Span::single(idx))),
-            Some((idx, b',')) => instrs.push((Instr::In, Span::single(idx))),
-            Some((idx, b'[')) => {
-                let (loop_instrs, span) = parse_loop(alloc, &mut src, 0, idx)?;
-                instrs.push((Instr::Loop(loop_instrs), span));
+    for (idx, byte) in src {
+        let Some(command) = commands[usize::from(byte)] else {
+            continue; // comment
+        };
+        let instr = match command {
+            b'+' => Instr::Add,
+            b'-' => Instr::Sub,
+            b'>' => Instr::Right,
+            b'<' => Instr::Left,
+            b'.' => Instr::Out,
+            b',' => Instr::In,
+            b'#' => Instr::Debug,
+            b'[' => {
+                let depth = open_loops.len() + 1;
+                if opts.max_depth.map_or(false, |max| depth > usize::from(max)) {
+                    return Err(ParseError::TooDeeplyNested {
+                        span: Span::single(idx),
+                        depth,
+                    });
+                }
+                let outer = std::mem::replace(&mut instrs, Vec::new_in(alloc));
+                open_loops.push((idx, outer));
+                continue;
             }
-            Some((_, b']')) => return Err(ParseError),
-            Some(_) => {} // comment
-            None => break,
-        }
+            b']' => {
+                let Some((start_idx, outer)) = open_loops.pop() else {
+                    return Err(ParseError::UnmatchedClose {
+                        span: Span::single(idx),
+                    });
+                };
+                let body = std::mem::replace(&mut instrs, outer);
+                instrs.push((Instr::Loop(body), Span::start_end_incl(start_idx, idx)));
+                continue;
+            }
+            _ => unreachable!("the command table only contains commands"),
+        };
+        instrs.push((instr, Span::single(idx)));
+    }
+
+    if let Some(&(start_idx, _)) = open_loops.last() {
+        return Err(ParseError::UnmatchedOpen {
+            span: Span::single(start_idx),
+        });
     }
 
     Ok(instrs)
 }
 
-fn parse_loop<'ast, I>(
-    alloc: &'ast Bump,
-    src: &mut I,
-    depth: u16,
-    start_idx: usize,
-) -> Result<(Ast<'ast>, Span), ParseError>
+/// Checks that the brackets in the code match, with the same errors as [`parse`], but without
+/// building the AST or allocating at all. This is cheap enough to run on every keystroke.
+///
+/// The depth limit of [`ParseOptions`] isn't checked, only the brackets.
+pub fn check_balanced(src: &[u8]) -> Result<(), ParseError> {
+    let mut depth = 0_usize;
+    for (idx, &byte) in src.iter().enumerate() {
+        match byte {
+            b'[' => depth += 1,
+            b']' if depth == 0 => {
+                return Err(ParseError::UnmatchedClose {
+                    span: Span::single(idx),
+                })
+            }
+            b']' => depth -= 1,
+            _ => {}
+        }
+    }
+
+    if depth == 0 {
+        return Ok(());
+    }
+    // the innermost `[` that is never closed is the last one without a `]` after it, so it is
+    // found by going backwards and skipping the closed loops
+    let mut closed = 0_usize;
+    for (idx, &byte) in src.iter().enumerate().rev() {
+        match byte {
+            b']' => closed += 1,
+            b'[' if closed == 0 => {
+                return Err(ParseError::UnmatchedOpen {
+                    span: Span::single(idx),
+                })
+            }
+            b'[' => closed -= 1,
+            _ => {}
+        }
+    }
+    unreachable!("an open loop is left, so there is a `[` without a `]`")
+}
+
+/// Parses the code like [`parse`], but keeps going after mismatched brackets and returns all of
+/// them, for editors that want to show every mismatch at once. The errors are sorted by their
+/// position. There is no limit on the depth of loops.
+///
+/// A `]` always closes the innermost open loop, only a `]` without any open loop is reported and
+/// skipped. Loops that are still open at the end of the code are closed there and reported with
+/// the span of their `[`.
+pub fn parse_recover<I>(alloc: &Bump, src: I) -> (Ast<'_>, Vec<ParseError>)
 where
     I: Iterator<Item = (usize, u8)>,
 {
-    const MAX_DEPTH: u16 = 1000;
+    let mut errors = Vec::new();
+    // the innermost open loop is at the end, with the index of its `[` and the body so far
+    let mut open_loops: Vec<(usize, Ast<'_>)> = Vec::new();
+    let mut instrs = Vec::new_in(alloc);
+    let mut last_idx = 0;
 
-    if depth > MAX_DEPTH {
-        return Err(ParseError);
+    for (idx, byte) in src {
+        last_idx = idx;
+        let instr = match byte {
+            b'+' => Instr::Add,
+            b'-' => Instr::Sub,
+            b'>' => Instr::Right,
+            b'<' => Instr::Left,
+            b'.' => Instr::Out,
+            b',' => Instr::In,
+            b'#' => Instr::Debug,
+            b'[' => {
+                let outer = std::mem::replace(&mut instrs, Vec::new_in(alloc));
+                open_loops.push((idx, outer));
+                continue;
+            }
+            b']' => {
+                let Some((start_idx, outer)) = open_loops.pop() else {
+                    errors.push(ParseError::UnmatchedClose {
+                        span: Span::single(idx),
+                    });
+                    continue;
+                };
+                let body = std::mem::replace(&mut instrs, outer);
+                instrs.push((Instr::Loop(body), Span::start_end_incl(start_idx, idx)));
+                continue;
+            }
+            _ => continue, // comment
+        };
+        instrs.push((instr, Span::single(idx)));
     }
 
-    let mut instrs = Vec::new_in(alloc);
+    while let Some((start_idx, outer)) = open_loops.pop() {
+        errors.push(ParseError::UnmatchedOpen {
+            span: Span::single(start_idx),
+        });
+        let body = std::mem::replace(&mut instrs, outer);
+        instrs.push((Instr::Loop(body), Span::start_end_incl(start_idx, last_idx)));
+    }
 
-    let end_idx = loop {
-        match src.next() {
-            Some((idx, b'+')) => instrs.push((Instr::Add, Span::single(idx))),
-            Some((idx, b'-')) => instrs.push((Instr::Sub, Span::single(idx))),
-            Some((idx, b'>')) => instrs.push((Instr::Right, Span::single(idx))),
-            Some((idx, b'<')) => instrs.push((Instr::Left, Span::single(idx))),
-            Some((idx, b'.')) => instrs.push((Instr::Out, Span::single(idx))),
-            Some((idx, b',')) => instrs.push((Instr::In, Span::single(idx))),
-            Some((idx, b'[')) => {
-                let (loop_instrs, span) = parse_loop(alloc, src, depth + 1, idx)?;
-                instrs.push((Instr::Loop(loop_instrs), span));
-            }
-            Some((idx, b']')) => break idx,
-            Some(_) => {} // comment
-            None => return Err(ParseError),
-        }
-    };
+    errors.sort_by_key(|err| err.span().start());
+    (instrs, errors)
+}
+
+/// Parses the file at `path` by memory-mapping it instead of reading it into memory first.
+///
+/// This keeps the peak memory usage low for multi-megabyte programs. The spans index into the
+/// bytes of the file. The file must not be modified or truncated while it is being parsed.
+#[cfg(feature = "mmap")]
+pub fn parse_mmap<'ast>(
+    alloc: &'ast Bump,
+    path: &std::path::Path,
+) -> Result<Ast<'ast>, crate::Error> {
+    let file = std::fs::File::open(path)?;
+    // SAFETY: The mapping is only read from and dropped at the end of this function. The caller
+    // promises to not modify the file concurrently.
+    let map = unsafe { memmap2::Mmap::map(&file)? };
 
-    Ok((instrs, Span::start_end_incl(start_idx, end_idx)))
+    Ok(parse_bytes(alloc, &map)?)
 }
 
 #[cfg(test)]
@@ -165,6 +450,42 @@ mod tests {
         insta::assert_debug_snapshot!(instrs);
     }
 
+    #[test]
+    fn parse_bytes_not_utf8() {
+        let alloc = Bump::new();
+
+        let bytes = super::parse_bytes(&alloc, b"\xFF+[\xC3-]").unwrap();
+        let iter = super::parse(&alloc, "_+[_-]".bytes().enumerate()).unwrap();
+        assert_eq!(bytes, iter);
+    }
+
+    #[test]
+    fn parse_reader_same_as_in_memory() {
+        let alloc = Bump::new();
+
+        let bf = include_str!("../benches/hanoi.bf");
+        let read = super::parse_reader(&alloc, bf.as_bytes()).unwrap();
+        assert_eq!(read, super::parse_bytes(&alloc, bf.as_bytes()).unwrap());
+
+        let err = super::parse_reader(&alloc, "+[".as_bytes()).unwrap_err();
+        assert!(matches!(err, crate::Error::Parse { .. }));
+    }
+
+    #[test]
+    fn parse_reader_io_error() {
+        struct Broken;
+
+        impl std::io::Read for Broken {
+            fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+        }
+
+        let alloc = Bump::new();
+        let err = super::parse_reader(&alloc, Broken).unwrap_err();
+        assert!(matches!(err, crate::Error::Io(_)));
+    }
+
     #[test]
     fn nested_loop() {
         let alloc = Bump::new();
@@ -173,4 +494,230 @@ mod tests {
         let instrs = super::parse(&alloc, bf.bytes().enumerate());
         insta::assert_debug_snapshot!(instrs);
     }
+
+    #[test]
+    fn loop_brackets() {
+        fn collect_brackets(ast: &super::Ast<'_>, brackets: &mut Vec<(usize, usize)>) {
+            for (instr, span) in ast {
+                if let super::Instr::Loop(body) = instr {
+                    brackets.push(span.loop_brackets().unwrap());
+                    collect_brackets(body, brackets);
+                }
+            }
+        }
+
+        let alloc = Bump::new();
+
+        let bf = "+[-[-[-]]+>>>]";
+        let ast = super::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        let mut brackets = Vec::new();
+        collect_brackets(&ast, &mut brackets);
+
+        assert_eq!(brackets, [(1, 13), (3, 8), (5, 7)]);
+        assert_eq!(super::Span::default().loop_brackets(), None);
+    }
+
+    #[test]
+    fn too_deeply_nested() {
+        let alloc = Bump::new();
+
+        let bf = format!("{}{}", "[".repeat(1100), "]".repeat(1100));
+        let err = super::parse(&alloc, bf.bytes().enumerate()).unwrap_err();
+
+        let super::ParseError::TooDeeplyNested { span, depth } = err else {
+            panic!("expected TooDeeplyNested, got {err:?}");
+        };
+        assert_eq!(span.start(), 1001);
+        assert_eq!(depth, 1002);
+    }
+
+    #[test]
+    fn max_depth_option() {
+        let alloc = Bump::new();
+
+        let bf = format!("{}{}", "[".repeat(1100), "]".repeat(1100));
+        let parse = |max_depth| {
+            let opts = super::ParseOptions {
+                max_depth,
+                ..super::ParseOptions::default()
+            };
+            super::parse_with_options(&alloc, bf.bytes().enumerate(), opts)
+        };
+
+        assert!(parse(Some(1099)).is_err());
+        assert!(parse(Some(1100)).is_ok());
+        assert!(parse(None).is_ok());
+    }
+
+    #[test]
+    fn custom_commands() {
+        let alloc = Bump::new();
+        let parse = |bf: &str, commands: &[u8; 8]| {
+            let opts = super::ParseOptions {
+                commands: *commands,
+                ..super::ParseOptions::default()
+            };
+            super::parse_with_options(&alloc, bf.bytes().enumerate(), opts).unwrap()
+        };
+
+        let standard = parse("+[->+<].", b"+-><.,[]");
+        assert_eq!(parse("-[+>-<].", b"-+><.,[]"), standard);
+        // the standard commands are just comments now
+        assert_eq!(parse("a(bcad)e+", b"abcdef()"), standard);
+    }
+
+    #[test]
+    fn very_deeply_nested() {
+        let alloc = Bump::new();
+
+        let bf = format!("+{}-{}", "[".repeat(50_000), "]".repeat(50_000));
+        let opts = super::ParseOptions {
+            max_depth: None,
+            ..super::ParseOptions::default()
+        };
+        let ast = super::parse_with_options(&alloc, bf.bytes().enumerate(), opts).unwrap();
+
+        let mut depth = 0;
+        let mut body = &ast;
+        while let Some((super::Instr::Loop(inner), _)) = body.last() {
+            depth += 1;
+            body = inner;
+        }
+        assert_eq!(depth, 50_000);
+        assert_eq!(body[0], (super::Instr::Sub, super::Span::single(50_001)));
+    }
+
+    #[test]
+    fn unmatched_brackets() {
+        let alloc = Bump::new();
+        let parse = |bf: &str| super::parse(&alloc, bf.bytes().enumerate()).unwrap_err();
+
+        assert_eq!(
+            parse("+[+[+]"),
+            super::ParseError::UnmatchedOpen {
+                span: super::Span::single(1)
+            }
+        );
+        // the inner loop is closed, the error points at the outer `[`
+        assert_eq!(
+            parse("[[]"),
+            super::ParseError::UnmatchedOpen {
+                span: super::Span::single(0)
+            }
+        );
+        assert_eq!(
+            parse("+[-]]+"),
+            super::ParseError::UnmatchedClose {
+                span: super::Span::single(4)
+            }
+        );
+    }
+
+    #[test]
+    fn until() {
+        let span = super::Span { start: 10, len: 2 };
+        let other = super::Span { start: 20, len: 3 };
+
+        assert_eq!(span.until(other), super::Span::start_end(10, 23));
+    }
+
+    #[test]
+    fn check_balanced_same_as_parse() {
+        let alloc = Bump::new();
+
+        for bf in [
+            "", "+[-]>.", "[[]", "+[+[+]", "+[-]]+", "][", "[[][]][", "[]][[", "[[-]>[+]",
+        ] {
+            let parsed = super::parse_bytes(&alloc, bf.as_bytes()).map(drop);
+            assert_eq!(super::check_balanced(bf.as_bytes()), parsed, "{bf}");
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let span = super::Span::start_end(2, 5);
+
+        assert!(!span.contains(1));
+        assert!(span.contains(2));
+        assert!(span.contains(4));
+        assert!(!span.contains(5));
+        assert!(!super::Span::start_end(3, 3).contains(3));
+    }
+
+    #[test]
+    fn overlaps() {
+        let span = super::Span::start_end;
+
+        // adjacent
+        assert!(!span(0, 2).overlaps(span(2, 4)));
+        assert!(!span(2, 4).overlaps(span(0, 2)));
+        // partial
+        assert!(span(0, 3).overlaps(span(2, 4)));
+        assert!(span(2, 4).overlaps(span(0, 3)));
+        // nested
+        assert!(span(0, 10).overlaps(span(4, 5)));
+        assert!(span(4, 5).overlaps(span(0, 10)));
+        assert!(span(1, 3).overlaps(span(1, 3)));
+        // empty
+        assert!(!span(0, 10).overlaps(span(4, 4)));
+        assert!(!span(4, 4).overlaps(span(4, 4)));
+    }
+
+    #[test]
+    fn line_col() {
+        let span_at = super::Span::single;
+
+        assert_eq!(span_at(0).line_col("+"), (1, 1));
+        assert_eq!(span_at(3).line_col("+\n-+"), (2, 2));
+        // `ä` and `→` are two and three bytes long, but only one column wide
+        let src = "ä→ +";
+        assert_eq!(span_at(src.find('+').unwrap()).line_col(src), (1, 4));
+        // `\r\n` is only one line break, and the `\r` isn't part of the next line
+        let src = "+\r\n\r\n ä[";
+        assert_eq!(span_at(src.find('[').unwrap()).line_col(src), (3, 3));
+    }
+
+    fn recover_error_positions(bf: &str) -> Vec<usize> {
+        let alloc = Bump::new();
+        let (_, errors) = super::parse_recover(&alloc, bf.bytes().enumerate());
+        errors.iter().map(|err| err.span().start()).collect()
+    }
+
+    #[test]
+    fn recover_close_without_open() {
+        let alloc = Bump::new();
+
+        let (ast, errors) = super::parse_recover(&alloc, "+[-]]+".bytes().enumerate());
+        assert_eq!(
+            errors,
+            [super::ParseError::UnmatchedClose {
+                span: super::Span::single(4)
+            }]
+        );
+        assert_eq!(ast.len(), 3);
+    }
+
+    #[test]
+    fn recover_close_innermost_open_loop() {
+        // the `]` closes the inner loop that is already open, only the outer one is missing
+        assert_eq!(recover_error_positions("+[[-]"), [1]);
+        assert_eq!(recover_error_positions("+[[-]]"), []);
+        assert_eq!(recover_error_positions("]+[[-]"), [0, 2]);
+    }
+
+    #[test]
+    #[cfg(feature = "mmap")]
+    fn mmap_same_as_in_memory() {
+        let alloc = Bump::new();
+
+        let bf = include_str!("../benches/hanoi.bf").repeat(50);
+        let path = std::env::temp_dir().join(format!("bf-mmap-test-{}.bf", std::process::id()));
+        std::fs::write(&path, &bf).unwrap();
+
+        let mapped = super::parse_mmap(&alloc, &path);
+        std::fs::remove_file(&path).unwrap();
+
+        let in_memory = super::parse(&alloc, bf.bytes().enumerate()).unwrap();
+        assert_eq!(mapped.unwrap(), in_memory);
+    }
 }
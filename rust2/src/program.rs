@@ -0,0 +1,121 @@
+//! a self-contained compiled program, for library users that just want to run brainfuck
+
+use std::io::{Read, Write};
+
+use bumpalo::Bump;
+
+use crate::{
+    hir,
+    lir::{
+        self,
+        interpreter::{RunConfig, RunStats, RuntimeError},
+    },
+    parse::{self, ParseError},
+};
+
+/// How [`compile`] compiles the program
+#[derive(Debug, Clone, Default)]
+pub struct CompileOptions {
+    /// The config the program is run with. The program is optimized for its cell width and
+    /// arithmetic.
+    pub config: RunConfig,
+}
+
+/// A parsed and optimized program that can be run as often as needed, see [`compile`]
+pub struct Program {
+    /// the LIR in the format of `lir::serialize`, it borrows its allocator and can't be stored
+    /// next to it
+    lir: Vec<u8>,
+    config: RunConfig,
+}
+
+/// Parses and optimizes the source code into a program that owns all of its memory
+///
+/// ```
+/// let program = brainfuck::compile(",+.", brainfuck::CompileOptions::default()).unwrap();
+///
+/// let mut stdout = Vec::new();
+/// program.run(b"a".as_slice(), &mut stdout).unwrap();
+/// assert_eq!(stdout, b"b");
+/// ```
+pub fn compile(src: &str, opts: CompileOptions) -> Result<Program, ParseError> {
+    let config = opts.config;
+    let alloc = Bump::new();
+    let ast = parse::parse_bytes(&alloc, src.as_bytes())?;
+    let hir = hir::optimized_hir_for_cells(&alloc, &ast, config.cell_width, config.arith_policy);
+    let lir = lir::generate(&alloc, &hir);
+
+    let mut serialized = Vec::new();
+    // writing to a `Vec` can't fail
+    lir::serialize(&lir, &mut serialized).unwrap();
+
+    Ok(Program {
+        lir: serialized,
+        config,
+    })
+}
+
+impl Program {
+    /// Runs the program from the start on a fresh tape
+    pub fn run<R, W>(&self, stdin: R, stdout: W) -> Result<RunStats, RuntimeError>
+    where
+        R: Read,
+        W: Write,
+    {
+        let alloc = Bump::new();
+        let lir = lir::deserialize(&alloc, &mut self.lir.as_slice())
+            .expect("the LIR was serialized by `compile`");
+        lir::interpreter::run(&lir, stdout, stdin, &self.config, |_| {})
+    }
+
+    pub fn config(&self) -> &RunConfig {
+        &self.config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        lir::{cell::CellWidth, interpreter::RunConfig},
+        CompileOptions,
+    };
+
+    #[test]
+    fn run_twice() {
+        let program = super::compile(
+            include_str!("../benches/fizzbuzz.bf"),
+            CompileOptions::default(),
+        )
+        .unwrap();
+
+        let mut first = Vec::new();
+        program.run([].as_slice(), &mut first).unwrap();
+        let mut second = Vec::new();
+        program.run([].as_slice(), &mut second).unwrap();
+
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn compiled_for_cell_width() {
+        let opts = CompileOptions {
+            config: RunConfig {
+                cell_width: CellWidth::U16,
+                ..RunConfig::default()
+            },
+        };
+        // 8 bit cells would wrap around to 0 and skip the loop
+        let bf = format!("{}[[-]{}.[-]]", "+".repeat(256), "+".repeat(65));
+        let program = super::compile(&bf, opts).unwrap();
+
+        let mut stdout = Vec::new();
+        program.run([].as_slice(), &mut stdout).unwrap();
+        assert_eq!(stdout, b"A");
+    }
+
+    #[test]
+    fn parse_error() {
+        assert!(super::compile("[", CompileOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,117 @@
+//! running code snippet by snippet on the same tape, for REPLs
+
+use std::{
+    io::{Read, Write},
+    num::Wrapping,
+};
+
+use bumpalo::Bump;
+
+use crate::{
+    hir,
+    lir::{
+        self,
+        interpreter::{self, RunConfig},
+    },
+    parse, Error,
+};
+
+/// Runs snippets of code one after another, each one continuing with the tape and pointer the last
+/// one left behind. [`crate::run`] starts every program on a fresh tape instead.
+///
+/// To step through a single program for debuggers, see [`lir::machine::Machine`].
+///
+/// ```
+/// let mut stdout = Vec::new();
+/// let mut repl = brainfuck::Repl::new(&mut stdout, [].as_slice());
+/// repl.eval("+++>++").unwrap();
+/// assert_eq!(repl.ptr(), 1);
+///
+/// repl.eval("[-<+>]<.").unwrap();
+/// assert_eq!(repl.tape()[0].0, 5);
+/// drop(repl);
+/// assert_eq!(stdout, [5]);
+/// ```
+pub struct Repl<W, R> {
+    mem: Vec<Wrapping<u8>>,
+    ptr: usize,
+    config: RunConfig,
+    stdout: W,
+    stdin: R,
+}
+
+impl<W: Write, R: Read> Repl<W, R> {
+    /// Creates a REPL with a zeroed tape and the default config of the interpreter
+    pub fn new(stdout: W, stdin: R) -> Self {
+        let config = RunConfig::default();
+        Self {
+            mem: vec![Wrapping(0); config.mem_size],
+            ptr: 0,
+            config,
+            stdout,
+            stdin,
+        }
+    }
+
+    /// Parses, optimizes and runs the snippet on the current tape. If it fails to parse, nothing
+    /// is run. If it fails while running, the tape and pointer stay where it stopped.
+    pub fn eval(&mut self, src: &str) -> Result<(), Error> {
+        let alloc = Bump::new();
+        let ast =
+            parse::parse_bytes(&alloc, src.as_bytes()).map_err(|err| Error::parse_in(err, src))?;
+        let hir = hir::optimized_snippet(&alloc, &ast);
+        let lir = lir::generate(&alloc, &hir);
+
+        interpreter::run_from(
+            &lir,
+            &mut self.mem,
+            &mut self.ptr,
+            &mut self.stdout,
+            &mut self.stdin,
+            &self.config,
+        )?;
+        Ok(())
+    }
+
+    /// The index of the current cell
+    pub fn ptr(&self) -> usize {
+        self.ptr
+    }
+
+    /// The whole tape
+    pub fn tape(&self) -> &[Wrapping<u8>] {
+        &self.mem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Repl;
+    use crate::Error;
+
+    #[test]
+    fn state_kept_between_snippets() {
+        let mut stdout = Vec::new();
+        let mut repl = Repl::new(&mut stdout, b"a".as_slice());
+
+        repl.eval(",>+").unwrap();
+        // would be removed as a comment at the start of a program
+        repl.eval("[-<+>]<.").unwrap();
+        assert_eq!(repl.ptr(), 0);
+        assert_eq!(repl.tape()[0].0, b'b');
+        assert_eq!(repl.tape()[1].0, 0);
+
+        drop(repl);
+        assert_eq!(stdout, b"b");
+    }
+
+    #[test]
+    fn parse_error_runs_nothing() {
+        let mut repl = Repl::new(std::io::sink(), std::io::empty());
+        repl.eval("++>").unwrap();
+
+        assert!(matches!(repl.eval("+[<"), Err(Error::Parse { .. })));
+        assert_eq!(repl.ptr(), 1);
+        assert_eq!(repl.tape()[0].0, 2);
+    }
+}